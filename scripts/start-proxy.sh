@@ -2,4 +2,5 @@
 
 ifconfig
 
-./omnia-proxy
\ No newline at end of file
+# exec, so that the proxy gets the SIGTERM of docker stop and saves the DB before exiting
+exec ./omnia-proxy
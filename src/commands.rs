@@ -0,0 +1,154 @@
+use std::{fs, io::Write, net::IpAddr, os::unix::fs::OpenOptionsExt, sync::Arc};
+
+use uuid::Uuid;
+
+use crate::{
+    models::GenericError,
+    proxy::{
+        proxy_db::ProxyDb,
+        registry::{export_bundle, import_bundle, RegistryBundle},
+        storage::{lock_db_from_env, DbStorage, StoredPeer},
+        wireguard::WireguardBackend,
+    },
+};
+
+/// The subcommands of the binary, which run on the DB and the interfaces configured by the env variables
+/// and exit, instead of starting the proxy:
+/// - `export <path> [--include-server-keys]`: writes the peer registry to a bundle, see [export_bundle]
+/// - `import <path>`: applies a bundle to the proxy, see [import_bundle]. It refuses to run while the proxy is running,
+///   see [crate::proxy::storage::lock::DbLock]
+/// - `find <uuid|vpn-ip|public-key>`: prints the peer saved in the storage, see [find_peer]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Export {
+        path: String,
+        include_server_keys: bool,
+    },
+    Import {
+        path: String,
+    },
+    Find {
+        key: String,
+    },
+}
+
+impl Command {
+    /// Reads the subcommand from the arguments (without the name of the binary),
+    /// `None` if there is none and the proxy should start
+    pub fn from_args(args: Vec<String>) -> Result<Option<Self>, GenericError> {
+        let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+
+        match args.as_slice() {
+            [] => Ok(None),
+            ["export", path] | ["export", path, "--include-server-keys"] => {
+                Ok(Some(Self::Export {
+                    path: path.to_string(),
+                    include_server_keys: args.len() == 3,
+                }))
+            }
+            ["import", path] => Ok(Some(Self::Import {
+                path: path.to_string(),
+            })),
+            ["find", key] => Ok(Some(Self::Find {
+                key: key.to_string(),
+            })),
+            _ => Err(format!(
+                "Unknown command: {}, expected one of:\n  export <path> [--include-server-keys]\n  import <path>\n  find <uuid|vpn-ip|public-key>",
+                args.join(" ")
+            )),
+        }
+    }
+
+    /// Runs the subcommand: the lookups only read the storage,
+    /// the other subcommands run on the DB loaded from it by `load_db`
+    pub fn run<B: WireguardBackend>(
+        self,
+        storage: Arc<dyn DbStorage<B>>,
+        load_db: impl FnOnce(Arc<dyn DbStorage<B>>) -> ProxyDb<B>,
+    ) -> Result<(), GenericError> {
+        match self {
+            Self::Find { key } => find_peer(storage.as_ref(), &key),
+            Self::Export {
+                path,
+                include_server_keys,
+            } => {
+                let proxy_db = load_db(storage);
+                let bundle = export_bundle(&proxy_db, include_server_keys)?;
+                write_bundle(&path, &bundle)?;
+
+                let peers_count = bundle
+                    .interfaces
+                    .iter()
+                    .map(|interface| interface.peers.len())
+                    .sum::<usize>();
+                println!(
+                    "Exported {peers_count} peers to {path} (server keys included: {include_server_keys})"
+                );
+
+                // the DB is not saved, so that the export can run while the proxy is running
+                Ok(())
+            }
+            Self::Import { path } => {
+                let bundle_json = fs::read_to_string(&path)
+                    .map_err(|e| format!("Error reading bundle from {path}: {e}"))?;
+                let bundle = serde_json::from_str::<RegistryBundle>(&bundle_json)
+                    .map_err(|e| format!("Error parsing bundle from {path}: {e}"))?;
+
+                // the proxy would overwrite the imported peers with the DB it holds
+                let _db_lock = lock_db_from_env()?;
+                let mut proxy_db = load_db(storage);
+                // the changes applied before an error are saved too
+                let result = import_bundle(&mut proxy_db, bundle);
+                if let Some((storage, snapshot)) = proxy_db.take_snapshot() {
+                    storage.save(snapshot)?;
+                }
+
+                result?.log();
+                println!("Imported the bundle {path}");
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Prints the peer saved in the storage with the given ID, VPN IP or public key,
+/// without loading the whole DB, see [DbStorage::find_peer_by_id]
+fn find_peer<B: WireguardBackend>(
+    storage: &dyn DbStorage<B>,
+    key: &str,
+) -> Result<(), GenericError> {
+    let stored_peer = if let Ok(id) = key.parse::<Uuid>() {
+        storage.find_peer_by_id(id)?
+    } else if let Ok(vpn_ip) = key.parse::<IpAddr>() {
+        storage.find_peer_by_vpn_ip(vpn_ip)?
+    } else {
+        storage.find_peer_by_public_key(key)?
+    };
+
+    match stored_peer {
+        Some(StoredPeer {
+            interface_name,
+            peer,
+        }) => {
+            println!("Peer {key} is registered to {interface_name}: {:?}", peer);
+            Ok(())
+        }
+        None => Err(format!("Peer {key} not found")),
+    }
+}
+
+/// Writes the bundle readable only by the owner, since it contains the keys of the peers
+fn write_bundle(path: &str, bundle: &RegistryBundle) -> Result<(), GenericError> {
+    let bundle_json = serde_json::to_string_pretty(bundle)
+        .map_err(|e| format!("Error serializing bundle: {e}"))?;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(bundle_json.as_bytes()))
+        .map_err(|e| format!("Error writing bundle to {path}: {e}"))
+}
@@ -9,6 +9,16 @@ pub fn get_env_var(var_name: &str) -> String {
     }
 }
 
+/// Same as [get_env_var], but returns `default` if the env var is not set
+pub fn get_env_var_or(var_name: &str, default: &str) -> String {
+    env::var(var_name).unwrap_or_else(|_| default.to_string())
+}
+
+/// Same as [get_env_var], but returns `None` if the env var is not set or empty
+pub fn get_env_var_or_none(var_name: &str) -> Option<String> {
+    env::var(var_name).ok().filter(|val| !val.is_empty())
+}
+
 pub fn load_env_variables() -> Result<(), dotenvy::Error> {
     match env::var("ENV") {
         Ok(val) => {
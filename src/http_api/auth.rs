@@ -0,0 +1,35 @@
+use crate::env::get_env_var_or;
+
+/// The token that operators use to call the admin endpoints,
+/// read from the `ADMIN_API_TOKEN` env variable.
+/// If it is not set (or empty), the admin endpoints can't be called with a token
+pub fn admin_token_from_env() -> Option<String> {
+    let token = get_env_var_or("ADMIN_API_TOKEN", "");
+
+    if token.is_empty() {
+        println!("ADMIN_API_TOKEN not set, admin token authentication disabled");
+        None
+    } else {
+        Some(token)
+    }
+}
+
+/// Checks the `Authorization: Bearer <token>` header against the admin token
+pub fn is_admin(admin_token: Option<&str>, authorization_header: Option<&str>) -> bool {
+    match (admin_token, authorization_header) {
+        (Some(admin_token), Some(header)) => match header.strip_prefix("Bearer ") {
+            Some(token) => constant_time_eq(token.trim().as_bytes(), admin_token.as_bytes()),
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// Compares the tokens without leaking where they differ through timing
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -4,53 +4,297 @@ use std::{
 };
 use uuid::Uuid;
 use warp::{
-    http::{HeaderMap, Method},
+    http::{HeaderMap, Method, StatusCode},
     path::FullPath,
-    reply::{json, Json},
+    reject::Rejection,
+    reply::{json, with_header, with_status, Json, Reply, Response},
 };
 use warp_reverse_proxy::QueryParameters;
 
-use crate::{env::get_env_var, http_api::models::PeerInfoResponseBody, proxy::proxy_db::ProxyDb};
+use crate::{
+    env::get_env_var,
+    http_api::{
+        auth::{constant_time_eq, is_admin},
+        models::{DeregisterPeerResponseBody, PeerInfoResponseBody},
+    },
+    proxy::{
+        client_config::{ClientConfig, ClientConfigSettings},
+        keepalive::KeepaliveSettings,
+        liveness::{LivenessThresholds, PeerStatus},
+        proxy_db::ProxyDb,
+        reconcile::{reconcile, OrphanPolicy},
+        registry::{check_bundle, export_bundle, import_bundle, RegistryBundle},
+        vpn::Vpn,
+        wireguard::{
+            keys::{self, generate_keypair, validate_key},
+            WireguardBackend,
+        },
+    },
+};
+
+use super::models::{
+    ApiError, ClientConfigFormat, ExportRegistryQueryParams, ProxyParams, RegisterPeerQueryParams,
+    RegisterPeerRequestBody, RegisterPeerResponseBody, RotatePeerKeyRequestBody,
+};
+
+/// The first segment of the paths of the proxy's own admin endpoints.
+/// The requests under it are never forwarded, so they can't shadow the paths of the Backend or of the peers
+pub const ADMIN_PATH_PREFIX: &str = "_proxy";
+
+/// Validates the keys sent by a peer and returns the preshared key to apply to it,
+/// generating a new one if `generate_preshared_key` is set
+fn preshared_key_from_request(
+    public_key: &str,
+    preshared_key: Option<String>,
+    generate_preshared_key: bool,
+) -> Result<Option<String>, ApiError> {
+    let bad_request = |message: String| ApiError {
+        message,
+        status_code: StatusCode::BAD_REQUEST,
+    };
+
+    validate_key(public_key).map_err(|e| bad_request(format!("Invalid public key: {e}")))?;
+
+    match (preshared_key, generate_preshared_key) {
+        (Some(_), true) => Err(bad_request(
+            "preshared_key and generate_preshared_key can't be used together".to_string(),
+        )),
+        (Some(preshared_key), false) => {
+            validate_key(&preshared_key)
+                .map_err(|_| bad_request("Invalid preshared key".to_string()))?;
+            Ok(Some(preshared_key))
+        }
+        (None, true) => Ok(Some(keys::generate_preshared_key())),
+        (None, false) => Ok(None),
+    }
+}
+
+/// Chooses the VPN the peer registers to:
+/// - if `registration_token` is set, the VPN of the interface with that token, which must be `interface` if set too
+/// - otherwise, the VPN of `interface` (or of the default interface), which must not require a registration token
+fn registration_vpn<'a, B: WireguardBackend>(
+    proxy_db: &'a ProxyDb<B>,
+    interface: Option<&str>,
+    registration_token: Option<&str>,
+) -> Result<&'a Vpn<B>, ApiError> {
+    let unauthorized = |message: String| ApiError {
+        message,
+        status_code: StatusCode::UNAUTHORIZED,
+    };
+
+    match registration_token {
+        Some(token) => {
+            let vpn = proxy_db
+                .vpns
+                .values()
+                .find(|vpn| match &vpn.config.registration_token {
+                    Some(expected) => constant_time_eq(token.as_bytes(), expected.as_bytes()),
+                    None => false,
+                })
+                .ok_or(unauthorized("Invalid registration token".to_string()))?;
+
+            match interface {
+                Some(interface) if interface != vpn.interface_name => Err(unauthorized(format!(
+                    "Invalid registration token for interface {interface}"
+                ))),
+                _ => Ok(vpn),
+            }
+        }
+        None => {
+            let interface = interface.unwrap_or(&proxy_db.default_interface);
+            let vpn = proxy_db.vpn(interface).ok_or(ApiError {
+                message: format!("Unknown interface {interface}"),
+                status_code: StatusCode::BAD_REQUEST,
+            })?;
 
-use super::models::{ApiError, ProxyParams, RegisterPeerRequestBody, RegisterPeerResponseBody};
+            match vpn.config.registration_token {
+                Some(_) => Err(unauthorized(format!(
+                    "Interface {interface} requires a registration token"
+                ))),
+                None => Ok(vpn),
+            }
+        }
+    }
+}
 
-// registers the new peer to the vpn, sending a docker command to wireguard
+// registers the new peer to the vpn of the interface it chooses, sending a docker command to wireguard
 // saves the remote_address of the peer to a mapping
-pub fn handle_register_to_vpn(
-    proxy_db: Arc<Mutex<ProxyDb>>,
+// if requested, generates the keypair of the peer and returns its complete wg-quick config
+pub fn handle_register_to_vpn<B: WireguardBackend>(
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    client_config_settings: ClientConfigSettings,
+    keepalive_settings: KeepaliveSettings,
     remote_address: Option<SocketAddr>,
+    query_params: RegisterPeerQueryParams,
     request_body: RegisterPeerRequestBody,
-) -> Result<Json, ApiError> {
+) -> Result<Response, ApiError> {
     let mut proxy_db = proxy_db.lock().unwrap();
 
+    let bad_request = |message: &str| ApiError {
+        message: message.to_string(),
+        status_code: StatusCode::BAD_REQUEST,
+    };
+
     if let Some(addr) = remote_address {
         println!("Remote address: {}", addr);
         println!("Registering peer: {:?}", request_body);
 
-        match proxy_db.vpn.add_or_update_peer(
-            request_body.public_key,
+        let vpn = registration_vpn(
+            &proxy_db,
+            request_body.interface.as_deref(),
+            request_body.registration_token.as_deref(),
+        )?;
+        let interface_name = vpn.interface_name.clone();
+        let server_public_key = vpn.interface_public_key.clone();
+        let client_endpoint = client_config_settings.endpoint(&vpn.config, vpn.listen_port);
+        let client_allowed_ips = vpn.config.client_allowed_ips();
+
+        let format = query_params
+            .format
+            .as_deref()
+            .map(|format| format.parse::<ClientConfigFormat>())
+            .transpose()
+            .map_err(|e| bad_request(&e))?
+            .unwrap_or(ClientConfigFormat::Json);
+
+        let (public_key, private_key) =
+            match (request_body.public_key, request_body.generate_keypair) {
+                (Some(public_key), false) => (public_key, None),
+                (None, true) => {
+                    let (private_key, public_key) = generate_keypair();
+                    (public_key, Some(private_key))
+                }
+                (Some(_), true) => {
+                    return Err(bad_request(
+                        "public_key and generate_keypair can't be used together",
+                    ))
+                }
+                (None, false) => {
+                    return Err(bad_request(
+                        "public_key is required, unless generate_keypair is set",
+                    ))
+                }
+            };
+
+        if private_key.is_none() && format != ClientConfigFormat::Json {
+            return Err(bad_request(
+                "The conf and qr formats can only be used with generate_keypair",
+            ));
+        }
+
+        // the config can't be built without the endpoint, so we fail before registering the peer
+        let endpoint = match (&private_key, client_endpoint) {
+            (Some(_), None) => {
+                return Err(ApiError {
+                    message: format!("Error registering peer: the WireGuard endpoint of the clients of {interface_name} is not configured"),
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                })
+            }
+            (_, endpoint) => endpoint,
+        };
+
+        // peers with a generated keypair also get a generated preshared key, unless they send one
+        let generate_preshared_key = request_body.generate_preshared_key
+            || (private_key.is_some() && request_body.preshared_key.is_none());
+        let preshared_key = preshared_key_from_request(
+            &public_key,
             request_body.preshared_key,
+            generate_preshared_key,
+        )?;
+
+        let persistent_keepalive = keepalive_settings.resolve(request_body.persistent_keepalive);
+
+        // a peer can be registered to a single interface, since its VPN IPs identify it
+        if let Some(other_vpn) = proxy_db
+            .vpn_of_peer(&public_key)
+            .filter(|other_vpn| other_vpn.interface_name != interface_name)
+        {
+            return Err(ApiError {
+                message: format!(
+                    "Error registering peer: the public key is already registered to interface {}",
+                    other_vpn.interface_name
+                ),
+                status_code: StatusCode::CONFLICT,
+            });
+        }
+
+        // the ID is chosen before the VPN assigns the IPs to the peer, see [ProxyDb::peer_id]
+        let peer_id = proxy_db.peer_id(&interface_name, &public_key);
+        // the VPN was chosen among the managed ones above
+        let vpn = proxy_db.vpn_mut(&interface_name).unwrap();
+
+        let conflicts = vpn.reservation_conflicts(&public_key);
+        if !conflicts.is_empty() {
+            return Err(ApiError {
+                message: format!("Error registering peer: {}", conflicts.join(", ")),
+                status_code: StatusCode::CONFLICT,
+            });
+        }
+
+        match vpn.add_or_update_peer(
+            public_key,
+            preshared_key.clone(),
+            persistent_keepalive,
             Some(addr),
         ) {
             Ok(peer) => {
-                println!("Registered peer: {:?}", peer);
+                println!("Registered peer to {interface_name}: {:?}", peer);
 
                 let peer_public_ip = addr.ip().to_string();
-                let peer_vpn_ip = peer.allowed_ips[0].to_string();
-                let peer_id = proxy_db.insert_peer(peer_public_ip, peer_vpn_ip.clone());
-
-                let response = RegisterPeerResponseBody {
-                    server_public_key: proxy_db.vpn.interface_public_key.clone(),
-                    assigned_ip: peer_vpn_ip,
-                    assigned_id: peer_id,
-                    proxy_address: get_env_var("PROXY_INTERNAL_ADDRESS"),
-                };
+                proxy_db.insert_peer(&interface_name, peer_id, peer_public_ip, &peer);
+
+                let client_config = private_key.map(|private_key| ClientConfig {
+                    addresses: peer.allowed_ips.clone(),
+                    private_key,
+                    dns: client_config_settings.dns,
+                    server_public_key: server_public_key.clone(),
+                    preshared_key: preshared_key.clone(),
+                    // we checked above that the endpoint is set when the keypair is generated
+                    endpoint: endpoint.unwrap_or_default(),
+                    allowed_ips: client_allowed_ips,
+                    persistent_keepalive,
+                });
+
+                match (format, client_config) {
+                    (ClientConfigFormat::Conf, Some(client_config)) => Ok(with_header(
+                        client_config.to_string(),
+                        "content-type",
+                        "text/plain; charset=utf-8",
+                    )
+                    .into_response()),
+                    (ClientConfigFormat::Qr, Some(client_config)) => {
+                        let png = client_config.to_qr_png().map_err(|e| ApiError {
+                            message: format!("Error registering peer: {e}"),
+                            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+                        })?;
+
+                        Ok(with_header(png, "content-type", "image/png").into_response())
+                    }
+                    (_, client_config) => {
+                        let response = RegisterPeerResponseBody {
+                            interface: interface_name,
+                            server_public_key,
+                            assigned_ip: peer.vpn_ip().map(|ip| ip.to_string()).unwrap_or_default(),
+                            assigned_ipv6: peer.ipv6().map(|ip| ip.to_string()),
+                            assigned_id: peer_id,
+                            proxy_address: get_env_var("PROXY_INTERNAL_ADDRESS"),
+                            persistent_keepalive: persistent_keepalive.unwrap_or(0),
+                            preshared_key: preshared_key.filter(|_| generate_preshared_key),
+                            private_key: client_config
+                                .as_ref()
+                                .map(|client_config| client_config.private_key.clone()),
+                            client_config: client_config
+                                .map(|client_config| client_config.to_string()),
+                        };
 
-                Ok(json(&response))
+                        Ok(json(&response).into_response())
+                    }
+                }
             }
             Err(e) => {
                 let error = ApiError {
                     message: format!("Error registering peer: {}", e),
+                    status_code: StatusCode::INTERNAL_SERVER_ERROR,
                 };
 
                 println!("{:?}", error);
@@ -59,7 +303,8 @@ pub fn handle_register_to_vpn(
         }
     } else {
         let error = ApiError {
-            message: format!("Error registering peer: No remote address"),
+            message: "Error registering peer: No remote address".to_string(),
+            status_code: StatusCode::BAD_REQUEST,
         };
 
         println!("{:?}", error);
@@ -69,14 +314,23 @@ pub fn handle_register_to_vpn(
 
 /// The `remote_addr` parameter in this case is the address of the peer inside the VPN
 /// This function maps the peer's public IP to the peer's VPN IP
-pub fn forward_request(
-    proxy_db: Arc<Mutex<ProxyDb>>,
+pub fn forward_request<B: WireguardBackend>(
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    liveness_thresholds: LivenessThresholds,
     path: FullPath,
     query_params: QueryParameters,
     method: Method,
     remote_addr: Option<SocketAddr>,
     request_headers: HeaderMap,
 ) -> Result<ProxyParams, ApiError> {
+    // the unknown admin paths are not forwarded either, they are just not found
+    if path.as_str().trim_start_matches('/').split('/').next() == Some(ADMIN_PATH_PREFIX) {
+        return Err(ApiError {
+            message: format!("{} not found", path.as_str()),
+            status_code: StatusCode::NOT_FOUND,
+        });
+    }
+
     let mut proxy_db = proxy_db.lock().unwrap();
 
     println!("Proxying for remote address: {:?}", remote_addr);
@@ -103,6 +357,24 @@ pub fn forward_request(
                 Ok(peer_internal_ip) => {
                     println!("Peer internal IP: {}", peer_internal_ip);
 
+                    // fail fast if the tunnel of the peer is dead,
+                    // instead of letting the backend wait for a timeout
+                    let peer_status = proxy_db
+                        .get_peer(peer_internal_ip)
+                        .map(|peer| liveness_thresholds.peer_status(peer.latest_handshake))
+                        .ok_or(ApiError {
+                            message: format!("Peer {p} not found in the VPN"),
+                            status_code: StatusCode::NOT_FOUND,
+                        })?;
+
+                    if peer_status == PeerStatus::Offline {
+                        println!("Peer {p} is offline");
+                        return Err(ApiError {
+                            message: format!("Peer {p} is offline"),
+                            status_code: StatusCode::SERVICE_UNAVAILABLE,
+                        });
+                    }
+
                     let forward_to_port = match headers.get("x-forward-to-port") {
                         // TODO: handle unwrap
                         Some(port) => port.to_str().unwrap(),
@@ -110,8 +382,14 @@ pub fn forward_request(
                         None => "8888",
                     };
 
+                    // IPv6 addresses must be enclosed in brackets in URLs
+                    let peer_host = match peer_internal_ip {
+                        IpAddr::V4(ip) => ip.to_string(),
+                        IpAddr::V6(ip) => format!("[{ip}]"),
+                    };
+
                     // TODO: change the default port. For not, points to the Gateway WoT Servient port
-                    format!("http://{peer_internal_ip}:{forward_to_port}/")
+                    format!("http://{peer_host}:{forward_to_port}/")
                 }
                 Err(e) => {
                     println!("Peer not registered {:?}", e);
@@ -126,54 +404,43 @@ pub fn forward_request(
             // but first we check if the peer is registered
             // if not, we return an empty proxy address
 
-            // just debugging...
-            proxy_db.save_db();
-
             match remote_addr {
                 Some(addr) => {
-                    match addr.ip() {
-                        IpAddr::V4(ip_v4) => {
-                            match proxy_db.get_peer_info(ip_v4) {
-                                Ok(peer_info) => {
-                                    // peer is registered
-                                    // add the `X-Proxied-For` header
-                                    headers.insert(
-                                        "X-Proxied-For",
-                                        peer_info
-                                            .public_ip
-                                            .parse()
-                                            .expect("Failed to parse public IP in header"),
-                                    );
-                                    // add the peer's ID header
-                                    headers.insert(
-                                        "X-Peer-Id",
-                                        peer_info
-                                            .id
-                                            .to_string()
-                                            .parse()
-                                            .expect("Failed to parse peer ID in header"),
-                                    );
-
-                                    // read `X-Destination-Url` header, which contains the url to where to forward the request
-                                    match headers.get("x-destination-url") {
-                                        Some(destination_url) => {
-                                            destination_url.to_str().unwrap().to_string()
-                                        }
-                                        None => {
-                                            println!("No destination URL found");
-                                            "".to_string()
-                                        }
-                                    }
+                    match proxy_db.get_peer_info(addr.ip()) {
+                        Ok(peer_info) => {
+                            // peer is registered
+                            // add the `X-Proxied-For` header
+                            headers.insert(
+                                "X-Proxied-For",
+                                peer_info
+                                    .public_ip
+                                    .parse()
+                                    .expect("Failed to parse public IP in header"),
+                            );
+                            // add the peer's ID header
+                            headers.insert(
+                                "X-Peer-Id",
+                                peer_info
+                                    .id
+                                    .to_string()
+                                    .parse()
+                                    .expect("Failed to parse peer ID in header"),
+                            );
+
+                            // read `X-Destination-Url` header, which contains the url to where to forward the request
+                            match headers.get("x-destination-url") {
+                                Some(destination_url) => {
+                                    destination_url.to_str().unwrap().to_string()
                                 }
-                                Err(e) => {
-                                    // peer not registered
-                                    println!("Peer not registered {:?}", e);
+                                None => {
+                                    println!("No destination URL found");
                                     "".to_string()
                                 }
                             }
                         }
-                        IpAddr::V6(ip_v6) => {
-                            println!("Peer is using IPv6: {}", ip_v6);
+                        Err(e) => {
+                            // peer not registered
+                            println!("Peer not registered {:?}", e);
                             "".to_string()
                         }
                     }
@@ -186,6 +453,7 @@ pub fn forward_request(
     if proxy_address.is_empty() {
         return Err(ApiError {
             message: "Peer not registered".to_string(),
+            status_code: StatusCode::NOT_FOUND,
         });
     }
 
@@ -205,8 +473,9 @@ pub fn forward_request(
 }
 
 /// Returns information about the peer. The peer is identified by it's remote address (which should be the internal ip) and retrieved from the database
-pub fn handle_peer_info(
-    proxy_db: Arc<Mutex<ProxyDb>>,
+pub fn handle_peer_info<B: WireguardBackend>(
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    liveness_thresholds: LivenessThresholds,
     remote_address: Option<SocketAddr>,
 ) -> Result<Json, ApiError> {
     let mut proxy_db = proxy_db.lock().unwrap();
@@ -214,44 +483,45 @@ pub fn handle_peer_info(
     match remote_address {
         Some(addr) => {
             println!("Retrieving peer information for remote address: {}", addr);
-            match addr.ip() {
-                IpAddr::V4(ip_v4) => {
-                    match proxy_db.get_peer_info(ip_v4) {
-                        Ok(peer_info) => {
-                            // peer is registered
-                            // we have to retrieve the public key from the vpn database
-                            match proxy_db.vpn.refresh_and_get_peer(ip_v4) {
-                                Ok(vpn_peer) => {
-                                    println!("Peer found: {ip_v4}");
-                                    let response = PeerInfoResponseBody {
-                                        id: peer_info.id,
-                                        internal_ip: ip_v4.to_string(),
-                                        public_ip: peer_info.public_ip,
-                                        proxy_address: get_env_var("PROXY_INTERNAL_ADDRESS"),
-                                        public_key: vpn_peer.public_key,
-                                    };
-                                    Ok(json(&response))
-                                }
-                                Err(e) => {
-                                    println!("Error retrieving peer public key: {}", e);
-                                    Err(ApiError {
-                                        message: format!("Error retrieving peer public key: {}", e),
-                                    })
-                                }
-                            }
+            let peer_vpn_ip = addr.ip();
+            match proxy_db.get_peer_info(peer_vpn_ip) {
+                Ok(peer_info) => {
+                    // peer is registered
+                    // we have to retrieve the public key from the vpn database
+                    match proxy_db.get_peer(peer_vpn_ip).cloned() {
+                        Some(vpn_peer) => {
+                            println!("Peer found: {peer_vpn_ip}");
+                            let response = PeerInfoResponseBody {
+                                id: peer_info.id,
+                                // the VPN of the peer exists, since we found the peer in it
+                                interface: proxy_db
+                                    .vpn_of_ip(peer_vpn_ip)
+                                    .map(|vpn| vpn.interface_name.clone())
+                                    .unwrap_or_default(),
+                                internal_ip: vpn_peer.vpn_ip().unwrap_or(peer_vpn_ip).to_string(),
+                                internal_ipv6: vpn_peer.ipv6().map(|ip| ip.to_string()),
+                                public_ip: peer_info.public_ip,
+                                proxy_address: get_env_var("PROXY_INTERNAL_ADDRESS"),
+                                public_key: vpn_peer.public_key,
+                                status: liveness_thresholds.peer_status(vpn_peer.latest_handshake),
+                                latest_handshake: vpn_peer.latest_handshake,
+                            };
+                            Ok(json(&response))
                         }
-                        Err(e) => {
-                            // peer is not registered, return an error
+                        None => {
+                            println!("Error retrieving peer public key: {peer_vpn_ip} not found in the VPN");
                             Err(ApiError {
-                                message: format!("Peer not registered: {}", e),
+                                message: format!("Error retrieving peer public key: {peer_vpn_ip} not found in the VPN"),
+                                status_code: StatusCode::INTERNAL_SERVER_ERROR,
                             })
                         }
                     }
                 }
-                IpAddr::V6(ip_v6) => {
-                    println!("Peer is using IPv6: {}", ip_v6);
+                Err(e) => {
+                    // peer is not registered, return an error
                     Err(ApiError {
-                        message: format!("Peer is using IPv6: {}", ip_v6),
+                        message: format!("Peer not registered: {}", e),
+                        status_code: StatusCode::NOT_FOUND,
                     })
                 }
             }
@@ -259,7 +529,205 @@ pub fn handle_peer_info(
         None => {
             // this should never happen
             let error = ApiError {
-                message: format!("Error retrieving peer information: No remote address"),
+                message: "Error retrieving peer information: No remote address".to_string(),
+                status_code: StatusCode::BAD_REQUEST,
+            };
+
+            println!("{:?}", error);
+            Err(error)
+        }
+    }
+}
+
+/// A peer the caller is allowed to manage, see [find_authorized_peer]
+struct AuthorizedPeer {
+    id: Uuid,
+    interface_name: String,
+    public_key: String,
+    /// Whether the caller sent the admin token, otherwise it's the peer itself
+    by_admin: bool,
+}
+
+/// Finds the peer with the given ID, checking that the caller is allowed to manage it:
+/// the caller must either send the admin token or be the peer itself (i.e. send the request from the peer's VPN IP)
+fn find_authorized_peer<B: WireguardBackend>(
+    proxy_db: &ProxyDb<B>,
+    admin_token: Option<&str>,
+    peer_id: &str,
+    remote_address: Option<SocketAddr>,
+    authorization_header: Option<&str>,
+) -> Result<AuthorizedPeer, ApiError> {
+    let peer_id = Uuid::try_parse(peer_id).map_err(|e| ApiError {
+        message: format!("Invalid peer ID {peer_id}: {e}"),
+        status_code: StatusCode::BAD_REQUEST,
+    })?;
+
+    let peer_vpn_ip = proxy_db.get_peer_internal_ip(peer_id).ok();
+    let vpn = peer_vpn_ip.and_then(|peer_vpn_ip| proxy_db.vpn_of_ip(peer_vpn_ip));
+    let peer = peer_vpn_ip.and_then(|peer_vpn_ip| proxy_db.get_peer(peer_vpn_ip));
+
+    let is_admin = is_admin(admin_token, authorization_header);
+    // the peer can send the request from any of its VPN IPs
+    let is_peer_itself = match (remote_address, peer) {
+        (Some(addr), Some(peer)) => peer.allowed_ips.contains(&addr.ip()),
+        _ => false,
+    };
+
+    if !is_admin && !is_peer_itself {
+        println!("Unauthorized request for peer {peer_id} from {remote_address:?}");
+        return Err(ApiError {
+            message: "Unauthorized".to_string(),
+            status_code: StatusCode::UNAUTHORIZED,
+        });
+    }
+
+    if peer_vpn_ip.is_none() {
+        return Err(ApiError {
+            message: format!("Peer with id {peer_id} not found"),
+            status_code: StatusCode::NOT_FOUND,
+        });
+    }
+
+    match (vpn, peer) {
+        (Some(vpn), Some(peer)) => Ok(AuthorizedPeer {
+            id: peer_id,
+            interface_name: vpn.interface_name.clone(),
+            public_key: peer.public_key.clone(),
+            by_admin: is_admin,
+        }),
+        _ => Err(ApiError {
+            message: format!("Peer with id {peer_id} not found in the VPN"),
+            status_code: StatusCode::NOT_FOUND,
+        }),
+    }
+}
+
+/// Removes a peer from the VPN and from the DB, releasing its VPN IP and its ID.
+/// See [find_authorized_peer] for who is allowed to deregister a peer
+pub fn handle_deregister_peer<B: WireguardBackend>(
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    admin_token: Option<String>,
+    peer_id: String,
+    remote_address: Option<SocketAddr>,
+    authorization_header: Option<String>,
+) -> Result<Json, ApiError> {
+    let mut proxy_db = proxy_db.lock().unwrap();
+
+    let AuthorizedPeer {
+        id: peer_id,
+        interface_name,
+        public_key,
+        ..
+    } = find_authorized_peer(
+        &proxy_db,
+        admin_token.as_deref(),
+        &peer_id,
+        remote_address,
+        authorization_header.as_deref(),
+    )?;
+
+    println!("Deregistering peer {peer_id} with public key {public_key} from {interface_name}");
+
+    match proxy_db.remove_peer(&interface_name, &public_key) {
+        Ok((peer, _)) => Ok(json(&DeregisterPeerResponseBody {
+            id: peer_id,
+            public_key,
+            released_ip: peer.vpn_ip().map(|ip| ip.to_string()).unwrap_or_default(),
+            released_ipv6: peer.ipv6().map(|ip| ip.to_string()),
+        })),
+        Err(e) => {
+            let error = ApiError {
+                message: format!("Error deregistering peer: {e}"),
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
+            };
+
+            println!("{:?}", error);
+            Err(error)
+        }
+    }
+}
+
+/// Replaces the public key of a peer, keeping its VPN IP and its ID.
+/// See [find_authorized_peer] for who is allowed to rotate the key of a peer
+pub fn handle_rotate_peer_key<B: WireguardBackend>(
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    admin_token: Option<String>,
+    peer_id: String,
+    remote_address: Option<SocketAddr>,
+    authorization_header: Option<String>,
+    request_body: RotatePeerKeyRequestBody,
+) -> Result<Json, ApiError> {
+    let mut proxy_db = proxy_db.lock().unwrap();
+
+    let AuthorizedPeer {
+        id: peer_id,
+        interface_name,
+        public_key: old_public_key,
+        by_admin,
+    } = find_authorized_peer(
+        &proxy_db,
+        admin_token.as_deref(),
+        &peer_id,
+        remote_address,
+        authorization_header.as_deref(),
+    )?;
+
+    // the peer that rotates its own key through the VPN may never get the response, which is sent to the new key,
+    // so it would lose a generated preshared key: it must send its own
+    if !by_admin && request_body.generate_preshared_key {
+        return Err(ApiError {
+            message: "generate_preshared_key requires the admin token, send the new preshared_key instead"
+                .to_string(),
+            status_code: StatusCode::BAD_REQUEST,
+        });
+    }
+
+    let preshared_key = preshared_key_from_request(
+        &request_body.public_key,
+        request_body.preshared_key,
+        request_body.generate_preshared_key,
+    )?;
+
+    if proxy_db.vpn_of_peer(&request_body.public_key).is_some() {
+        return Err(ApiError {
+            message: format!(
+                "Public key {} is already registered",
+                request_body.public_key
+            ),
+            status_code: StatusCode::CONFLICT,
+        });
+    }
+
+    println!(
+        "Rotating key of peer {peer_id} from {old_public_key} to {}",
+        request_body.public_key
+    );
+
+    match proxy_db.rotate_peer_key(
+        &interface_name,
+        &old_public_key,
+        request_body.public_key,
+        preshared_key.clone(),
+    ) {
+        Ok(peer) => Ok(json(&RegisterPeerResponseBody {
+            server_public_key: proxy_db
+                .vpn(&interface_name)
+                .map(|vpn| vpn.interface_public_key.clone())
+                .unwrap_or_default(),
+            interface: interface_name,
+            assigned_ip: peer.vpn_ip().map(|ip| ip.to_string()).unwrap_or_default(),
+            assigned_ipv6: peer.ipv6().map(|ip| ip.to_string()),
+            assigned_id: peer_id,
+            proxy_address: get_env_var("PROXY_INTERNAL_ADDRESS"),
+            persistent_keepalive: peer.persistent_keepalive.unwrap_or(0),
+            preshared_key: preshared_key.filter(|_| request_body.generate_preshared_key),
+            private_key: None,
+            client_config: None,
+        })),
+        Err(e) => {
+            let error = ApiError {
+                message: format!("Error rotating peer key: {e}"),
+                status_code: StatusCode::INTERNAL_SERVER_ERROR,
             };
 
             println!("{:?}", error);
@@ -267,3 +735,232 @@ pub fn handle_peer_info(
         }
     }
 }
+
+/// Converts the [ApiError] rejections to JSON responses with the appropriate status code.
+/// Other rejections are left to warp
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    match rejection.find::<ApiError>() {
+        Some(error) => Ok(with_status(json(error), error.status_code)),
+        None => Err(rejection),
+    }
+}
+
+/// Reconciles the DB with the live state of WireGuard, see [reconcile].
+/// Only the admin is allowed to trigger it
+pub fn handle_reconcile<B: WireguardBackend>(
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    orphan_policy: OrphanPolicy,
+    admin_token: Option<String>,
+    authorization_header: Option<String>,
+) -> Result<Json, ApiError> {
+    if !is_admin(admin_token.as_deref(), authorization_header.as_deref()) {
+        println!("Unauthorized reconcile request");
+        return Err(ApiError {
+            message: "Unauthorized".to_string(),
+            status_code: StatusCode::UNAUTHORIZED,
+        });
+    }
+
+    let mut proxy_db = proxy_db.lock().unwrap();
+
+    let report = reconcile(&mut proxy_db, orphan_policy);
+    report.log();
+
+    Ok(json(&report))
+}
+
+/// Exports the peer registry as a bundle, see [export_bundle].
+/// Only the admin is allowed to export it, since it contains the keys of the peers
+pub fn handle_export_registry<B: WireguardBackend>(
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    admin_token: Option<String>,
+    authorization_header: Option<String>,
+    query_params: ExportRegistryQueryParams,
+) -> Result<Json, ApiError> {
+    if !is_admin(admin_token.as_deref(), authorization_header.as_deref()) {
+        println!("Unauthorized registry export request");
+        return Err(ApiError {
+            message: "Unauthorized".to_string(),
+            status_code: StatusCode::UNAUTHORIZED,
+        });
+    }
+
+    let proxy_db = proxy_db.lock().unwrap();
+
+    match export_bundle(&proxy_db, query_params.include_server_keys) {
+        Ok(bundle) => {
+            println!(
+                "Exported the peer registry (server keys included: {})",
+                query_params.include_server_keys
+            );
+            Ok(json(&bundle))
+        }
+        Err(e) => Err(ApiError {
+            message: format!("Error exporting the peer registry: {e}"),
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+        }),
+    }
+}
+
+/// Imports a bundle exported by another proxy, see [import_bundle].
+/// Only the admin is allowed to import it
+pub fn handle_import_registry<B: WireguardBackend>(
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    admin_token: Option<String>,
+    authorization_header: Option<String>,
+    bundle: RegistryBundle,
+) -> Result<Json, ApiError> {
+    if !is_admin(admin_token.as_deref(), authorization_header.as_deref()) {
+        println!("Unauthorized registry import request");
+        return Err(ApiError {
+            message: "Unauthorized".to_string(),
+            status_code: StatusCode::UNAUTHORIZED,
+        });
+    }
+
+    let mut proxy_db = proxy_db.lock().unwrap();
+
+    // nothing is changed if the bundle is invalid
+    if let Err(e) = check_bundle(&proxy_db, &bundle) {
+        return Err(ApiError {
+            message: format!("Invalid bundle: {e}"),
+            status_code: StatusCode::BAD_REQUEST,
+        });
+    }
+
+    match import_bundle(&mut proxy_db, bundle) {
+        Ok(report) => {
+            report.log();
+            Ok(json(&report))
+        }
+        Err(e) => Err(ApiError {
+            message: format!("Error importing the peer registry: {e}"),
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::proxy::{interfaces::InterfaceConfig, wireguard::memory::MemoryBackend};
+
+    use super::*;
+
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+    const NEW_PEER_KEY: &str = "CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=";
+    const PRESHARED_KEY: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+
+    /// A proxy managing `wg0` with a registered peer, returning the DB, the ID and the VPN address of the peer
+    fn proxy_db_with_peer() -> (Arc<Mutex<ProxyDb<MemoryBackend>>>, Uuid, SocketAddr) {
+        let interface = InterfaceConfig {
+            name: "wg0".to_string(),
+            ..Default::default()
+        };
+        let mut proxy_db = ProxyDb::<MemoryBackend>::new(vec![interface], BTreeMap::new());
+        let peer_id = proxy_db.peer_id("wg0", PEER_KEY);
+        let peer = proxy_db
+            .vpn_mut("wg0")
+            .unwrap()
+            .add_or_update_peer(PEER_KEY.to_string(), None, None, None)
+            .unwrap();
+        proxy_db.insert_peer("wg0", peer_id, "203.0.113.7".to_string(), &peer);
+        let peer_address = SocketAddr::new(peer.vpn_ip().unwrap(), 40000);
+
+        (Arc::new(Mutex::new(proxy_db)), peer_id, peer_address)
+    }
+
+    /// Rotates the key of the peer with a request sent by the peer itself through the VPN
+    fn rotate_from_peer(
+        proxy_db: &Arc<Mutex<ProxyDb<MemoryBackend>>>,
+        peer_id: Uuid,
+        peer_address: SocketAddr,
+        request_body: RotatePeerKeyRequestBody,
+    ) -> Result<Json, ApiError> {
+        handle_rotate_peer_key(
+            proxy_db.clone(),
+            Some("admin-token".to_string()),
+            peer_id.to_string(),
+            Some(peer_address),
+            None,
+            request_body,
+        )
+    }
+
+    #[test]
+    fn the_peer_itself_cant_generate_the_preshared_key() {
+        let (proxy_db, peer_id, peer_address) = proxy_db_with_peer();
+
+        let result = rotate_from_peer(
+            &proxy_db,
+            peer_id,
+            peer_address,
+            RotatePeerKeyRequestBody {
+                public_key: NEW_PEER_KEY.to_string(),
+                preshared_key: None,
+                generate_preshared_key: true,
+            },
+        );
+
+        assert!(matches!(result, Err(error) if error.status_code == StatusCode::BAD_REQUEST));
+        // the old key is kept, so the peer can still reach the proxy
+        let proxy_db = proxy_db.lock().unwrap();
+        assert!(proxy_db.vpn("wg0").unwrap().peers.contains_key(PEER_KEY));
+    }
+
+    #[test]
+    fn the_peer_itself_rotates_its_key_with_its_preshared_key() {
+        std::env::set_var("PROXY_INTERNAL_ADDRESS", "10.13.13.1:8081");
+        let (proxy_db, peer_id, peer_address) = proxy_db_with_peer();
+
+        rotate_from_peer(
+            &proxy_db,
+            peer_id,
+            peer_address,
+            RotatePeerKeyRequestBody {
+                public_key: NEW_PEER_KEY.to_string(),
+                preshared_key: Some(PRESHARED_KEY.to_string()),
+                generate_preshared_key: false,
+            },
+        )
+        .unwrap();
+
+        let proxy_db = proxy_db.lock().unwrap();
+        let peers = &proxy_db.vpn("wg0").unwrap().peers;
+        assert!(!peers.contains_key(PEER_KEY));
+        assert_eq!(
+            peers[NEW_PEER_KEY].preshared_key.as_deref(),
+            Some(PRESHARED_KEY)
+        );
+        assert_eq!(
+            proxy_db.get_peer_internal_ip(peer_id),
+            Ok(peer_address.ip())
+        );
+    }
+
+    #[test]
+    fn the_admin_can_generate_the_preshared_key() {
+        std::env::set_var("PROXY_INTERNAL_ADDRESS", "10.13.13.1:8081");
+        let (proxy_db, peer_id, _) = proxy_db_with_peer();
+
+        handle_rotate_peer_key(
+            proxy_db.clone(),
+            Some("admin-token".to_string()),
+            peer_id.to_string(),
+            None,
+            Some("Bearer admin-token".to_string()),
+            RotatePeerKeyRequestBody {
+                public_key: NEW_PEER_KEY.to_string(),
+                preshared_key: None,
+                generate_preshared_key: true,
+            },
+        )
+        .unwrap();
+
+        let proxy_db = proxy_db.lock().unwrap();
+        assert!(proxy_db.vpn("wg0").unwrap().peers[NEW_PEER_KEY]
+            .preshared_key
+            .is_some());
+    }
+}
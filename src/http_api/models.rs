@@ -1,38 +1,156 @@
+use std::{fmt, str::FromStr};
+
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use warp::filters::path::FullPath;
-use warp::http::{HeaderMap, Method};
+use warp::http::{HeaderMap, Method, StatusCode};
 use warp_reverse_proxy::QueryParameters;
 
-#[derive(Deserialize, Debug)]
+use crate::{models::redacted, proxy::liveness::PeerStatus};
+
+#[derive(Deserialize)]
 pub struct RegisterPeerRequestBody {
-    pub public_key: String,
+    /// required, unless `generate_keypair` is `true`
+    pub public_key: Option<String>,
+    /// if `true`, the proxy generates the keypair of the peer and returns its complete wg-quick config
+    #[serde(default)]
+    pub generate_keypair: bool,
     pub preshared_key: Option<String>,
+    /// if `true`, the proxy generates the preshared key and returns it in the response
+    #[serde(default)]
+    pub generate_preshared_key: bool,
+    /// seconds between the keepalive packets, `0` disables them.
+    /// If not set, the proxy default is used
+    pub persistent_keepalive: Option<u16>,
+    /// the interface to register to, if not set the one of the `registration_token`, or the default one
+    pub interface: Option<String>,
+    /// the token of the interface to register to, required by the interfaces that have one
+    pub registration_token: Option<String>,
+}
+
+impl fmt::Debug for RegisterPeerRequestBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisterPeerRequestBody")
+            .field("public_key", &self.public_key)
+            .field("generate_keypair", &self.generate_keypair)
+            .field("preshared_key", &redacted(&self.preshared_key))
+            .field("generate_preshared_key", &self.generate_preshared_key)
+            .field("persistent_keepalive", &self.persistent_keepalive)
+            .field("interface", &self.interface)
+            .field("registration_token", &redacted(&self.registration_token))
+            .finish()
+    }
 }
 
 #[derive(Serialize, Debug)]
 pub struct RegisterPeerResponseBody {
+    /// the interface the peer is registered to
+    pub interface: String,
     pub server_public_key: String,
+    /// the IP assigned to the peer in the VPN: its IPv4 address, unless it only has an IPv6 one
     pub assigned_ip: String,
+    /// the IPv6 address assigned to the peer in the VPN, if the VPN is dual-stack
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assigned_ipv6: Option<String>,
     pub assigned_id: Uuid,
     /// the address assigned inside docker network to wireguard
     /// since proxy runs in wireguard container's network
     /// a port must be specified also
     pub proxy_address: String,
+    /// seconds between the keepalive packets exchanged with the proxy, `0` if disabled
+    pub persistent_keepalive: u16,
+    /// the preshared key generated by the proxy, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preshared_key: Option<String>,
+    /// the private key generated by the proxy, if requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    /// the wg-quick config of the peer, if the proxy generated its keypair
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_config: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RegisterPeerQueryParams {
+    /// see [ClientConfigFormat]
+    pub format: Option<String>,
+}
+
+/// The format of the response of a registration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientConfigFormat {
+    /// the [RegisterPeerResponseBody]
+    Json,
+    /// the wg-quick config of the peer, as text
+    Conf,
+    /// the wg-quick config of the peer, as a QR code PNG image
+    Qr,
+}
+
+impl FromStr for ClientConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "conf" => Ok(Self::Conf),
+            "qr" => Ok(Self::Qr),
+            other => Err(format!(
+                "Unknown format: {other}, expected one of json, conf, qr"
+            )),
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
 pub struct PeerInfoResponseBody {
     pub id: Uuid,
+    /// the interface the peer is registered to
+    pub interface: String,
     pub internal_ip: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal_ipv6: Option<String>,
     pub public_ip: String,
     pub public_key: String,
     pub proxy_address: String,
+    pub status: PeerStatus,
+    /// seconds since the UNIX epoch of the latest handshake with the proxy
+    pub latest_handshake: Option<u64>,
 }
 
-#[derive(Debug)]
+#[derive(Deserialize, Debug)]
+pub struct RotatePeerKeyRequestBody {
+    /// the new public key of the peer
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    /// if `true`, the proxy generates the preshared key and returns it in the response
+    #[serde(default)]
+    pub generate_preshared_key: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DeregisterPeerResponseBody {
+    pub id: Uuid,
+    pub public_key: String,
+    /// the VPN IP that was assigned to the peer, now available for new peers
+    pub released_ip: String,
+    /// the VPN IPv6 address that was assigned to the peer, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub released_ipv6: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportRegistryQueryParams {
+    /// if `true`, the private keys of the interfaces are exported too
+    #[serde(default)]
+    pub include_server_keys: bool,
+}
+
+#[derive(Serialize, Debug)]
 pub struct ApiError {
     pub message: String,
+    #[serde(skip)]
+    pub status_code: StatusCode,
 }
 
 impl warp::reject::Reject for ApiError {}
@@ -1,90 +1,400 @@
+mod commands;
 mod env;
 mod http_api;
 mod models;
 mod proxy;
 
-use futures::future;
-use std::sync::{Arc, Mutex};
+use futures::{future, FutureExt};
+use std::{
+    convert::Infallible,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+};
+use tokio::signal::unix::{signal, SignalKind};
 use warp::reject;
-use warp::{http::Response, hyper::Body, Filter, Rejection, Reply};
+use warp::{
+    http::{Response, StatusCode},
+    hyper::Body,
+    Filter, Rejection, Reply,
+};
 use warp_reverse_proxy::{proxy_to_and_forward_response, query_params_filter};
 
+use commands::Command;
 use env::load_env_variables;
 use http_api::{
-    handlers::{forward_request, handle_peer_info, handle_register_to_vpn},
-    models::RegisterPeerRequestBody,
+    auth::admin_token_from_env,
+    handlers::{
+        forward_request, handle_deregister_peer, handle_export_registry, handle_import_registry,
+        handle_peer_info, handle_reconcile, handle_register_to_vpn, handle_rejection,
+        handle_rotate_peer_key, ADMIN_PATH_PREFIX,
+    },
+    models::{
+        ApiError, ExportRegistryQueryParams, RegisterPeerQueryParams, RegisterPeerRequestBody,
+        RotatePeerKeyRequestBody,
+    },
+};
+use proxy::{
+    client_config::ClientConfigSettings,
+    gc::{run_gc, GcConfig},
+    interfaces::interfaces_from_env,
+    keepalive::KeepaliveSettings,
+    liveness::LivenessThresholds,
+    persister::{flush_max_delay_from_env, Persister},
+    poller::{poll_interval_from_env, run_poller},
+    proxy_db::ProxyDb,
+    reconcile::{reconcile, OrphanPolicy},
+    registry::RegistryBundle,
+    reservations::Reservations,
+    storage::{lock_db_from_env, storage_from_env, DbStorage},
+    vpn::{check_vpn, check_vpn_network},
+    wireguard::{
+        docker_exec::DockerExecBackend, local::LocalBackend, memory::MemoryBackend,
+        uapi::UapiBackend, WireguardBackend, WireguardBackendKind,
+    },
 };
-use proxy::{proxy_db::ProxyDb, vpn::check_vpn};
 
 use crate::env::get_env_var;
 
+/// Runs a handler on the blocking threads: the handlers lock the DB and may wait for the WireGuard backend,
+/// which must never block the threads of the async runtime
+async fn run_blocking<T: Send + 'static>(
+    handler: impl FnOnce() -> Result<T, ApiError> + Send + 'static,
+) -> Result<T, Rejection> {
+    match tokio::task::spawn_blocking(handler).await {
+        Ok(result) => result.map_err(reject::custom),
+        Err(e) => Err(reject::custom(ApiError {
+            message: format!("Handler failed: {e}"),
+            status_code: StatusCode::INTERNAL_SERVER_ERROR,
+        })),
+    }
+}
+
 async fn log_response(response: Response<Body>) -> Result<impl Reply, Rejection> {
     println!("{:?}", response);
     Ok(response)
 }
 
+/// The remote address of the request. Since we listen on a dual-stack socket,
+/// IPv4 clients show up with IPv4-mapped IPv6 addresses, which we convert back to IPv4
+fn remote_address() -> impl Filter<Extract = (Option<SocketAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote().map(|remote_address: Option<SocketAddr>| {
+        remote_address.map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+    })
+}
+
+/// The address to listen on: the IPv6 wildcard address, which also accepts IPv4 connections,
+/// or the IPv4 one if IPv6 is not available on the host (e.g. disabled in the container)
+fn listen_ip() -> IpAddr {
+    match TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)) {
+        Ok(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        Err(e) => {
+            println!("IPv6 not available ({e}), listening on IPv4 only");
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     // load env variables
     assert!(load_env_variables().is_ok(), "Failed to load env variables");
 
+    let command =
+        Command::from_args(std::env::args().skip(1).collect()).unwrap_or_else(|e| panic!("{e}"));
+
+    let backend_kind = WireguardBackendKind::from_env().expect("Invalid WireGuard backend");
+    println!("WireGuard backend: {:?}", backend_kind);
+
+    match backend_kind {
+        WireguardBackendKind::DockerExec => run::<DockerExecBackend>(command).await,
+        WireguardBackendKind::Local => run::<LocalBackend>(command).await,
+        WireguardBackendKind::Memory => run::<MemoryBackend>(command).await,
+        WireguardBackendKind::Uapi => run::<UapiBackend>(command).await,
+    }
+}
+
+/// Loads the DB and starts the proxy or, if a subcommand is given, runs it and exits
+async fn run<B: WireguardBackend>(command: Option<Command>) {
+    let storage = storage_from_env::<B>().expect("Invalid DB storage");
+
+    // the subcommands run on the DB as it is: e.g. an export that runs next to the proxy
+    // must not change WireGuard behind its back
+    if let Some(command) = command {
+        command
+            .run(storage, load_db)
+            .unwrap_or_else(|e| panic!("{e}"));
+        return;
+    }
+
+    // held until the proxy exits, so that the subcommands that save the DB refuse to run meanwhile
+    let _db_lock = lock_db_from_env().unwrap_or_else(|e| panic!("{e}"));
+    let mut proxy_db = load_db(storage);
+
+    // the WireGuard state may have changed while the proxy was not running
+    let orphan_policy = OrphanPolicy::from_env().expect("Invalid peer reconcile orphan policy");
+    reconcile(&mut proxy_db, orphan_policy).log();
+
+    // the peers that registered before their reservation was added can't register again until the conflict is solved
+    for vpn in proxy_db.vpns.values() {
+        for reservation in vpn.reservations.iter() {
+            for conflict in vpn.reservation_conflicts(&reservation.public_key) {
+                println!("Reservation conflict on {}: {conflict}", vpn.interface_name);
+            }
+        }
+    }
+
+    serve(proxy_db, orphan_policy).await;
+}
+
+/// Checks the WireGuard interfaces configured by the env variables and loads the DB of their VPNs
+fn load_db<B: WireguardBackend>(storage: Arc<dyn DbStorage<B>>) -> ProxyDb<B> {
     // check if wireguard is running, otherwise throw
-    assert!(check_vpn().is_ok(), "Wireguard is not running");
+    let interface_names = match check_vpn(&B::default()) {
+        Ok(interface_names) => interface_names,
+        Err(e) => panic!("Wireguard is not running: {e}"),
+    };
+
+    let interfaces = interfaces_from_env(&interface_names).expect("Invalid WireGuard interfaces");
+    for interface in &interfaces {
+        println!(
+            "Interface {}: VPN networks {:?}",
+            interface.name,
+            interface.network.networks()
+        );
+        if let Err(e) = check_vpn_network(&B::default(), &interface.name, &interface.network) {
+            panic!("Invalid VPN network of {}: {e}", interface.name);
+        }
+    }
+
+    let reservations = Reservations::from_env(&interfaces).expect("Invalid peer reservations");
 
-    let proxy_db = ProxyDb::load_db();
+    ProxyDb::<B>::load_db(storage, interfaces, reservations)
+}
+
+/// Starts the proxy on the loaded DB
+async fn serve<B: WireguardBackend>(proxy_db: ProxyDb<B>, orphan_policy: OrphanPolicy) {
     let shared_proxy_db = Arc::new(Mutex::new(proxy_db));
 
+    let flush_max_delay = flush_max_delay_from_env().expect("Invalid DB flush max delay");
+    let persister = Arc::new(Persister::new(shared_proxy_db.clone()));
+    tokio::spawn(persister.clone().run(flush_max_delay));
+
+    let poll_interval = poll_interval_from_env().expect("Invalid WireGuard poll interval");
+    tokio::spawn(run_poller(shared_proxy_db.clone(), poll_interval));
+
+    if let Some(gc_config) = GcConfig::from_env().expect("Invalid peer GC config") {
+        tokio::spawn(run_gc(shared_proxy_db.clone(), gc_config));
+    }
+
     let shared_filter = warp::any().map(move || shared_proxy_db.clone());
 
+    let liveness_thresholds =
+        LivenessThresholds::from_env().expect("Invalid peer liveness thresholds");
+    let liveness_filter = warp::any().map(move || liveness_thresholds);
+
+    let client_config_settings =
+        ClientConfigSettings::from_env().expect("Invalid WireGuard client config settings");
+    let client_config_filter = warp::any().map(move || client_config_settings.clone());
+
+    let keepalive_settings =
+        KeepaliveSettings::from_env().expect("Invalid peer keepalive settings");
+    let keepalive_filter = warp::any().map(move || keepalive_settings);
+
+    let admin_token = admin_token_from_env();
+    let admin_token_filter = warp::any().map(move || admin_token.clone());
+
     let health_check = warp::get().and(warp::path("health-check")).map(|| "OK");
 
     let register_to_vpn = warp::post()
         .and(warp::path("register-to-vpn"))
         .and(shared_filter.clone())
-        .and(warp::addr::remote())
+        .and(client_config_filter)
+        .and(keepalive_filter)
+        .and(remote_address())
+        .and(warp::query::<RegisterPeerQueryParams>())
         .and(warp::body::json::<RegisterPeerRequestBody>())
-        .and_then(|shared_proxy_db, remote_address, request_body| async move {
-            match handle_register_to_vpn(shared_proxy_db, remote_address, request_body) {
-                Ok(res) => Ok(res),
-                // TODO: handle errors and return appropriate status codes, see https://docs.rs/warp/0.3.3/warp/reject/index.html#example
-                Err(e) => Err(reject::custom(e)),
-            }
-        });
+        .and_then(
+            |shared_proxy_db,
+             client_config_settings,
+             keepalive_settings,
+             remote_address,
+             query_params,
+             request_body| async move {
+                run_blocking(move || {
+                    handle_register_to_vpn(
+                        shared_proxy_db,
+                        client_config_settings,
+                        keepalive_settings,
+                        remote_address,
+                        query_params,
+                        request_body,
+                    )
+                })
+                .await
+            },
+        )
+        // reply with the error here, otherwise the request falls through to the proxy
+        .recover(handle_rejection);
 
     let peer_info = warp::get()
         .and(warp::path("peer-info"))
         .and(shared_filter.clone())
-        .and(warp::addr::remote())
-        .and_then(|shared_proxy_db, remote_address| async move {
-            match handle_peer_info(shared_proxy_db, remote_address) {
-                Ok(res) => Ok(res),
-                // TODO: handle errors and return appropriate status codes, see https://docs.rs/warp/0.3.3/warp/reject/index.html#example
-                Err(e) => Err(reject::custom(e)),
-            }
-        });
+        .and(liveness_filter)
+        .and(remote_address())
+        .and_then(
+            |shared_proxy_db, liveness_thresholds, remote_address| async move {
+                run_blocking(move || {
+                    handle_peer_info(shared_proxy_db, liveness_thresholds, remote_address)
+                })
+                .await
+            },
+        );
+
+    let deregister_peer = warp::delete()
+        .and(warp::path(ADMIN_PATH_PREFIX))
+        .and(warp::path!("peers" / String))
+        .and(shared_filter.clone())
+        .and(admin_token_filter.clone())
+        .and(remote_address())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            |peer_id, shared_proxy_db, admin_token, remote_address, authorization| async move {
+                run_blocking(move || {
+                    handle_deregister_peer(
+                        shared_proxy_db,
+                        admin_token,
+                        peer_id,
+                        remote_address,
+                        authorization,
+                    )
+                })
+                .await
+            },
+        )
+        // reply with the error here, otherwise the request falls through to the proxy
+        .recover(handle_rejection);
+
+    let rotate_peer_key =
+        warp::post()
+            .and(warp::path(ADMIN_PATH_PREFIX))
+            .and(warp::path!("peers" / String / "rotate-key"))
+            .and(shared_filter.clone())
+            .and(admin_token_filter.clone())
+            .and(remote_address())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::body::json::<RotatePeerKeyRequestBody>())
+            .and_then(
+                |peer_id,
+                 shared_proxy_db,
+                 admin_token,
+                 remote_address,
+                 authorization,
+                 request_body| async move {
+                    run_blocking(move || {
+                        handle_rotate_peer_key(
+                            shared_proxy_db,
+                            admin_token,
+                            peer_id,
+                            remote_address,
+                            authorization,
+                            request_body,
+                        )
+                    })
+                    .await
+                },
+            )
+            // reply with the error here, otherwise the request falls through to the proxy
+            .recover(handle_rejection);
+
+    let reconcile_peers = warp::post()
+        .and(warp::path(ADMIN_PATH_PREFIX))
+        .and(warp::path!("reconcile"))
+        .and(shared_filter.clone())
+        .and(warp::any().map(move || orphan_policy))
+        .and(admin_token_filter.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            |shared_proxy_db, orphan_policy, admin_token, authorization| async move {
+                run_blocking(move || {
+                    handle_reconcile(shared_proxy_db, orphan_policy, admin_token, authorization)
+                })
+                .await
+            },
+        )
+        // reply with the error here, otherwise the request falls through to the proxy
+        .recover(handle_rejection);
+
+    let export_registry = warp::get()
+        .and(warp::path(ADMIN_PATH_PREFIX))
+        .and(warp::path!("registry" / "export"))
+        .and(shared_filter.clone())
+        .and(admin_token_filter.clone())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<ExportRegistryQueryParams>())
+        .and_then(
+            |shared_proxy_db, admin_token, authorization, query_params| async move {
+                run_blocking(move || {
+                    handle_export_registry(
+                        shared_proxy_db,
+                        admin_token,
+                        authorization,
+                        query_params,
+                    )
+                })
+                .await
+            },
+        )
+        // reply with the error here, otherwise the request falls through to the proxy
+        .recover(handle_rejection);
+
+    let import_registry = warp::post()
+        .and(warp::path(ADMIN_PATH_PREFIX))
+        .and(warp::path!("registry" / "import"))
+        .and(shared_filter.clone())
+        .and(admin_token_filter)
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::json::<RegistryBundle>())
+        .and_then(
+            |shared_proxy_db, admin_token, authorization, bundle| async move {
+                run_blocking(move || {
+                    handle_import_registry(shared_proxy_db, admin_token, authorization, bundle)
+                })
+                .await
+            },
+        )
+        // reply with the error here, otherwise the request falls through to the proxy
+        .recover(handle_rejection);
 
     let proxy = warp::any()
         // not sure how this impacts memory, but it should be cloned to avoid locking the mutex
         .and(shared_filter.clone())
+        .and(liveness_filter)
         .and(warp::path::full())
         .and(query_params_filter())
         .and(warp::method())
-        .and(warp::addr::remote())
+        .and(remote_address())
         .and(warp::header::headers_cloned())
         // TODO: improve this handler, we don't want to write every time the variables
         .and_then(
-            |shared_proxy_db, path, query_params, method, remote_address, headers| async move {
-                match forward_request(
-                    shared_proxy_db,
-                    path,
-                    query_params,
-                    method,
-                    remote_address,
-                    headers,
-                ) {
-                    Ok(res) => Ok(res),
-                    Err(e) => Err(reject::custom(e)),
-                }
+            |shared_proxy_db,
+             liveness_thresholds,
+             path,
+             query_params,
+             method,
+             remote_address,
+             headers| async move {
+                run_blocking(move || {
+                    forward_request(
+                        shared_proxy_db,
+                        liveness_thresholds,
+                        path,
+                        query_params,
+                        method,
+                        remote_address,
+                        headers,
+                    )
+                })
+                .await
             },
         )
         .untuple_one()
@@ -92,29 +402,61 @@ async fn main() {
         .and_then(proxy_to_and_forward_response)
         .and_then(log_response);
 
-    let app = warp::any().and(health_check.or(register_to_vpn).or(peer_info).or(proxy));
+    let app = warp::any()
+        .and(
+            health_check
+                .or(register_to_vpn)
+                .or(peer_info)
+                .or(deregister_peer)
+                .or(rotate_peer_key)
+                .or(reconcile_peers)
+                .or(export_registry)
+                .or(import_registry)
+                .or(proxy),
+        )
+        .recover(handle_rejection);
 
     let http_port = 8081;
     let https_port = 443;
 
-    println!("Listening on port (HTTP): {}", http_port);
+    let listen_ip = listen_ip();
+
+    println!("Listening on {listen_ip} port (HTTP): {}", http_port);
 
     // spawn proxy server
     // we have to listen to HTTP in any case to handle communication within wireguard network
-    let (_http_addr, http_warp) = warp::serve(app.clone()).bind_ephemeral(([0, 0, 0, 0], http_port));
+    let (_http_addr, http_warp) = warp::serve(app.clone()).bind_ephemeral((listen_ip, http_port));
 
-    if get_env_var("ENABLE_HTTPS") == "true" {
+    let servers = if get_env_var("ENABLE_HTTPS") == "true" {
         println!("HTTPS: enabled on port 443");
         let (_https_addr, https_warp) = warp::serve(app)
             .tls()
             .cert_path(get_env_var("HTTPS_CERT_PATH"))
             .key_path(get_env_var("HTTPS_KEY_PATH"))
-            .bind_ephemeral(([0, 0, 0, 0], https_port));
+            .bind_ephemeral((listen_ip, https_port));
 
-        future::join(http_warp, https_warp).await;
+        future::join(http_warp, https_warp).map(|_| ()).boxed()
     } else {
         println!("HTTPS: disabled");
-        http_warp.await;
+        http_warp.boxed()
+    };
+
+    tokio::select! {
+        _ = servers => {}
+        _ = shutdown_signal() => println!("Shutting down..."),
+    }
+
+    // the last changes are still waiting for the persister
+    persister.flush().await;
+}
+
+/// Resolves when the proxy is asked to stop, with Ctrl+C or by `docker stop` (SIGTERM)
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Error listening for SIGTERM");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = sigterm.recv() => {}
     }
 }
 
@@ -1 +1,10 @@
 pub type GenericError = String;
+
+/// What the [Debug](std::fmt::Debug) impls print in place of a secret (a key or a token),
+/// so that the secrets never end up in the logs
+const REDACTED: &str = "<redacted>";
+
+/// A secret as printed by the [Debug](std::fmt::Debug) impls: [REDACTED] if it's set
+pub fn redacted(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| REDACTED)
+}
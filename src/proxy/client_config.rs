@@ -0,0 +1,103 @@
+use std::{fmt, io::Cursor, net::IpAddr};
+
+use image::{ImageFormat, Luma};
+use ipnet::IpNet;
+use qrcode::QrCode;
+
+use crate::{env::get_env_var_or_none, models::GenericError};
+
+use super::interfaces::InterfaceConfig;
+
+/// The parameters of the client configurations that don't depend on the peer nor on its interface,
+/// read from the env variables:
+/// - `PROXY_SERVER_PUBLIC_URL`: the host the peers connect to, on the listen port of their interface
+/// - `WIREGUARD_CLIENT_DNS`: the DNS servers of the peers (default: none)
+///
+/// The endpoint and the allowed ips can be set for each interface, see [InterfaceConfig].
+/// The keepalive of the peers is the same used on the proxy side, see [super::keepalive::KeepaliveSettings]
+#[derive(Debug, Clone)]
+pub struct ClientConfigSettings {
+    pub public_host: Option<String>,
+    pub dns: Option<String>,
+}
+
+impl ClientConfigSettings {
+    pub fn from_env() -> Result<Self, GenericError> {
+        Ok(Self {
+            public_host: get_env_var_or_none("PROXY_SERVER_PUBLIC_URL"),
+            dns: get_env_var_or_none("WIREGUARD_CLIENT_DNS"),
+        })
+    }
+
+    /// The `host:port` the peers of the interface connect to, if known
+    pub fn endpoint(&self, interface: &InterfaceConfig, listen_port: u16) -> Option<String> {
+        interface.client_endpoint.clone().or_else(|| {
+            self.public_host
+                .as_ref()
+                .map(|host| format!("{host}:{listen_port}"))
+        })
+    }
+}
+
+/// A complete [wg-quick](https://manpages.debian.org/unstable/wireguard-tools/wg-quick.8.en.html) configuration
+/// for a peer, rendered with [fmt::Display]
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// The VPN ips of the peer, one for each family
+    pub addresses: Vec<IpAddr>,
+    pub private_key: String,
+    pub dns: Option<String>,
+    pub server_public_key: String,
+    pub preshared_key: Option<String>,
+    pub endpoint: String,
+    pub allowed_ips: String,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl ClientConfig {
+    /// Renders the configuration as a QR code PNG image, that can be scanned by the WireGuard apps
+    pub fn to_qr_png(&self) -> Result<Vec<u8>, GenericError> {
+        let image = QrCode::new(self.to_string().as_bytes())
+            .map_err(|e| format!("Error encoding the config in a QR code: {e}"))?
+            .render::<Luma<u8>>()
+            .build();
+
+        let mut png = Cursor::new(Vec::new());
+        image
+            .write_to(&mut png, ImageFormat::Png)
+            .map_err(|e| format!("Error encoding the QR code as PNG: {e}"))?;
+
+        Ok(png.into_inner())
+    }
+}
+
+impl fmt::Display for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[Interface]")?;
+        let addresses = self
+            .addresses
+            .iter()
+            .map(|ip| IpNet::from(*ip).to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        writeln!(f, "Address = {addresses}")?;
+        writeln!(f, "PrivateKey = {}", self.private_key)?;
+        if let Some(dns) = &self.dns {
+            writeln!(f, "DNS = {dns}")?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "[Peer]")?;
+        writeln!(f, "PublicKey = {}", self.server_public_key)?;
+        if let Some(preshared_key) = &self.preshared_key {
+            writeln!(f, "PresharedKey = {preshared_key}")?;
+        }
+        writeln!(f, "Endpoint = {}", self.endpoint)?;
+        writeln!(f, "AllowedIPs = {}", self.allowed_ips)?;
+        if let Some(persistent_keepalive) = self.persistent_keepalive {
+            writeln!(f, "PersistentKeepalive = {persistent_keepalive}")?;
+        }
+
+        Ok(())
+    }
+}
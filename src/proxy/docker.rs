@@ -1,40 +1,710 @@
-use std::process::Command;
+use std::{fmt, future::Future, io, path::PathBuf, thread, time::Duration};
 
-use crate::{env::get_env_var, models::GenericError};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    runtime::{Handle, RuntimeFlavor},
+    time::timeout,
+};
 
-/// Runs a Wireguard [wg command](https://manpages.debian.org/unstable/wireguard-tools/wg.8.en.html)
-/// through docker command on the `wireguard` container
-pub fn wg_docker_command(args: Vec<&str>, use_wg_quick: bool) -> Result<String, GenericError> {
-    let wireguard_container_name = get_env_var("WIREGUARD_CONTAINER_NAME");
+use crate::env::get_env_var_or;
 
-    let output = Command::new("docker")
-        .arg("exec")
-        .arg(wireguard_container_name)
-        .arg(if use_wg_quick { "wg-quick" } else { "wg" })
-        .args(args)
-        .output()
-        .expect("failed to execute process");
+const DEFAULT_DOCKER_SOCKET_PATH: &str = "/var/run/docker.sock";
+const DOCKER_TIMEOUT: Duration = Duration::from_secs(30);
+/// How many times the exec instance is inspected while waiting for its exit code
+const EXEC_INSPECT_ATTEMPTS: u32 = 10;
+const EXEC_INSPECT_INTERVAL: Duration = Duration::from_millis(100);
 
-    assert_eq!(output.status.code(), Some(0));
+/// Stream types of the [multiplexed stream](https://docs.docker.com/engine/api/v1.41/#tag/Container/operation/ContainerAttach)
+/// returned when starting an exec instance
+const STDOUT_STREAM: u8 = 1;
+const STDERR_STREAM: u8 = 2;
 
-    match output.status.code() {
-        Some(0) => {
-            let res = String::from_utf8_lossy(&output.stdout);
+#[derive(Debug)]
+pub enum DockerError {
+    /// The Docker socket can't be reached, or the connection dropped
+    Connection(io::Error),
+    /// The Engine API replied with an error status
+    Api { status: u16, message: String },
+    /// The Engine API replied with something we can't understand
+    InvalidResponse(String),
+    /// The command ran, but exited with a non-zero exit code
+    CommandFailed { exit_code: i64, stderr: String },
+}
+
+impl fmt::Display for DockerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DockerError::Connection(e) => write!(f, "Docker connection error: {e}"),
+            DockerError::Api { status, message } => {
+                write!(f, "Docker API error (status {status}): {message}")
+            }
+            DockerError::InvalidResponse(e) => write!(f, "Invalid Docker API response: {e}"),
+            DockerError::CommandFailed { exit_code, stderr } => {
+                write!(f, "exit code: {exit_code}, stderr:\n{stderr}")
+            }
+        }
+    }
+}
+
+impl From<io::Error> for DockerError {
+    fn from(e: io::Error) -> Self {
+        DockerError::Connection(e)
+    }
+}
+
+/// The result of a command executed in a container
+#[derive(Debug, Clone)]
+pub struct ExecOutput {
+    pub exit_code: i64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ExecCreateResponse {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ExecInspectResponse {
+    running: bool,
+    exit_code: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    message: String,
+}
+
+/// Fails with a connection error if the future doesn't complete within [DOCKER_TIMEOUT]
+async fn with_timeout<T>(
+    future: impl Future<Output = Result<T, DockerError>>,
+) -> Result<T, DockerError> {
+    timeout(DOCKER_TIMEOUT, future).await.unwrap_or_else(|_| {
+        Err(DockerError::Connection(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no response within {:?}", DOCKER_TIMEOUT),
+        )))
+    })
+}
+
+/// A minimal async client for the [Docker Engine API](https://docs.docker.com/engine/api/),
+/// talking HTTP/1.1 over the Docker unix socket.
+///
+/// The socket path is read from the `DOCKER_SOCKET_PATH` env variable (default: `/var/run/docker.sock`),
+/// so that the client can also be pointed to a stub server.
+#[derive(Debug)]
+pub struct DockerClient {
+    socket_path: PathBuf,
+}
+
+impl Default for DockerClient {
+    fn default() -> Self {
+        Self::new(PathBuf::from(get_env_var_or(
+            "DOCKER_SOCKET_PATH",
+            DEFAULT_DOCKER_SOCKET_PATH,
+        )))
+    }
+}
+
+impl DockerClient {
+    pub fn new(socket_path: PathBuf) -> Self {
+        Self { socket_path }
+    }
+
+    /// Sends a request to the Engine API and returns the status code and the body of the response.
+    /// The connection is closed by the daemon once the response is complete.
+    async fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<(u16, Vec<u8>), DockerError> {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+
+        with_timeout(async {
+            let mut stream = UnixStream::connect(&self.socket_path).await?;
+            stream
+                .write_all(
+                    format!(
+                        "{method} {path} HTTP/1.1\r\nHost: docker\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+
+            parse_http_response(&response)
+        })
+        .await
+    }
+
+    /// Sends a request to the Engine API and fails if the response status is not a success
+    async fn request_ok(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<Vec<u8>, DockerError> {
+        let (status, body) = self.request(method, path, body).await?;
+
+        if (200..300).contains(&status) {
+            Ok(body)
+        } else {
+            let message = serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|error| error.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).to_string());
+
+            Err(DockerError::Api { status, message })
+        }
+    }
+
+    /// Starts an exec instance with an attached stdin, like `echo <stdin> | docker exec -i ...`.
+    ///
+    /// The daemon [hijacks the connection](https://docs.docker.com/engine/api/v1.41/#tag/Container/operation/ContainerAttach)
+    /// after the response headers: we write stdin to it and close our side, so that the command sees the end of its input,
+    /// and then read the multiplexed output stream until the daemon closes the connection.
+    async fn start_with_stdin(&self, exec_id: &str, stdin: &str) -> Result<Vec<u8>, DockerError> {
+        let body = json!({ "Detach": false, "Tty": false }).to_string();
+
+        let mut stream = UnixStream::connect(&self.socket_path).await?;
+        stream
+            .write_all(
+                format!(
+                    "POST /exec/{exec_id}/start HTTP/1.1\r\nHost: docker\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\r\n{body}",
+                    body.len()
+                )
+                .as_bytes(),
+            )
+            .await?;
 
-            println!("docker stdout:\n{res}");
+        // read the response headers, the output stream may already follow them
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        let headers_end = loop {
+            if let Some(position) = response.windows(4).position(|window| window == b"\r\n\r\n") {
+                break position + 4;
+            }
+
+            match stream.read(&mut buf).await? {
+                0 => {
+                    return Err(DockerError::InvalidResponse(
+                        "connection closed before the end of headers".to_string(),
+                    ))
+                }
+                n => response.extend_from_slice(&buf[..n]),
+            }
+        };
+
+        // with a non-success status the daemon doesn't hijack the connection and sends an error body
+        let (status, _) = parse_http_response(&response[..headers_end])?;
+        if !(status == 101 || (200..300).contains(&status)) {
+            stream.read_to_end(&mut response).await?;
+            let (status, body) = parse_http_response(&response)?;
+            let message = serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|error| error.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).to_string());
+
+            return Err(DockerError::Api { status, message });
+        }
+
+        stream.write_all(stdin.as_bytes()).await?;
+        // closes our side of the connection only
+        stream.shutdown().await?;
+
+        let mut output_stream = response[headers_end..].to_vec();
+        stream.read_to_end(&mut output_stream).await?;
+
+        Ok(output_stream)
+    }
+
+    /// Runs a command in the given container, like `docker exec <container_name> <command>`.
+    /// If `stdin` is set, it's written to the stdin of the command, like `docker exec -i`.
+    ///
+    /// The exec instance is created and started through the Engine API, collecting stdout and stderr from
+    /// its multiplexed output stream. Since the daemon sets the exit code asynchronously once the process
+    /// terminates, the exec instance is then inspected until it is not running anymore.
+    pub async fn exec(
+        &self,
+        container_name: &str,
+        command: Vec<&str>,
+        stdin: Option<&str>,
+    ) -> Result<ExecOutput, DockerError> {
+        let create_response = self
+            .request_ok(
+                "POST",
+                &format!("/containers/{container_name}/exec"),
+                Some(json!({
+                    "AttachStdin": stdin.is_some(),
+                    "AttachStdout": true,
+                    "AttachStderr": true,
+                    "Cmd": command,
+                })),
+            )
+            .await?;
+        let exec_id = serde_json::from_slice::<ExecCreateResponse>(&create_response)
+            .map_err(|e| DockerError::InvalidResponse(e.to_string()))?
+            .id;
+
+        let output_stream = match stdin {
+            Some(stdin) => with_timeout(self.start_with_stdin(&exec_id, stdin)).await?,
+            None => {
+                self.request_ok(
+                    "POST",
+                    &format!("/exec/{exec_id}/start"),
+                    Some(json!({ "Detach": false, "Tty": false })),
+                )
+                .await?
+            }
+        };
+        let (stdout, stderr) = demultiplex_stream(&output_stream)?;
+
+        for _ in 0..EXEC_INSPECT_ATTEMPTS {
+            let inspect_response = self
+                .request_ok("GET", &format!("/exec/{exec_id}/json"), None)
+                .await?;
+            let inspect = serde_json::from_slice::<ExecInspectResponse>(&inspect_response)
+                .map_err(|e| DockerError::InvalidResponse(e.to_string()))?;
+
+            if let (false, Some(exit_code)) = (inspect.running, inspect.exit_code) {
+                return Ok(ExecOutput {
+                    exit_code,
+                    stdout,
+                    stderr,
+                });
+            }
+
+            tokio::time::sleep(EXEC_INSPECT_INTERVAL).await;
+        }
+
+        Err(DockerError::InvalidResponse(format!(
+            "exec instance {exec_id} is still running after its output stream closed"
+        )))
+    }
+}
+
+/// Splits a raw HTTP/1.1 response in status code and body, decoding chunked bodies
+fn parse_http_response(response: &[u8]) -> Result<(u16, Vec<u8>), DockerError> {
+    let invalid = |e: &str| DockerError::InvalidResponse(e.to_string());
+
+    let headers_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or(invalid("missing end of headers"))?;
+    let head = String::from_utf8_lossy(&response[..headers_end]);
+    let body = &response[headers_end + 4..];
+
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|status_line| status_line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(invalid("invalid status line"))?;
+
+    let is_chunked = lines.any(|header| {
+        header.to_lowercase().starts_with("transfer-encoding:")
+            && header.to_lowercase().contains("chunked")
+    });
+
+    if !is_chunked {
+        return Ok((status, body.to_vec()));
+    }
+
+    let mut decoded = Vec::new();
+    let mut rest = body;
+    loop {
+        let size_end = rest
+            .windows(2)
+            .position(|window| window == b"\r\n")
+            .ok_or(invalid("missing chunk size"))?;
+        let size = String::from_utf8_lossy(&rest[..size_end]);
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16)
+            .map_err(|_| invalid("invalid chunk size"))?;
+
+        if size == 0 {
+            return Ok((status, decoded));
+        }
+
+        let chunk_start = size_end + 2;
+        let chunk = rest
+            .get(chunk_start..chunk_start + size)
+            .ok_or(invalid("truncated chunk"))?;
+        decoded.extend_from_slice(chunk);
+        // skip the chunk and its trailing CRLF
+        rest = rest.get(chunk_start + size + 2..).unwrap_or(&[]);
+    }
+}
+
+/// Splits the multiplexed output stream of an exec instance in stdout and stderr.
+/// Each frame has an 8 bytes header: the stream type, 3 zero bytes and the payload size (big endian u32).
+fn demultiplex_stream(mut stream: &[u8]) -> Result<(String, String), DockerError> {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+
+    while !stream.is_empty() {
+        let header = stream.get(..8).ok_or(DockerError::InvalidResponse(
+            "truncated frame header".to_string(),
+        ))?;
+        let size = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let payload = stream
+            .get(8..8 + size)
+            .ok_or(DockerError::InvalidResponse("truncated frame".to_string()))?;
+
+        match header[0] {
+            STDOUT_STREAM => stdout.extend_from_slice(payload),
+            STDERR_STREAM => stderr.extend_from_slice(payload),
+            // stdin is never echoed back
+            _ => {}
+        }
+
+        stream = &stream[8 + size..];
+    }
+
+    Ok((
+        String::from_utf8_lossy(&stdout).to_string(),
+        String::from_utf8_lossy(&stderr).to_string(),
+    ))
+}
 
-            Ok(res.to_string())
+/// Runs the future to completion from synchronous code, like the [crate::proxy::wireguard::WireguardBackend] methods.
+/// On the multi-threaded runtime of the proxy, the worker thread hands its other tasks over to the other workers
+/// while waiting, so that they're not blocked. Otherwise (e.g. in tests), the future runs on a temporary runtime
+fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(|| handle.block_on(future))
         }
-        _ => {
-            let err = format!(
-                "status: {}, stderr:\n{}",
-                output.status,
-                String::from_utf8_lossy(&output.stderr)
+        // a runtime can't be started on a thread that is already running one
+        _ => thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .expect("Error creating the Docker client runtime")
+                        .block_on(future)
+                })
+                .join()
+                .expect("Docker client thread panicked")
+        }),
+    }
+}
+
+/// Runs a command on the given container through the Engine API, optionally writing `stdin` to it,
+/// and returns its stdout, failing if the command exits with a non-zero exit code
+pub fn docker_exec(
+    container_name: &str,
+    command: Vec<&str>,
+    stdin: Option<&str>,
+) -> Result<String, DockerError> {
+    let program = command.first().copied().unwrap_or_default().to_string();
+    let output = block_on(DockerClient::default().exec(container_name, command, stdin))?;
+
+    match output.exit_code {
+        0 => {
+            // the output may contain keys (e.g. `wg show <interface> dump`), so it's never logged
+            println!(
+                "docker exec {program}: {} bytes of stdout",
+                output.stdout.len()
             );
 
+            Ok(output.stdout)
+        }
+        exit_code => {
+            let err = DockerError::CommandFailed {
+                exit_code,
+                stderr: output.stderr,
+            };
+
             println!("Error executing docker command: {err}");
 
             Err(err)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::{UnixListener, UnixStream as StdUnixStream},
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A reply of the stub Engine API
+    enum StubReply {
+        /// A whole HTTP response, after which the connection is closed
+        Http(String),
+        /// Hijacks the connection, like the start of an exec instance with stdin,
+        /// and echoes the stdin it reads on the stdout stream
+        EchoStdin,
+    }
+
+    fn http_reply(status: &str, body: &str) -> StubReply {
+        StubReply::Http(format!(
+            "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        ))
+    }
+
+    /// A frame of the multiplexed output stream
+    fn frame(stream_type: u8, payload: &str) -> Vec<u8> {
+        let mut frame = vec![stream_type, 0, 0, 0];
+        frame.extend((payload.len() as u32).to_be_bytes());
+        frame.extend(payload.as_bytes());
+
+        frame
+    }
+
+    /// Reads a request up to the end of its body, returning its request line
+    fn read_request(stream: &mut StdUnixStream) -> String {
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            request.push(byte[0]);
+        }
+        let head = String::from_utf8(request).unwrap();
+
+        let content_length = head
+            .lines()
+            .find_map(|header| header.strip_prefix("Content-Length: "))
+            .map(|length| length.parse::<usize>().unwrap())
+            .unwrap_or(0);
+        let mut body = vec![0u8; content_length];
+        stream.read_exact(&mut body).unwrap();
+
+        head.lines().next().unwrap().to_string()
+    }
+
+    /// Serves the replies to the next connections on a temporary socket, one per connection,
+    /// returning the client and the request lines the server received
+    fn stub_engine_api(
+        replies: Vec<StubReply>,
+    ) -> (TempDir, DockerClient, thread::JoinHandle<Vec<String>>) {
+        let socket_dir = TempDir::new().unwrap();
+        let socket_path = socket_dir.path().join("docker.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for reply in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                requests.push(read_request(&mut stream));
+
+                match reply {
+                    StubReply::Http(response) => stream.write_all(response.as_bytes()).unwrap(),
+                    StubReply::EchoStdin => {
+                        stream
+                            .write_all(b"HTTP/1.1 101 UPGRADED\r\nConnection: Upgrade\r\nUpgrade: tcp\r\n\r\n")
+                            .unwrap();
+                        let mut stdin = String::new();
+                        stream.read_to_string(&mut stdin).unwrap();
+                        stream.write_all(&frame(STDOUT_STREAM, &stdin)).unwrap();
+                    }
+                }
+            }
+
+            requests
+        });
+
+        (socket_dir, DockerClient::new(socket_path), server)
+    }
+
+    #[test]
+    fn parses_chunked_responses() {
+        let response = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6;name=value\r\n world\r\n0\r\n\r\n";
+
+        let (status, body) = parse_http_response(response).unwrap();
+
+        assert_eq!(status, 200);
+        assert_eq!(body, b"hello world");
+    }
+
+    #[test]
+    fn parses_plain_responses() {
+        let (status, body) =
+            parse_http_response(b"HTTP/1.1 404 Not Found\r\nContent-Length: 2\r\n\r\n{}").unwrap();
+
+        assert_eq!(status, 404);
+        assert_eq!(body, b"{}");
+    }
+
+    #[test]
+    fn rejects_invalid_responses() {
+        assert!(parse_http_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_http_response(b"garbage\r\n\r\n").is_err());
+        assert!(parse_http_response(
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nA\r\nshort\r\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn demultiplexes_the_output_stream() {
+        let mut stream = frame(STDOUT_STREAM, "interfaces: ");
+        stream.extend(frame(STDERR_STREAM, "warning"));
+        stream.extend(frame(STDOUT_STREAM, "wg0\n"));
+
+        let (stdout, stderr) = demultiplex_stream(&stream).unwrap();
+
+        assert_eq!(stdout, "interfaces: wg0\n");
+        assert_eq!(stderr, "warning");
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let stream = frame(STDOUT_STREAM, "wg0\n");
+
+        assert!(demultiplex_stream(&stream[..6]).is_err());
+        assert!(demultiplex_stream(&stream[..10]).is_err());
+    }
+
+    #[tokio::test]
+    async fn runs_a_command() {
+        let mut output = frame(STDOUT_STREAM, "wg0\n");
+        output.extend(frame(STDERR_STREAM, "warning"));
+        let output = String::from_utf8(output).unwrap();
+        let (_socket_dir, client, server) = stub_engine_api(vec![
+            http_reply("201 Created", r#"{"Id":"exec-id"}"#),
+            StubReply::Http(format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.docker.raw-stream\r\n\r\n{output}"
+            )),
+            http_reply("200 OK", r#"{"Running":false,"ExitCode":0}"#),
+        ]);
+
+        let output = client
+            .exec("wireguard", vec!["wg", "show", "interfaces"], None)
+            .await
+            .unwrap();
+
+        assert_eq!(output.exit_code, 0);
+        assert_eq!(output.stdout, "wg0\n");
+        assert_eq!(output.stderr, "warning");
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                "POST /containers/wireguard/exec HTTP/1.1",
+                "POST /exec/exec-id/start HTTP/1.1",
+                "GET /exec/exec-id/json HTTP/1.1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_stdin_to_the_command() {
+        let (_socket_dir, client, server) = stub_engine_api(vec![
+            http_reply("201 Created", r#"{"Id":"exec-id"}"#),
+            StubReply::EchoStdin,
+            http_reply("200 OK", r#"{"Running":false,"ExitCode":0}"#),
+        ]);
+
+        let output = client
+            .exec("wireguard", vec!["wg", "pubkey"], Some("private-key"))
+            .await
+            .unwrap();
+
+        server.join().unwrap();
+        assert_eq!(output.stdout, "private-key");
+    }
+
+    #[tokio::test]
+    async fn reports_the_exit_code() {
+        let (_socket_dir, client, server) = stub_engine_api(vec![
+            http_reply("201 Created", r#"{"Id":"exec-id"}"#),
+            http_reply("200 OK", ""),
+            // the exit code is set asynchronously, after the output stream is closed
+            http_reply("200 OK", r#"{"Running":true,"ExitCode":null}"#),
+            http_reply("200 OK", r#"{"Running":false,"ExitCode":1}"#),
+        ]);
+
+        let output = client
+            .exec("wireguard", vec!["wg", "show", "wg1"], None)
+            .await
+            .unwrap();
+
+        server.join().unwrap();
+        assert_eq!(output.exit_code, 1);
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_statuses() {
+        let (_socket_dir, client, server) = stub_engine_api(vec![http_reply(
+            "404 Not Found",
+            r#"{"message":"No such container: wireguard"}"#,
+        )]);
+
+        let error = client
+            .exec("wireguard", vec!["wg"], None)
+            .await
+            .unwrap_err();
+
+        server.join().unwrap();
+        match error {
+            DockerError::Api { status, message } => {
+                assert_eq!(status, 404);
+                assert_eq!(message, "No such container: wireguard");
+            }
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_to_start_in_a_stopped_container() {
+        let (_socket_dir, client, server) = stub_engine_api(vec![
+            http_reply("201 Created", r#"{"Id":"exec-id"}"#),
+            http_reply("409 Conflict", r#"{"message":"container is not running"}"#),
+        ]);
+
+        let error = client
+            .exec("wireguard", vec!["wg"], Some("stdin"))
+            .await
+            .unwrap_err();
+
+        server.join().unwrap();
+        assert!(
+            matches!(error, DockerError::Api { status: 409, .. }),
+            "{error}"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_from_the_runtime_threads() {
+        let (_socket_dir, client, server) = stub_engine_api(vec![
+            http_reply("201 Created", r#"{"Id":"exec-id"}"#),
+            http_reply("200 OK", ""),
+            http_reply("200 OK", r#"{"Running":false,"ExitCode":0}"#),
+        ]);
+
+        // like a backend method called by a handler
+        let output = block_on(client.exec("wireguard", vec!["wg"], None)).unwrap();
+
+        server.join().unwrap();
+        assert_eq!(output.exit_code, 0);
+    }
+
+    #[test]
+    fn runs_without_a_runtime() {
+        let socket_dir = TempDir::new().unwrap();
+        let client = DockerClient::new(socket_dir.path().join("missing.sock"));
+
+        let result = block_on(client.exec("wireguard", vec!["wg"], None));
+
+        assert!(matches!(result, Err(DockerError::Connection(_))));
+    }
+}
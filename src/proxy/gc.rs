@@ -0,0 +1,163 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use uuid::Uuid;
+
+use crate::{env::get_env_var_or, models::GenericError};
+
+use super::{liveness::unix_now, proxy_db::ProxyDb, wireguard::WireguardBackend};
+
+const DEFAULT_GC_INTERVAL_SECS: &str = "3600";
+
+/// The configuration of the stale peers garbage collector, read from env variables:
+/// - `PEER_RETENTION_SECS`: peers not seen for longer than this are purged. If not set, the garbage collector is disabled
+/// - `PEER_GC_INTERVAL_SECS`: how often the garbage collector runs (default: 3600)
+/// - `PEER_GC_DRY_RUN`: if `true`, stale peers are only logged and not purged (default: `false`)
+#[derive(Debug, Clone, Copy)]
+pub struct GcConfig {
+    pub retention: Duration,
+    pub interval: Duration,
+    pub dry_run: bool,
+}
+
+impl GcConfig {
+    /// Returns `None` if the garbage collector is disabled
+    pub fn from_env() -> Result<Option<Self>, GenericError> {
+        let retention = match std::env::var("PEER_RETENTION_SECS") {
+            Ok(retention) => retention
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid PEER_RETENTION_SECS: {e}"))?,
+            Err(_) => return Ok(None),
+        };
+        let interval = get_env_var_or("PEER_GC_INTERVAL_SECS", DEFAULT_GC_INTERVAL_SECS)
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid PEER_GC_INTERVAL_SECS: {e}"))?;
+
+        if interval == 0 {
+            return Err("PEER_GC_INTERVAL_SECS must be greater than 0".to_string());
+        }
+
+        Ok(Some(Self {
+            retention: Duration::from_secs(retention),
+            interval: Duration::from_secs(interval),
+            dry_run: get_env_var_or("PEER_GC_DRY_RUN", "false") == "true",
+        }))
+    }
+}
+
+/// A peer purged (or that would be purged, in dry-run mode) by the garbage collector
+#[derive(Debug, Clone)]
+pub struct StalePeer {
+    /// The interface the peer is registered to
+    pub interface_name: String,
+    pub public_key: String,
+    pub id: Option<Uuid>,
+    /// Seconds since the UNIX epoch of the last time the peer was seen alive
+    pub last_seen: u64,
+}
+
+/// Purges the peers that haven't been seen for longer than the retention period
+/// from the VPNs and from the DB, returning them.
+/// The state of the peers is read from WireGuard without locking the DB, which is locked only to purge them
+pub fn collect_stale_peers<B: WireguardBackend>(
+    proxy_db: &Mutex<ProxyDb<B>>,
+    config: &GcConfig,
+) -> Result<Vec<StalePeer>, GenericError> {
+    // we need up to date handshakes to decide which peers are stale
+    let interfaces = proxy_db
+        .lock()
+        .unwrap()
+        .vpns
+        .values()
+        .map(|vpn| (vpn.backend.clone(), vpn.interface_name.clone()))
+        .collect::<Vec<_>>();
+    let dumps = interfaces
+        .into_iter()
+        .map(|(backend, interface_name)| {
+            backend
+                .show_dump(&interface_name)
+                .map(|dump| (interface_name, dump))
+                .map_err(|e| format!("Error refreshing peers: {e}"))
+        })
+        .collect::<Result<Vec<_>, GenericError>>()?;
+
+    let mut proxy_db = proxy_db.lock().unwrap();
+    for (interface_name, dump) in &dumps {
+        proxy_db.apply_dump(interface_name, dump);
+    }
+
+    let retention_start = unix_now().saturating_sub(config.retention.as_secs());
+    let stale_peers = proxy_db
+        .vpns
+        .values()
+        .flat_map(|vpn| vpn.peers.values().map(move |peer| (vpn, peer)))
+        .filter_map(|(vpn, peer)| match peer.last_seen() {
+            Some(last_seen) if last_seen < retention_start => Some(StalePeer {
+                interface_name: vpn.interface_name.clone(),
+                public_key: peer.public_key.clone(),
+                id: peer
+                    .allowed_ips
+                    .iter()
+                    .find_map(|ip| proxy_db.internal_mapping.get(ip))
+                    .map(|peer_info| peer_info.id),
+                last_seen,
+            }),
+            _ => None,
+        })
+        .collect::<Vec<StalePeer>>();
+
+    if config.dry_run {
+        return Ok(stale_peers);
+    }
+
+    let mut purged_peers = Vec::new();
+    for stale_peer in stale_peers {
+        match proxy_db.remove_peer(&stale_peer.interface_name, &stale_peer.public_key) {
+            Ok(_) => purged_peers.push(stale_peer),
+            // we'll try again on the next run
+            Err(e) => println!("GC: error purging peer {}: {e}", stale_peer.public_key),
+        }
+    }
+
+    Ok(purged_peers)
+}
+
+/// Runs the garbage collector every `config.interval`, forever
+pub async fn run_gc<B: WireguardBackend>(proxy_db: Arc<Mutex<ProxyDb<B>>>, config: GcConfig) {
+    println!("GC: enabled with config {:?}", config);
+
+    let mut interval = tokio::time::interval(config.interval);
+
+    loop {
+        interval.tick().await;
+
+        let proxy_db = proxy_db.clone();
+        // the backend may block, so we don't run it on the async runtime threads
+        let result =
+            tokio::task::spawn_blocking(move || collect_stale_peers(&proxy_db, &config)).await;
+
+        match result {
+            Ok(Ok(stale_peers)) => {
+                for stale_peer in &stale_peers {
+                    println!(
+                        "GC: {} peer {} of {} (id: {:?}), last seen at {}",
+                        if config.dry_run {
+                            "[dry run] would purge"
+                        } else {
+                            "purged"
+                        },
+                        stale_peer.public_key,
+                        stale_peer.interface_name,
+                        stale_peer.id,
+                        stale_peer.last_seen
+                    );
+                }
+                println!("GC: {} stale peers", stale_peers.len());
+            }
+            Ok(Err(e)) => println!("GC: error collecting stale peers: {e}"),
+            Err(e) => println!("GC: task failed: {e}"),
+        }
+    }
+}
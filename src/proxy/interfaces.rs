@@ -0,0 +1,155 @@
+use std::{collections::BTreeSet, fmt, fs};
+
+use ipnet::{Ipv4Net, Ipv6Net};
+use serde::Deserialize;
+
+use crate::{
+    env::get_env_var_or_none,
+    models::{redacted, GenericError},
+};
+
+use super::network::{ip_quarantine_from_env, VpnNetwork};
+
+/// The configuration of a WireGuard interface managed by the proxy, which is not persisted in the DB
+#[derive(Clone, Default)]
+pub struct InterfaceConfig {
+    pub name: String,
+    pub network: VpnNetwork,
+    /// The token the peers must send to register to this interface, if any
+    pub registration_token: Option<String>,
+    /// The endpoint in the configs of the peers generated by the proxy,
+    /// if not set the public host of the proxy is used with the listen port of the interface
+    pub client_endpoint: Option<String>,
+    /// The ips routed through the interface by the peers, if not set the networks of the interface
+    pub client_allowed_ips: Option<String>,
+}
+
+impl fmt::Debug for InterfaceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterfaceConfig")
+            .field("name", &self.name)
+            .field("network", &self.network)
+            .field("registration_token", &redacted(&self.registration_token))
+            .field("client_endpoint", &self.client_endpoint)
+            .field("client_allowed_ips", &self.client_allowed_ips)
+            .finish()
+    }
+}
+
+impl InterfaceConfig {
+    /// The ips routed through the interface by the peers, see [InterfaceConfig::client_allowed_ips]
+    pub fn client_allowed_ips(&self) -> String {
+        self.client_allowed_ips.clone().unwrap_or_else(|| {
+            self.network
+                .networks()
+                .iter()
+                .map(|network| network.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        })
+    }
+}
+
+/// An interface in the `WIREGUARD_INTERFACES_PATH` file
+#[derive(Deserialize)]
+struct InterfaceConfigEntry {
+    name: String,
+    ipv4_network: Ipv4Net,
+    ipv6_network: Option<Ipv6Net>,
+    registration_token: Option<String>,
+    client_endpoint: Option<String>,
+    client_allowed_ips: Option<String>,
+}
+
+/// Reads the interfaces managed by the proxy from the JSON file at `WIREGUARD_INTERFACES_PATH`:
+/// ```json
+/// [
+///     { "name": "wg0", "ipv4_network": "10.13.13.0/24", "ipv6_network": "fd13:13:13::/64", "registration_token": "<token>" },
+///     { "name": "wg1", "ipv4_network": "10.14.14.0/24", "client_endpoint": "staging.example.com:51821" }
+/// ]
+/// ```
+/// where all the fields but `name` and `ipv4_network` are optional. The first interface is the default one.
+///
+/// If `WIREGUARD_INTERFACES_PATH` is not set, the proxy manages only the first interface that is up,
+/// configured with the `WIREGUARD_IPV4_NETWORK`, `WIREGUARD_IPV6_NETWORK`,
+/// `WIREGUARD_CLIENT_ENDPOINT` and `WIREGUARD_CLIENT_ALLOWED_IPS` env variables.
+///
+/// `interface_names`: the interfaces that are up, see [super::wireguard::WireguardBackend::interface_names]
+pub fn interfaces_from_env(
+    interface_names: &[String],
+) -> Result<Vec<InterfaceConfig>, GenericError> {
+    let path = match get_env_var_or_none("WIREGUARD_INTERFACES_PATH") {
+        Some(path) => path,
+        None => {
+            let name = interface_names
+                .first()
+                .ok_or("No WireGuard interface is up")?
+                .clone();
+
+            return Ok(vec![InterfaceConfig {
+                name,
+                network: VpnNetwork::from_env()?,
+                registration_token: None,
+                client_endpoint: get_env_var_or_none("WIREGUARD_CLIENT_ENDPOINT"),
+                client_allowed_ips: get_env_var_or_none("WIREGUARD_CLIENT_ALLOWED_IPS"),
+            }]);
+        }
+    };
+
+    let interfaces_json = fs::read_to_string(&path)
+        .map_err(|e| format!("Error reading interfaces from {path}: {e}"))?;
+    let entries = serde_json::from_str::<Vec<InterfaceConfigEntry>>(&interfaces_json)
+        .map_err(|e| format!("Error parsing interfaces from {path}: {e}"))?;
+
+    if entries.is_empty() {
+        return Err(format!("No interface in {path}"));
+    }
+
+    let ip_quarantine = ip_quarantine_from_env()?;
+    let mut interfaces: Vec<InterfaceConfig> = Vec::new();
+    let mut registration_tokens = BTreeSet::new();
+
+    for entry in entries {
+        let name = entry.name;
+
+        if !interface_names.contains(&name) {
+            return Err(format!(
+                "Interface {name} is not up, the interfaces are {interface_names:?}"
+            ));
+        }
+        if interfaces.iter().any(|interface| interface.name == name) {
+            return Err(format!("Interface {name} is configured more than once"));
+        }
+
+        let network = VpnNetwork::new(entry.ipv4_network, entry.ipv6_network, ip_quarantine)
+            .map_err(|e| format!("Invalid network of interface {name}: {e}"))?;
+        if let Some(other) = interfaces
+            .iter()
+            .find(|interface| interface.network.overlaps(&network))
+        {
+            return Err(format!(
+                "The networks of interface {name} overlap with the ones of interface {}",
+                other.name
+            ));
+        }
+
+        let registration_token = entry.registration_token.filter(|token| !token.is_empty());
+        if let Some(token) = &registration_token {
+            if !registration_tokens.insert(token.clone()) {
+                return Err(format!(
+                    "The registration token of interface {name} is used by another interface"
+                ));
+            }
+        }
+
+        interfaces.push(InterfaceConfig {
+            name,
+            network,
+            registration_token,
+            client_endpoint: entry.client_endpoint,
+            client_allowed_ips: entry.client_allowed_ips,
+        });
+    }
+
+    Ok(interfaces)
+}
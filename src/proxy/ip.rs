@@ -1,41 +1,157 @@
-use std::net::Ipv4Addr;
+use std::{collections::BTreeSet, net::IpAddr};
 
-use super::models::AssignedIpsMap;
+use ipnet::IpNet;
 
-type Ipv4Mask = Ipv4Addr;
+use crate::models::GenericError;
 
-fn next_ipv4_address(ip_num: u32, netmask: Ipv4Mask) -> Option<Ipv4Addr> {
-    // Convert the IPv4 address and netmask to 32-bit unsigned integers
-    let netmask_num = u32::from(netmask);
+use super::models::{AssignedIpsMap, ReleasedIpsMap};
 
-    // Compute the network prefix by masking the IP address with the netmask
-    let network_prefix = ip_num & netmask_num;
+/// Returns the lowest address of the network that can be assigned to a peer, skipping:
+/// - the network and broadcast addresses (for IPv6, the Subnet-Router anycast address)
+/// - the address of the wireguard interface
+/// - the addresses assigned to the other peers
+/// - the addresses released by removed peers that are still in quarantine
+/// - the addresses reserved to specific peers
+///
+/// Returns an error if the network has no address left.
+///
+/// Only the skipped addresses can come before the lowest available one,
+/// so the scan is bounded by their number, even in the huge IPv6 networks
+pub fn next_available_address(
+    network: IpNet,
+    interface_addr: IpAddr,
+    assigned_ips: &AssignedIpsMap,
+    quarantined_ips: &ReleasedIpsMap,
+    reserved_ips: &BTreeSet<IpAddr>,
+) -> Result<IpAddr, GenericError> {
+    let network_addr = network.network();
+    // the network address, the interface address and the ones of the peers, plus the available one
+    let max_candidates = 3 + assigned_ips.len() + quarantined_ips.len() + reserved_ips.len();
 
-    // Compute the range of addresses within the network by setting all bits outside the netmask to 1
-    let network_range = netmask_num ^ u32::MAX;
+    // the hosts of an IPv4 network already exclude the network and broadcast addresses
+    network
+        .hosts()
+        .take(max_candidates)
+        .find(|ip| {
+            *ip != network_addr
+                && *ip != interface_addr
+                && !assigned_ips.contains_key(ip)
+                && !quarantined_ips.contains_key(ip)
+                && !reserved_ips.contains(ip)
+        })
+        .ok_or(format!(
+            "VPN IP pool exhausted: no address available in {network}"
+        ))
+}
 
-    // Increment the IP address within the network range
-    let next_ip_num = ip_num.checked_add(1)?.min(network_prefix + network_range);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // Convert the result back to an IPv4 address
-    Some(Ipv4Addr::from(next_ip_num))
-}
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    /// The ips assigned to a single peer
+    fn assigned(ips: &[&str]) -> AssignedIpsMap {
+        ips.iter()
+            .map(|assigned_ip| (ip(assigned_ip), PEER_KEY.to_string()))
+            .collect()
+    }
+
+    fn next_ipv4_address(
+        assigned_ips: &AssignedIpsMap,
+        quarantined_ips: &ReleasedIpsMap,
+        reserved_ips: &BTreeSet<IpAddr>,
+    ) -> Result<IpAddr, GenericError> {
+        next_available_address(
+            "10.13.13.0/29".parse().unwrap(),
+            ip("10.13.13.1"),
+            assigned_ips,
+            quarantined_ips,
+            reserved_ips,
+        )
+    }
 
-pub fn next_available_ipv4_address(
-    ip_addrs: &AssignedIpsMap,
-    netmask: Ipv4Mask,
-    first_addr: Ipv4Addr,
-) -> Option<Ipv4Addr> {
-    // set it to the first address in the network, which is the wireguard interface address
-    let mut max_ip_num = u32::from(first_addr);
+    #[test]
+    fn skips_the_network_broadcast_and_interface_addresses() {
+        let empty = BTreeSet::new();
 
-    for (&ip, _) in ip_addrs {
-        let ip_num = u32::from(ip);
-        // Check if the IP address is greater than the current maximum
-        if ip_num > max_ip_num {
-            max_ip_num = ip_num;
-        }
+        assert_eq!(
+            next_ipv4_address(&AssignedIpsMap::new(), &ReleasedIpsMap::new(), &empty),
+            Ok(ip("10.13.13.2"))
+        );
+        assert_eq!(
+            next_ipv4_address(
+                &assigned(&["10.13.13.2", "10.13.13.3", "10.13.13.4", "10.13.13.5"]),
+                &ReleasedIpsMap::new(),
+                &empty
+            ),
+            Ok(ip("10.13.13.6"))
+        );
+        // the Subnet-Router anycast address is skipped in IPv6 networks
+        assert_eq!(
+            next_available_address(
+                "fd13:13:13::/64".parse().unwrap(),
+                ip("fd13:13:13::1"),
+                &AssignedIpsMap::new(),
+                &ReleasedIpsMap::new(),
+                &empty,
+            ),
+            Ok(ip("fd13:13:13::2"))
+        );
     }
 
-    next_ipv4_address(max_ip_num, netmask)
+    #[test]
+    fn reuses_freed_addresses() {
+        let assigned_ips = assigned(&["10.13.13.2", "10.13.13.4"]);
+
+        assert_eq!(
+            next_ipv4_address(&assigned_ips, &ReleasedIpsMap::new(), &BTreeSet::new()),
+            Ok(ip("10.13.13.3"))
+        );
+    }
+
+    #[test]
+    fn skips_quarantined_and_reserved_addresses() {
+        let quarantined_ips = ReleasedIpsMap::from([(ip("10.13.13.2"), 1700000000)]);
+        let reserved_ips = BTreeSet::from([ip("10.13.13.3")]);
+
+        assert_eq!(
+            next_ipv4_address(&AssignedIpsMap::new(), &quarantined_ips, &reserved_ips),
+            Ok(ip("10.13.13.4"))
+        );
+    }
+
+    #[test]
+    fn fails_when_the_network_is_exhausted() {
+        let assigned_ips = assigned(&["10.13.13.2", "10.13.13.3", "10.13.13.4", "10.13.13.5"]);
+        let quarantined_ips = ReleasedIpsMap::from([(ip("10.13.13.6"), 1700000000)]);
+
+        assert!(next_ipv4_address(&assigned_ips, &quarantined_ips, &BTreeSet::new()).is_err());
+    }
+
+    #[test]
+    fn finds_the_lowest_ipv6_address_in_a_bounded_scan() {
+        let network: IpNet = "fd13:13:13::/64".parse().unwrap();
+        let assigned_ips = network
+            .hosts()
+            .skip(2)
+            .take(1000)
+            .map(|ip| (ip, PEER_KEY.to_string()))
+            .collect::<AssignedIpsMap>();
+
+        assert_eq!(
+            next_available_address(
+                network,
+                ip("fd13:13:13::1"),
+                &assigned_ips,
+                &ReleasedIpsMap::new(),
+                &BTreeSet::new(),
+            ),
+            Ok(ip("fd13:13:13::3ea"))
+        );
+    }
 }
@@ -0,0 +1,62 @@
+use crate::{env::get_env_var_or, models::GenericError};
+
+/// Most NATs drop idle UDP mappings after 30 seconds or more
+const DEFAULT_KEEPALIVE_SECS: &str = "25";
+const DEFAULT_MIN_KEEPALIVE_SECS: &str = "10";
+const DEFAULT_MAX_KEEPALIVE_SECS: &str = "300";
+
+/// The persistent keepalive of the peers, which keeps the tunnels of the peers behind a NAT open.
+/// Read from the env variables:
+/// - `PEER_KEEPALIVE_DEFAULT_SECS`: the keepalive of the peers that don't request one, `0` disables it (default: 25)
+/// - `PEER_KEEPALIVE_MIN_SECS` and `PEER_KEEPALIVE_MAX_SECS`: the bounds of the keepalive requested by the peers
+///   (default: 10 and 300)
+#[derive(Debug, Clone, Copy)]
+pub struct KeepaliveSettings {
+    pub default: Option<u16>,
+    pub min: u16,
+    pub max: u16,
+}
+
+impl KeepaliveSettings {
+    pub fn from_env() -> Result<Self, GenericError> {
+        let read_secs = |var_name: &str, default: &str| {
+            get_env_var_or(var_name, default)
+                .parse::<u16>()
+                .map_err(|e| format!("Invalid {var_name}: {e}"))
+        };
+
+        let settings = Self {
+            default: Some(read_secs(
+                "PEER_KEEPALIVE_DEFAULT_SECS",
+                DEFAULT_KEEPALIVE_SECS,
+            )?)
+            .filter(|secs| *secs > 0),
+            min: read_secs("PEER_KEEPALIVE_MIN_SECS", DEFAULT_MIN_KEEPALIVE_SECS)?,
+            max: read_secs("PEER_KEEPALIVE_MAX_SECS", DEFAULT_MAX_KEEPALIVE_SECS)?,
+        };
+
+        if settings.min == 0 || settings.min > settings.max {
+            return Err(
+                "PEER_KEEPALIVE_MIN_SECS must be greater than 0 and not greater than PEER_KEEPALIVE_MAX_SECS"
+                    .to_string(),
+            );
+        }
+        if let Some(default) = settings.default {
+            if !(settings.min..=settings.max).contains(&default) {
+                return Err("PEER_KEEPALIVE_DEFAULT_SECS must be between PEER_KEEPALIVE_MIN_SECS and PEER_KEEPALIVE_MAX_SECS".to_string());
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// The keepalive to apply to a peer that requested `requested` seconds:
+    /// the default one if it didn't request any, none if it requested `0`, otherwise the requested one within the bounds
+    pub fn resolve(&self, requested: Option<u16>) -> Option<u16> {
+        match requested {
+            None => self.default,
+            Some(0) => None,
+            Some(secs) => Some(secs.clamp(self.min, self.max)),
+        }
+    }
+}
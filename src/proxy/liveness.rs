@@ -0,0 +1,81 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::{env::get_env_var_or, models::GenericError};
+
+/// WireGuard renegotiates the session every 2 minutes while there's traffic (or keepalives),
+/// so an active peer should never have a handshake older than this
+const DEFAULT_IDLE_AFTER_SECS: &str = "180";
+const DEFAULT_OFFLINE_AFTER_SECS: &str = "600";
+
+/// The connection state of a peer, derived from its latest handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerStatus {
+    /// The peer completed a handshake recently
+    Online,
+    /// The peer didn't complete a handshake for a while, but it may just have no traffic
+    Idle,
+    /// The peer didn't complete a handshake for too long (or never did), its tunnel is considered dead
+    Offline,
+}
+
+/// The thresholds used to classify peers, read from the
+/// `PEER_IDLE_AFTER_SECS` (default: 180) and `PEER_OFFLINE_AFTER_SECS` (default: 600) env variables
+#[derive(Debug, Clone, Copy)]
+pub struct LivenessThresholds {
+    pub idle_after: Duration,
+    pub offline_after: Duration,
+}
+
+impl LivenessThresholds {
+    pub fn from_env() -> Result<Self, GenericError> {
+        let read_secs = |var_name: &str, default: &str| {
+            get_env_var_or(var_name, default)
+                .parse::<u64>()
+                .map(Duration::from_secs)
+                .map_err(|e| format!("Invalid {var_name}: {e}"))
+        };
+
+        let thresholds = Self {
+            idle_after: read_secs("PEER_IDLE_AFTER_SECS", DEFAULT_IDLE_AFTER_SECS)?,
+            offline_after: read_secs("PEER_OFFLINE_AFTER_SECS", DEFAULT_OFFLINE_AFTER_SECS)?,
+        };
+
+        if thresholds.idle_after > thresholds.offline_after {
+            return Err(
+                "PEER_IDLE_AFTER_SECS must not be greater than PEER_OFFLINE_AFTER_SECS".to_string(),
+            );
+        }
+
+        Ok(thresholds)
+    }
+
+    /// Classifies a peer given its latest handshake, in seconds since the UNIX epoch
+    pub fn peer_status(&self, latest_handshake: Option<u64>) -> PeerStatus {
+        let latest_handshake = match latest_handshake {
+            Some(latest_handshake) => latest_handshake,
+            None => return PeerStatus::Offline,
+        };
+
+        // a handshake in the future (e.g. clock skew between containers) is as recent as it gets
+        let elapsed = Duration::from_secs(unix_now().saturating_sub(latest_handshake));
+
+        if elapsed <= self.idle_after {
+            PeerStatus::Online
+        } else if elapsed <= self.offline_after {
+            PeerStatus::Idle
+        } else {
+            PeerStatus::Offline
+        }
+    }
+}
+
+/// Seconds since the UNIX epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
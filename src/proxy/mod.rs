@@ -1,5 +1,19 @@
+pub mod client_config;
 mod docker;
-mod models;
+pub mod gc;
+pub mod interfaces;
 mod ip;
+pub mod keepalive;
+pub mod liveness;
+mod models;
+pub mod network;
+pub mod peer_id;
+pub mod persister;
+pub mod poller;
 pub mod proxy_db;
+pub mod reconcile;
+pub mod registry;
+pub mod reservations;
+pub mod storage;
 pub mod vpn;
+pub mod wireguard;
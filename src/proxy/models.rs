@@ -1,12 +1,15 @@
 use std::{
     collections::BTreeMap,
-    net::{Ipv4Addr, SocketAddr},
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
 };
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::models::redacted;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct RegisteredPeer {
     /// The public key of the peer
     pub public_key: String,
@@ -14,14 +17,67 @@ pub struct RegisteredPeer {
     pub preshared_key: Option<String>,
     /// The remote address of the peer
     pub remote_address: Option<SocketAddr>,
-    /// The allowed ips of the peer, which in our case should only contain the ip of the peer
-    pub allowed_ips: Vec<Ipv4Addr>,
+    /// The allowed ips of the peer, which in our case should only contain the ips of the peer:
+    /// one IPv4 address and, if the VPN is dual-stack, one IPv6 address
+    pub allowed_ips: Vec<IpAddr>,
+    /// Seconds between the keepalive packets sent to the peer, `None` if disabled
+    pub persistent_keepalive: Option<u16>,
+    /// Seconds since the UNIX epoch of the latest handshake with the peer, as last read from the VPN
+    pub latest_handshake: Option<u64>,
+    /// Seconds since the UNIX epoch when the peer was registered (or first seen) by the proxy
+    pub registered_at: Option<u64>,
+}
+
+impl fmt::Debug for RegisteredPeer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RegisteredPeer")
+            .field("public_key", &self.public_key)
+            .field("preshared_key", &redacted(&self.preshared_key))
+            .field("remote_address", &self.remote_address)
+            .field("allowed_ips", &self.allowed_ips)
+            .field("persistent_keepalive", &self.persistent_keepalive)
+            .field("latest_handshake", &self.latest_handshake)
+            .field("registered_at", &self.registered_at)
+            .finish()
+    }
+}
+
+impl RegisteredPeer {
+    /// Seconds since the UNIX epoch of the last time we know the peer was alive:
+    /// its latest handshake or, if it never completed one, its registration
+    pub fn last_seen(&self) -> Option<u64> {
+        self.latest_handshake.max(self.registered_at)
+    }
+
+    /// The IPv4 address of the peer in the VPN, if any
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.allowed_ips.iter().find_map(|ip| match ip {
+            IpAddr::V4(ip) => Some(*ip),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    /// The IPv6 address of the peer in the VPN, if any
+    pub fn ipv6(&self) -> Option<Ipv6Addr> {
+        self.allowed_ips.iter().find_map(|ip| match ip {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ip) => Some(*ip),
+        })
+    }
+
+    /// The address used to reach the peer in the VPN: its IPv4 address if it has one, its IPv6 address otherwise
+    pub fn vpn_ip(&self) -> Option<IpAddr> {
+        self.ipv4().map(IpAddr::V4).or(self.ipv6().map(IpAddr::V6))
+    }
 }
 
 /// The peers of the VPN: peer public key -> peer
 pub type RegisteredPeersMap = BTreeMap<String, RegisteredPeer>;
 /// The assigned ips of the peers: ip -> peer public key
-pub type AssignedIpsMap = BTreeMap<Ipv4Addr, String>;
+pub type AssignedIpsMap = BTreeMap<IpAddr, String>;
+/// The ips released by removed peers that can't be assigned yet:
+/// ip -> seconds since the UNIX epoch when it was released
+pub type ReleasedIpsMap = BTreeMap<IpAddr, u64>;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
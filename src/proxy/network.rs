@@ -0,0 +1,229 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+
+use crate::{
+    env::{get_env_var_or, get_env_var_or_none},
+    models::GenericError,
+};
+
+/// The same network of the `INTERNAL_SUBNET` default of the linuxserver/wireguard image
+const DEFAULT_IPV4_NETWORK: &str = "10.13.13.0/24";
+
+/// The address ranges of the VPN of an interface. In each network, the first address is reserved for the WireGuard interface.
+/// - IPv4: any prefix length up to `/30` (default: `10.13.13.0/24`)
+/// - IPv6: any prefix length up to `/126` (e.g. a ULA `/64` like `fd13:13:13::/64`). If not set, the VPN is IPv4 only
+///
+/// Every peer gets an address of each enabled family.
+///
+/// The addresses of removed peers are reused for new peers only after `PEER_IP_QUARANTINE_SECS` seconds
+/// (default: 0, reused right away), so that traffic still addressed to a removed peer doesn't reach a new one
+#[derive(Debug, Clone, Copy)]
+pub struct VpnNetwork {
+    pub ipv4: Ipv4Net,
+    pub ipv6: Option<Ipv6Net>,
+    pub ip_quarantine: Duration,
+}
+
+impl Default for VpnNetwork {
+    fn default() -> Self {
+        Self {
+            // the default network is a valid constant
+            ipv4: DEFAULT_IPV4_NETWORK.parse().unwrap(),
+            ipv6: None,
+            ip_quarantine: Duration::ZERO,
+        }
+    }
+}
+
+impl VpnNetwork {
+    /// Reads the networks from the `WIREGUARD_IPV4_NETWORK` and `WIREGUARD_IPV6_NETWORK` env variables
+    pub fn from_env() -> Result<Self, GenericError> {
+        let ipv4 = get_env_var_or("WIREGUARD_IPV4_NETWORK", DEFAULT_IPV4_NETWORK)
+            .parse::<Ipv4Net>()
+            .map_err(|e| format!("Invalid WIREGUARD_IPV4_NETWORK: {e}"))?;
+        let ipv6 = get_env_var_or_none("WIREGUARD_IPV6_NETWORK")
+            .map(|network| {
+                network
+                    .parse::<Ipv6Net>()
+                    .map_err(|e| format!("Invalid WIREGUARD_IPV6_NETWORK: {e}"))
+            })
+            .transpose()?;
+
+        Self::new(ipv4, ipv6, ip_quarantine_from_env()?)
+    }
+
+    /// Checks that the networks are large enough, ignoring their host bits
+    pub fn new(
+        ipv4: Ipv4Net,
+        ipv6: Option<Ipv6Net>,
+        ip_quarantine: Duration,
+    ) -> Result<Self, GenericError> {
+        let ipv4 = ipv4.trunc();
+        let ipv6 = ipv6.map(|network| network.trunc());
+
+        // we need at least the interface address and an address for a peer,
+        // besides the network (and broadcast) addresses
+        if ipv4.prefix_len() > 30 {
+            return Err(format!(
+                "IPv4 network {ipv4} is too small, the prefix length can be at most 30"
+            ));
+        }
+        if let Some(network) = ipv6 {
+            if network.prefix_len() > 126 {
+                return Err(format!(
+                    "IPv6 network {network} is too small, the prefix length can be at most 126"
+                ));
+            }
+        }
+
+        Ok(Self {
+            ipv4,
+            ipv6,
+            ip_quarantine,
+        })
+    }
+
+    /// Checks that the WireGuard interface has the addresses reserved for it in the networks of the VPN,
+    /// with a prefix at most as long as the one of their network, so that the interface routes
+    /// all the addresses assigned to the peers.
+    /// `interface_addresses`: the addresses of the interface, the check is skipped if empty
+    pub fn check_interface_addresses(
+        &self,
+        interface_addresses: &[IpNet],
+    ) -> Result<(), GenericError> {
+        if interface_addresses.is_empty() {
+            println!("The addresses of the WireGuard interface are unknown, skipping the VPN network check");
+            return Ok(());
+        }
+
+        let expected_addresses = [
+            Some((
+                IpAddr::V4(self.ipv4_interface_address()),
+                IpNet::V4(self.ipv4),
+            )),
+            self.ipv6_interface_address()
+                .zip(self.ipv6)
+                .map(|(address, network)| (IpAddr::V6(address), IpNet::V6(network))),
+        ];
+        for (expected_address, network) in expected_addresses.into_iter().flatten() {
+            if !interface_addresses.iter().any(|address| {
+                address.addr() == expected_address && address.prefix_len() <= network.prefix_len()
+            }) {
+                return Err(format!(
+                    "The WireGuard interface doesn't have the address {expected_address}/{} (or a shorter prefix) of the VPN networks {:?}, its addresses are {:?}",
+                    network.prefix_len(),
+                    self.networks(),
+                    interface_addresses
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The IPv4 address of the WireGuard interface
+    pub fn ipv4_interface_address(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.ipv4.network()) + 1)
+    }
+
+    /// The IPv6 address of the WireGuard interface, if the VPN is dual-stack
+    pub fn ipv6_interface_address(&self) -> Option<Ipv6Addr> {
+        self.ipv6
+            .map(|network| Ipv6Addr::from(u128::from(network.network()) + 1))
+    }
+
+    /// The networks of the VPN, IPv4 first
+    pub fn networks(&self) -> Vec<IpNet> {
+        let mut networks = vec![IpNet::V4(self.ipv4)];
+        networks.extend(self.ipv6.map(IpNet::V6));
+
+        networks
+    }
+
+    /// Whether the ip belongs to one of the networks of the VPN
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.networks().iter().any(|network| network.contains(&ip))
+    }
+
+    /// Whether the VPN shares some addresses with the other one
+    pub fn overlaps(&self, other: &VpnNetwork) -> bool {
+        self.networks().iter().any(|network| {
+            other.networks().iter().any(|other_network| {
+                network.contains(other_network) || other_network.contains(network)
+            })
+        })
+    }
+}
+
+/// Reads the `PEER_IP_QUARANTINE_SECS` env variable (default: 0), see [VpnNetwork]
+pub fn ip_quarantine_from_env() -> Result<Duration, GenericError> {
+    get_env_var_or("PEER_IP_QUARANTINE_SECS", "0")
+        .parse::<u64>()
+        .map(Duration::from_secs)
+        .map_err(|e| format!("Invalid PEER_IP_QUARANTINE_SECS: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(addresses: &[&str]) -> Vec<IpNet> {
+        addresses
+            .iter()
+            .map(|address| address.parse().unwrap())
+            .collect()
+    }
+
+    fn dual_stack_network() -> VpnNetwork {
+        VpnNetwork::new(
+            "10.13.13.0/24".parse().unwrap(),
+            Some("fd13:13:13::/64".parse().unwrap()),
+            Duration::ZERO,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn accepts_matching_interface_addresses() {
+        let network = dual_stack_network();
+
+        assert!(network
+            .check_interface_addresses(&addresses(&["10.13.13.1/24", "fd13:13:13::1/64"]))
+            .is_ok());
+        // a shorter prefix routes the whole network too
+        assert!(network
+            .check_interface_addresses(&addresses(&["10.13.13.1/16", "fd13:13:13::1/48"]))
+            .is_ok());
+        // unknown addresses skip the check
+        assert!(network.check_interface_addresses(&[]).is_ok());
+    }
+
+    #[test]
+    fn rejects_mismatching_interface_addresses() {
+        let network = dual_stack_network();
+
+        assert!(network
+            .check_interface_addresses(&addresses(&["10.14.14.1/24", "fd13:13:13::1/64"]))
+            .is_err());
+        // the IPv6 address is missing
+        assert!(network
+            .check_interface_addresses(&addresses(&["10.13.13.1/24"]))
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_narrower_interface_prefixes() {
+        let network = dual_stack_network();
+
+        assert!(network
+            .check_interface_addresses(&addresses(&["10.13.13.1/25", "fd13:13:13::1/64"]))
+            .is_err());
+        assert!(network
+            .check_interface_addresses(&addresses(&["10.13.13.1/24", "fd13:13:13::1/128"]))
+            .is_err());
+    }
+}
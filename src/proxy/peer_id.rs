@@ -0,0 +1,30 @@
+use uuid::Uuid;
+
+/// The namespace of the IDs derived from the public keys of the peers
+const PEER_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6f6d6e69_612d_4f70_9278_79706565720a);
+
+/// The ID of the peer with the given public key: a version 5 (name-based) UUID of the public key,
+/// so that the same peer gets the same ID every time it registers, even if the DB is rebuilt
+pub fn peer_id_from_public_key(public_key: &str) -> Uuid {
+    Uuid::new_v5(&PEER_ID_NAMESPACE, public_key.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_the_same_id_from_the_same_key() {
+        let public_key = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+
+        // the IDs already handed out to the peers must never change
+        assert_eq!(
+            peer_id_from_public_key(public_key),
+            Uuid::parse_str("0bc8504f-c6f8-51b3-a21e-695e88437b38").unwrap()
+        );
+        assert_ne!(
+            peer_id_from_public_key(public_key),
+            peer_id_from_public_key("CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=")
+        );
+    }
+}
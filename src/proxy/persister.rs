@@ -0,0 +1,82 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{env::get_env_var_or, models::GenericError};
+
+use super::{proxy_db::ProxyDb, wireguard::WireguardBackend};
+
+const DEFAULT_FLUSH_MAX_DELAY_SECS: &str = "1";
+
+/// Reads the `DB_FLUSH_MAX_DELAY_SECS` env variable (default: 1),
+/// which sets how long a change of the DB can wait before it's saved
+pub fn flush_max_delay_from_env() -> Result<Duration, GenericError> {
+    let max_delay = get_env_var_or("DB_FLUSH_MAX_DELAY_SECS", DEFAULT_FLUSH_MAX_DELAY_SECS)
+        .parse::<u64>()
+        .map_err(|e| format!("Invalid DB_FLUSH_MAX_DELAY_SECS: {e}"))?;
+
+    if max_delay == 0 {
+        return Err("DB_FLUSH_MAX_DELAY_SECS must be greater than 0".to_string());
+    }
+
+    Ok(Duration::from_secs(max_delay))
+}
+
+/// Saves the DB in the background, so that the requests never wait for the disk:
+/// the changes only mark the DB as dirty (see [ProxyDb::mark_dirty]),
+/// and all the changes made since the previous flush are saved at once
+#[derive(Debug)]
+pub struct Persister<B> {
+    proxy_db: Arc<Mutex<ProxyDb<B>>>,
+    /// Held while saving, so that the snapshots are saved in the order they're taken
+    flush_lock: tokio::sync::Mutex<()>,
+}
+
+impl<B: WireguardBackend> Persister<B> {
+    pub fn new(proxy_db: Arc<Mutex<ProxyDb<B>>>) -> Self {
+        Self {
+            proxy_db,
+            flush_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Saves the DB if it changed since the previous flush.
+    /// The DB is locked only to take the snapshot, not while it's written to the storage
+    pub async fn flush(&self) {
+        let _flush_guard = self.flush_lock.lock().await;
+
+        // the DB lock may be held by the handlers while they wait for WireGuard, and the storage blocks too,
+        // so neither runs on the async runtime threads
+        let proxy_db = self.proxy_db.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || save_snapshot(&proxy_db)).await {
+            println!("Persister: task failed: {e}");
+        }
+    }
+
+    /// Flushes the DB every `max_delay`, so that a change is saved at most `max_delay` after it's made
+    pub async fn run(self: Arc<Self>, max_delay: Duration) {
+        println!("Persister: saving DB changes every {:?}", max_delay);
+
+        let mut interval = tokio::time::interval(max_delay);
+
+        loop {
+            interval.tick().await;
+            self.flush().await;
+        }
+    }
+}
+
+/// Saves the changes of the DB since the previous snapshot, if any, see [ProxyDb::take_snapshot]
+fn save_snapshot<B: WireguardBackend>(proxy_db: &Mutex<ProxyDb<B>>) {
+    let (storage, snapshot) = match proxy_db.lock().unwrap().take_snapshot() {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+
+    if let Err(e) = storage.save(snapshot) {
+        // the changes of the failed snapshot are lost, so the whole DB is saved again by the next flush
+        println!("Persister: error saving the DB: {e}");
+        proxy_db.lock().unwrap().mark_unsaved();
+    }
+}
@@ -0,0 +1,63 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{env::get_env_var_or, models::GenericError};
+
+use super::{proxy_db::ProxyDb, wireguard::WireguardBackend};
+
+const DEFAULT_POLL_INTERVAL_SECS: &str = "15";
+
+/// Reads the `WIREGUARD_POLL_INTERVAL_SECS` env variable (default: 15),
+/// which sets how often the state of the peers is read from WireGuard
+pub fn poll_interval_from_env() -> Result<Duration, GenericError> {
+    let interval = get_env_var_or("WIREGUARD_POLL_INTERVAL_SECS", DEFAULT_POLL_INTERVAL_SECS)
+        .parse::<u64>()
+        .map_err(|e| format!("Invalid WIREGUARD_POLL_INTERVAL_SECS: {e}"))?;
+
+    if interval == 0 {
+        return Err("WIREGUARD_POLL_INTERVAL_SECS must be greater than 0".to_string());
+    }
+
+    Ok(Duration::from_secs(interval))
+}
+
+/// Periodically reads the state of the peers (remote addresses and handshakes) of every interface from WireGuard
+/// and updates the cached state in the DB, so that requests never have to read it.
+/// The DB is locked only to apply the new state, not while reading it from WireGuard.
+pub async fn run_poller<B: WireguardBackend>(proxy_db: Arc<Mutex<ProxyDb<B>>>, interval: Duration) {
+    println!("Poller: reading WireGuard state every {:?}", interval);
+
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        // the DB lock may be held by the handlers while they wait for WireGuard, and the backend may block,
+        // so neither runs on the async runtime threads
+        let proxy_db = proxy_db.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || poll(&proxy_db)).await {
+            println!("Poller: task failed: {e}");
+        }
+    }
+}
+
+/// Reads the state of the peers of every interface and applies it to the DB,
+/// which is locked only to apply the new state, not while reading it from WireGuard
+fn poll<B: WireguardBackend>(proxy_db: &Mutex<ProxyDb<B>>) {
+    let interfaces = proxy_db
+        .lock()
+        .unwrap()
+        .vpns
+        .values()
+        .map(|vpn| (vpn.backend.clone(), vpn.interface_name.clone()))
+        .collect::<Vec<_>>();
+
+    for (backend, interface_name) in interfaces {
+        match backend.show_dump(&interface_name) {
+            Ok(dump) => proxy_db.lock().unwrap().apply_dump(&interface_name, &dump),
+            Err(e) => println!("Poller: error reading WireGuard state of {interface_name}: {e}"),
+        }
+    }
+}
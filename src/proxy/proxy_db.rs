@@ -1,101 +1,265 @@
-use std::{collections::BTreeMap, fs, net::Ipv4Addr};
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use super::{models::PeerInfo, vpn::Vpn};
+use super::{
+    interfaces::InterfaceConfig,
+    liveness::unix_now,
+    models::{PeerInfo, RegisteredPeer},
+    peer_id::peer_id_from_public_key,
+    reservations::Reservations,
+    storage::{snapshot::DbSnapshot, tracked_map::TrackedMap, DbStorage},
+    vpn::Vpn,
+    wireguard::{dump::WgDump, WireguardBackend},
+};
 
 #[derive(Debug, Default, Serialize, Deserialize)]
-pub struct ProxyDb {
-    /// The mapping between IP assigned in the VPN and the public IP of the peer
-    pub internal_mapping: BTreeMap<Ipv4Addr, PeerInfo>,
-    /// The mapping between the public subdomain/id and peer IP assigned in the VPN
-    pub external_mapping: BTreeMap<Uuid, Ipv4Addr>,
-
-    /// The VPN instance
-    pub vpn: Vpn,
+#[serde(bound = "B: WireguardBackend")]
+pub struct ProxyDb<B> {
+    /// The mapping between IPs assigned in the VPNs (of both families) and the public IP of the peer
+    pub internal_mapping: TrackedMap<IpAddr, PeerInfo>,
+    /// The mapping between the public subdomain/id and peer IP assigned in the VPN,
+    /// see [RegisteredPeer::vpn_ip]
+    pub external_mapping: TrackedMap<Uuid, IpAddr>,
+
+    /// The VPN of each interface managed by the proxy: interface name -> VPN.
+    /// The networks of the interfaces don't overlap, so a VPN IP identifies a peer among all the VPNs
+    pub vpns: BTreeMap<String, Vpn<B>>,
+    /// The interface the peers register to, unless they choose another one
+    #[serde(skip)]
+    pub default_interface: String,
+    /// Where the DB is saved, see [ProxyDb::take_snapshot]
+    #[serde(skip)]
+    storage: Option<Arc<dyn DbStorage<B>>>,
+    /// Whether the DB changed since the last snapshot
+    #[serde(skip)]
+    dirty: bool,
+    /// The interfaces whose VPN was removed since the last snapshot
+    #[serde(skip)]
+    removed_interfaces: Vec<String>,
+    /// Whether the next snapshot has the whole DB, see [DbSnapshot::full]
+    #[serde(skip)]
+    full_snapshot: bool,
 }
 
-impl ProxyDb {
-    pub fn new() -> Self {
+impl<B: WireguardBackend> ProxyDb<B> {
+    /// `interfaces`: the interfaces to manage, the first one being the default one
+    /// `reservations`: the reservations of each interface
+    pub fn new(
+        interfaces: Vec<InterfaceConfig>,
+        mut reservations: BTreeMap<String, Reservations>,
+    ) -> Self {
         let mut instance = ProxyDb::default();
 
-        instance.vpn = instance.new_vpn();
+        let backend = Arc::new(B::default());
+        instance.default_interface = interfaces
+            .first()
+            .map(|interface| interface.name.clone())
+            .unwrap_or_default();
+        for interface in interfaces {
+            let interface_reservations = reservations.remove(&interface.name).unwrap_or_default();
+            instance.add_vpn(backend.clone(), interface, interface_reservations);
+        }
 
         instance
     }
 
-    fn new_vpn(&mut self) -> Vpn {
-        let vpn = Vpn::new().expect("Error creating VPN");
+    /// Starts managing the interface, mapping the peers it already has
+    fn add_vpn(&mut self, backend: Arc<B>, config: InterfaceConfig, reservations: Reservations) {
+        let vpn = Vpn::new(backend, config, reservations).expect("Error creating VPN");
         println!("Initialized VPN: {:?}", vpn);
 
+        let interface_name = vpn.interface_name.clone();
+        let peers = vpn.peers.values().cloned().collect::<Vec<RegisteredPeer>>();
+        self.vpns.insert(interface_name.clone(), vpn);
+
         // we also need to map the registered peers in the DB
-        vpn.peers.iter().for_each(|(_, peer)| {
-            match peer.remote_address.clone() {
+        for peer in peers {
+            match peer.remote_address {
                 Some(addr) => {
-                    let peer_vpn_ip = peer.allowed_ips[0].clone();
-
-                    self.insert_peer(addr.ip().to_string(), peer_vpn_ip.to_string());
+                    let peer_id = self.peer_id(&interface_name, &peer.public_key);
+                    self.insert_peer(&interface_name, peer_id, addr.ip().to_string(), &peer);
                 }
                 None => println!("Peer remote address not set, skipping mapping..."),
             };
-        });
+        }
+    }
+
+    /// The VPN of the interface with the given name
+    pub fn vpn(&self, interface_name: &str) -> Option<&Vpn<B>> {
+        self.vpns.get(interface_name)
+    }
+
+    /// The VPN the ip belongs to: the one that assigned it, or the one whose networks contain it
+    pub fn vpn_of_ip(&self, ip: IpAddr) -> Option<&Vpn<B>> {
+        self.vpns
+            .values()
+            .find(|vpn| vpn.assigned_ips.contains_key(&ip))
+            .or_else(|| {
+                self.vpns
+                    .values()
+                    .find(|vpn| vpn.config.network.contains(ip))
+            })
+    }
+
+    /// The VPN the peer with the given public key is registered to, if any
+    pub fn vpn_of_peer(&self, public_key: &str) -> Option<&Vpn<B>> {
+        self.vpns
+            .values()
+            .find(|vpn| vpn.peers.contains_key(public_key))
+    }
+
+    /// Gets the known peer with the given VPN IP from any VPN, see [Vpn::get_peer]
+    pub fn get_peer(&self, peer_vpn_ip: IpAddr) -> Option<&RegisteredPeer> {
+        self.vpn_of_ip(peer_vpn_ip)
+            .and_then(|vpn| vpn.get_peer(peer_vpn_ip))
+    }
 
-        vpn
+    /// Stops managing the interface, removing its VPN and the mappings of its peers from the DB
+    pub fn remove_vpn(&mut self, interface_name: &str) -> Option<Vpn<B>> {
+        let vpn = self.vpns.remove(interface_name)?;
+        for peer in vpn.peers.values() {
+            self.unmap_peer(peer);
+        }
+        self.removed_interfaces.push(interface_name.to_string());
+        self.mark_dirty();
+
+        Some(vpn)
     }
 
-    /// TODO: change input types to `Ipv4Addr` to avoid parsing and be consistent with the db
-    pub fn insert_peer(&mut self, peer_public_ip: String, peer_vpn_ip: String) -> Uuid {
-        let peer_id = Uuid::new_v4();
+    /// The VPN of the interface with the given name, to change its peers
+    pub fn vpn_mut(&mut self, interface_name: &str) -> Option<&mut Vpn<B>> {
+        self.vpns.get_mut(interface_name)
+    }
 
+    /// The ID of the peer with the given public key, in order of priority:
+    /// - its reserved ID, if any
+    /// - the ID its VPN IPs are already mapped to, so that it's kept when the peer registers again or rotates its key.
+    ///   Only the IPs the VPN already assigns to the peer count, so that a peer never inherits the ID
+    ///   left on a reused IP: when registering a peer, call it before the VPN assigns the IPs
+    /// - the ID derived from its public key, see [peer_id_from_public_key]
+    pub fn peer_id(&self, interface_name: &str, public_key: &str) -> Uuid {
+        let vpn = self.vpn(interface_name);
+        let reserved_id = vpn
+            .and_then(|vpn| vpn.reservations.get(public_key))
+            .and_then(|reservation| reservation.id);
+        let mapped_id = vpn
+            .and_then(|vpn| {
+                let peer = vpn.peers.get(public_key)?;
+                peer.allowed_ips
+                    .iter()
+                    .filter(|peer_vpn_ip| {
+                        vpn.assigned_ips.get(peer_vpn_ip).map(String::as_str) == Some(public_key)
+                    })
+                    .find_map(|peer_vpn_ip| self.internal_mapping.get(peer_vpn_ip))
+            })
+            .map(|peer_info| peer_info.id);
+
+        reserved_id
+            .or(mapped_id)
+            .unwrap_or_else(|| peer_id_from_public_key(public_key))
+    }
+
+    /// Maps all the VPN IPs of the peer to its public IP and to the ID, see [ProxyDb::peer_id],
+    /// which is mapped to the VPN IP used to reach the peer. The other IDs mapped to the peer are removed
+    /// `interface_name`: the interface the peer is registered to
+    pub fn insert_peer(
+        &mut self,
+        interface_name: &str,
+        peer_id: Uuid,
+        peer_public_ip: String,
+        peer: &RegisteredPeer,
+    ) {
         println!(
-            "Mapping peer public IP {} to VPN IP {}. Assigned ID: {}",
-            peer_public_ip, peer_vpn_ip, peer_id
+            "Mapping peer public IP {} to VPN IPs {:?} of {}. Assigned ID: {}",
+            peer_public_ip, peer.allowed_ips, interface_name, peer_id
         );
+        self.map_peer(peer_id, peer_public_ip, peer);
+    }
 
-        // TODO: handle unwrap
-        let peer_vpn_ip: Ipv4Addr = peer_vpn_ip.parse().unwrap();
+    /// Maps all the VPN IPs of the peer to its public IP and the ID to the VPN IP used to reach the peer.
+    /// The other IDs mapped to the peer are removed
+    pub fn map_peer(&mut self, peer_id: Uuid, peer_public_ip: String, peer: &RegisteredPeer) {
+        for peer_vpn_ip in &peer.allowed_ips {
+            self.internal_mapping.insert(
+                *peer_vpn_ip,
+                PeerInfo {
+                    id: peer_id,
+                    public_ip: peer_public_ip.clone(),
+                },
+            );
+        }
+        // e.g. the IPs replaced by the reserved ones and the ID the peer had before its reservation was added
+        self.internal_mapping.retain(|mapped_vpn_ip, peer_info| {
+            peer_info.id != peer_id || peer.allowed_ips.contains(mapped_vpn_ip)
+        });
+        self.external_mapping.retain(|mapped_id, mapped_vpn_ip| {
+            *mapped_id == peer_id || !peer.allowed_ips.contains(mapped_vpn_ip)
+        });
+        if let Some(peer_vpn_ip) = peer.vpn_ip() {
+            self.external_mapping.insert(peer_id, peer_vpn_ip);
+        }
 
-        self.internal_mapping.insert(
-            peer_vpn_ip.clone(),
-            PeerInfo {
-                id: peer_id,
-                public_ip: peer_public_ip,
-            },
-        );
-        self.external_mapping.insert(peer_id, peer_vpn_ip);
+        self.mark_dirty();
+    }
 
-        // save db
-        // TODO: improve the logic to save db to file
-        self.save_db();
+    /// Removes the mappings of the VPN IPs of the peer, returning the id it was mapped to, if any
+    fn unmap_peer(&mut self, peer: &RegisteredPeer) -> Option<Uuid> {
+        let mut peer_id = None;
+        for peer_vpn_ip in &peer.allowed_ips {
+            if let Some(peer_info) = self.internal_mapping.remove(peer_vpn_ip) {
+                peer_id = Some(peer_info.id);
+            }
+            // also remove stale ids that still point to the peer
+            self.external_mapping
+                .retain(|_, mapped_vpn_ip| mapped_vpn_ip != peer_vpn_ip);
+        }
 
         peer_id
     }
 
-    pub fn update_peer(
-        &mut self,
-        peer_vpn_ip: Ipv4Addr,
-        peer_public_ip: String,
-    ) -> Result<PeerInfo, String> {
-        let peer_info = {
-            let peer_info = self
-                .internal_mapping
-                .get_mut(&peer_vpn_ip)
-                .ok_or(format!("Peer with VPN IP {} not found", peer_vpn_ip))?;
-
-            peer_info.public_ip = peer_public_ip;
-            self.external_mapping.insert(peer_info.id, peer_vpn_ip);
-            peer_info.clone()
+    /// Updates the cached state of the peers of the interface with the one read from its VPN,
+    /// including the public IPs of the mapped peers. Saves the DB only if a public IP changed
+    pub fn apply_dump(&mut self, interface_name: &str, dump: &WgDump) {
+        let vpn = match self.vpns.get_mut(interface_name) {
+            Some(vpn) => vpn,
+            None => return,
         };
+        vpn.apply_dump(dump);
 
-        // save db
-        self.save_db();
+        let mut changed = false;
+        for peer in vpn.peers.values() {
+            let peer_public_ip = match peer.remote_address {
+                Some(addr) => addr.ip().to_string(),
+                None => continue,
+            };
+
+            for peer_vpn_ip in &peer.allowed_ips {
+                let peer_info = match self.internal_mapping.get(peer_vpn_ip) {
+                    Some(peer_info) if peer_info.public_ip != peer_public_ip => peer_info,
+                    _ => continue,
+                };
+                println!(
+                    "Peer {} public IP changed from {} to {}",
+                    peer_info.id, peer_info.public_ip, peer_public_ip
+                );
+                // we checked above that the IP is mapped
+                self.internal_mapping
+                    .get_mut(peer_vpn_ip)
+                    .unwrap()
+                    .public_ip = peer_public_ip.clone();
+                changed = true;
+            }
+        }
 
-        Ok(peer_info)
+        if changed {
+            self.mark_dirty();
+        }
     }
 
-    /// Get the public IP of a peer given its VPN IP
-    /// If the public IP can't be found, reads the public IP from the VPN and updates the DB accordingly
+    // Get the public IP of a peer given its VPN IP
+    // If the public IP can't be found, reads the public IP from the VPN and updates the DB accordingly
     // pub fn get_peer_public_ip(&mut self, peer_vpn_ip: Ipv4Addr) -> Result<String, String> {
     //     // TODO: handle unwraps
     //     match self.internal_mapping.get(&peer_vpn_ip) {
@@ -123,49 +287,352 @@ impl ProxyDb {
     //     }
     // }
 
+    /// Removes a peer from the VPN of the interface and from the DB, given its public key
+    /// Returns the removed peer and the id it was mapped to, if any
+    pub fn remove_peer(
+        &mut self,
+        interface_name: &str,
+        public_key: &str,
+    ) -> Result<(RegisteredPeer, Option<Uuid>), String> {
+        let peer = self
+            .vpn_mut(interface_name)
+            .ok_or(format!("Interface {interface_name} not found"))?
+            .remove_peer(public_key)?;
+        let peer_id = self.unmap_peer(&peer);
+
+        println!(
+            "Removed peer {} with VPN IPs {:?} from {}. Assigned ID: {:?}",
+            public_key, peer.allowed_ips, interface_name, peer_id
+        );
+
+        self.mark_dirty();
+
+        Ok((peer, peer_id))
+    }
+
+    /// Replaces the public key of a peer, see [Vpn::rotate_peer_key].
+    /// The peer keeps its VPN IP, so its ID and public IP mappings are left untouched
+    pub fn rotate_peer_key(
+        &mut self,
+        interface_name: &str,
+        old_public_key: &str,
+        new_public_key: String,
+        preshared_key: Option<String>,
+    ) -> Result<RegisteredPeer, String> {
+        let peer = self
+            .vpn_mut(interface_name)
+            .ok_or(format!("Interface {interface_name} not found"))?
+            .rotate_peer_key(old_public_key, new_public_key, preshared_key)?;
+
+        println!(
+            "Rotated key of peer with VPN IPs {:?} from {} to {}",
+            peer.allowed_ips, old_public_key, peer.public_key
+        );
+
+        self.mark_dirty();
+
+        Ok(peer)
+    }
+
     /// Get the internal VPN IP of a peer given its ID
-    pub fn get_peer_internal_ip(&self, peer_id: Uuid) -> Result<Ipv4Addr, String> {
+    pub fn get_peer_internal_ip(&self, peer_id: Uuid) -> Result<IpAddr, String> {
         match self.external_mapping.get(&peer_id) {
             Some(peer_internal_ip) => Ok(peer_internal_ip.to_owned()),
             None => Err(format!("Peer with id {peer_id} not found")),
         }
     }
 
-    /// Get the peer info, refreshing the public IP from the VPN
-    /// TODO: optimize this to avoid refreshing every time (e.g. use a timeout for each peer)
-    pub fn get_peer_info(&mut self, peer_vpn_ip: Ipv4Addr) -> Result<PeerInfo, String> {
-        let peer = self.vpn.refresh_and_get_peer(peer_vpn_ip)?;
-        // TODO: handle unwrap
-        self.update_peer(peer_vpn_ip, peer.remote_address.unwrap().ip().to_string())
+    /// Get the peer info from the cached state.
+    /// If the VPN IP is unknown (e.g. the peer was added to WireGuard manually),
+    /// reads the peer from the VPN the IP belongs to and maps it
+    pub fn get_peer_info(&mut self, peer_vpn_ip: IpAddr) -> Result<PeerInfo, String> {
+        if let Some(peer_info) = self.internal_mapping.get(&peer_vpn_ip) {
+            return Ok(peer_info.clone());
+        }
+
+        let interface_name = self
+            .vpn_of_ip(peer_vpn_ip)
+            .map(|vpn| vpn.interface_name.clone())
+            .ok_or(format!("{peer_vpn_ip} is not an IP of the VPNs"))?;
+        let peer = self
+            .vpn_mut(&interface_name)
+            .ok_or(format!("Interface {interface_name} not found"))?
+            .refresh_and_get_peer(peer_vpn_ip)?;
+        let peer_public_ip = peer
+            .remote_address
+            .ok_or(format!("Peer {} has no remote address", peer_vpn_ip))?
+            .ip()
+            .to_string();
+        let peer_id = self.peer_id(&interface_name, &peer.public_key);
+        self.insert_peer(&interface_name, peer_id, peer_public_ip.clone(), &peer);
+
+        Ok(PeerInfo {
+            id: peer_id,
+            public_ip: peer_public_ip,
+        })
     }
 
-    /// Load the DB from disk
+    /// Load the DB from the storage
     /// If the DB doesn't exist, create a new one
-    pub fn load_db() -> Self {
-        match fs::read_to_string("data/db.json") {
-            Ok(db_json) => {
-                println!("Loading DB from disk...");
-                // TODO: handle unwrap
-                let instance: ProxyDb = serde_json::from_str(&db_json).unwrap();
+    /// `interfaces` and `reservations`: the configuration of the VPNs, which is not persisted in the DB, see [ProxyDb::new]
+    pub fn load_db(
+        storage: Arc<dyn DbStorage<B>>,
+        interfaces: Vec<InterfaceConfig>,
+        reservations: BTreeMap<String, Reservations>,
+    ) -> Self {
+        let mut instance = match storage.load() {
+            Ok(Some(mut instance)) => {
+                // the loaded entries are already stored, only the changes made from now on are saved
+                instance.take_changes();
+                instance.configure(interfaces, reservations);
+                instance.remove_stale_ids();
 
-                println!("Initialized VPN: {:?}", instance.vpn);
+                instance
+            }
+            Ok(None) => {
+                println!("No valid DB found, creating new one from the WireGuard peers...");
+                let mut instance = Self::new(interfaces, reservations);
+                instance.full_snapshot = true;
+
+                instance
+            }
+            Err(e) => {
+                println!("Error loading the DB, creating new one from the WireGuard peers: {e}");
+                let mut instance = Self::new(interfaces, reservations);
+                // the new DB replaces whatever is stored
+                instance.full_snapshot = true;
 
                 instance
             }
-            Err(_) => {
-                println!("DB not found, creating new one...");
-                Self::new()
+        };
+
+        // the changes made while loading are saved all at once by the persister
+        instance.storage = Some(storage);
+        instance.mark_dirty();
+
+        instance
+    }
+
+    /// Applies the configuration to the VPNs loaded from disk: the VPNs of the interfaces
+    /// that are no longer managed are dropped, and the ones of the new interfaces are created
+    fn configure(
+        &mut self,
+        interfaces: Vec<InterfaceConfig>,
+        mut reservations: BTreeMap<String, Reservations>,
+    ) {
+        let backend = Arc::new(B::default());
+        self.default_interface = interfaces
+            .first()
+            .map(|interface| interface.name.clone())
+            .unwrap_or_default();
+
+        let dropped_interfaces = self
+            .vpns
+            .keys()
+            .filter(|name| !interfaces.iter().any(|interface| &interface.name == *name))
+            .cloned()
+            .collect::<Vec<String>>();
+        for name in dropped_interfaces {
+            // we checked above that the VPN exists
+            let vpn = self.remove_vpn(&name).unwrap();
+            println!(
+                "Interface {name} is no longer managed, removing its {} peers from the DB",
+                vpn.peers.len()
+            );
+        }
+
+        // peers registered before we tracked the registration time
+        // are considered registered now
+        let now = unix_now();
+        for interface in interfaces {
+            let interface_reservations = reservations.remove(&interface.name).unwrap_or_default();
+
+            match self.vpns.get_mut(&interface.name) {
+                Some(vpn) => {
+                    vpn.backend = backend.clone();
+                    vpn.config = interface;
+                    vpn.reservations = interface_reservations;
+                    if let Err(e) = vpn.refresh_interface_info() {
+                        println!("{e}");
+                    }
+
+                    let unregistered_peers = vpn
+                        .peers
+                        .values()
+                        .filter(|peer| peer.registered_at.is_none())
+                        .map(|peer| peer.public_key.clone())
+                        .collect::<Vec<String>>();
+                    for public_key in unregistered_peers {
+                        if let Some(peer) = vpn.peers.get_mut(&public_key) {
+                            peer.registered_at = Some(now);
+                        }
+                    }
+
+                    println!("Initialized VPN: {:?}", vpn);
+                }
+                None => {
+                    println!(
+                        "Interface {} not found in the DB, adding it...",
+                        interface.name
+                    );
+                    self.add_vpn(backend.clone(), interface, interface_reservations);
+                }
             }
         }
     }
 
-    /// Save the DB to disk
-    pub fn save_db(&self) {
-        // TODO: handle unwrap
-        let db_json = serde_json::to_string(&self).unwrap();
+    /// Removes the IDs that don't point to their peer anymore,
+    /// e.g. the ones assigned on every registration before the IDs were preserved
+    fn remove_stale_ids(&mut self) {
+        let internal_mapping = &self.internal_mapping;
+        let ids_count = self.external_mapping.len();
+        self.external_mapping.retain(|peer_id, peer_vpn_ip| {
+            internal_mapping
+                .get(peer_vpn_ip)
+                .is_some_and(|peer_info| peer_info.id == *peer_id)
+        });
+
+        let removed_count = ids_count - self.external_mapping.len();
+        if removed_count > 0 {
+            println!("Removed {removed_count} stale peer IDs from the DB");
+            self.mark_dirty();
+        }
+    }
+
+    /// Marks the DB as changed, so that it's saved by the persister, see [super::persister]
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Marks the whole DB as changed, e.g. after a snapshot failed to be saved,
+    /// so that the next snapshot replaces the saved DB, see [DbSnapshot::full]
+    pub fn mark_unsaved(&mut self) {
+        self.full_snapshot = true;
+        self.mark_dirty();
+    }
 
-        println!("serialized db: {db_json}");
+    /// Takes the changes of the DB since the previous call, see [DbSnapshot]
+    pub fn take_changes(&mut self) -> DbSnapshot {
+        let full = std::mem::take(&mut self.full_snapshot);
+        if full {
+            self.internal_mapping.mark_all_changed();
+            self.external_mapping.mark_all_changed();
+            for vpn in self.vpns.values_mut() {
+                vpn.mark_all_changed();
+            }
+        }
+
+        DbSnapshot {
+            full,
+            removed_interfaces: std::mem::take(&mut self.removed_interfaces),
+            vpns: self.vpns.values_mut().map(Vpn::take_changes).collect(),
+            internal_mapping: self.internal_mapping.take_changes(),
+            external_mapping: self.external_mapping.take_changes(),
+        }
+    }
+
+    /// Takes the changes of the DB if it changed since the last snapshot, to be saved to the returned storage
+    /// without locking the DB. Returns `None` if there's nothing to save
+    pub fn take_snapshot(&mut self) -> Option<(Arc<dyn DbStorage<B>>, DbSnapshot)> {
+        if !self.dirty {
+            return None;
+        }
+        let storage = self.storage.clone()?;
+        self.dirty = false;
+
+        Some((storage, self.take_changes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::wireguard::memory::MemoryBackend;
+
+    use super::*;
+
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+    const NEW_PEER_KEY: &str = "CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=";
+    const PEER_PUBLIC_IP: &str = "203.0.113.7";
+
+    fn interface() -> InterfaceConfig {
+        InterfaceConfig {
+            name: "wg0".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A proxy managing `wg0` on the backend
+    fn new_proxy_db(backend: Arc<MemoryBackend>) -> ProxyDb<MemoryBackend> {
+        let mut proxy_db = ProxyDb {
+            default_interface: "wg0".to_string(),
+            ..Default::default()
+        };
+        proxy_db.add_vpn(backend, interface(), Reservations::default());
+
+        proxy_db
+    }
+
+    /// Registers the peer to `wg0` like the `/register-to-vpn` endpoint, returning its ID
+    fn register(proxy_db: &mut ProxyDb<MemoryBackend>, public_key: &str) -> Uuid {
+        let peer_id = proxy_db.peer_id("wg0", public_key);
+        let peer = proxy_db
+            .vpn_mut("wg0")
+            .unwrap()
+            .add_or_update_peer(public_key.to_string(), None, None, None)
+            .unwrap();
+        proxy_db.insert_peer("wg0", peer_id, PEER_PUBLIC_IP.to_string(), &peer);
+
+        peer_id
+    }
+
+    #[test]
+    fn keeps_the_id_of_a_peer_that_registers_again() {
+        let backend = Arc::new(MemoryBackend::new(vec!["wg0".to_string()]));
+        let mut proxy_db = new_proxy_db(backend.clone());
+
+        let peer_id = register(&mut proxy_db, PEER_KEY);
+
+        assert_eq!(peer_id, peer_id_from_public_key(PEER_KEY));
+        assert_eq!(register(&mut proxy_db, PEER_KEY), peer_id);
+        // the DB rebuilt from the interface derives the same ID
+        let mut rebuilt_proxy_db = new_proxy_db(backend);
+        assert_eq!(register(&mut rebuilt_proxy_db, PEER_KEY), peer_id);
+    }
+
+    #[test]
+    fn keeps_the_id_of_a_peer_that_rotates_its_key() {
+        let mut proxy_db = new_proxy_db(Arc::new(MemoryBackend::new(vec!["wg0".to_string()])));
+        let peer_id = register(&mut proxy_db, PEER_KEY);
+
+        proxy_db
+            .rotate_peer_key("wg0", PEER_KEY, NEW_PEER_KEY.to_string(), None)
+            .unwrap();
+
+        assert_eq!(register(&mut proxy_db, NEW_PEER_KEY), peer_id);
+        let peer_vpn_ip = proxy_db.vpn("wg0").unwrap().peers[NEW_PEER_KEY]
+            .vpn_ip()
+            .unwrap();
+        assert_eq!(proxy_db.external_mapping[&peer_id], peer_vpn_ip);
+    }
 
-        fs::write("data/db.json", db_json).unwrap();
+    #[test]
+    fn never_inherits_the_id_left_on_a_reused_ip() {
+        let mut proxy_db = new_proxy_db(Arc::new(MemoryBackend::new(vec!["wg0".to_string()])));
+        let peer_id = register(&mut proxy_db, PEER_KEY);
+        // the peer is removed from the VPN, but its mappings are left behind
+        proxy_db
+            .vpn_mut("wg0")
+            .unwrap()
+            .remove_peer(PEER_KEY)
+            .unwrap();
+
+        let new_peer_id = register(&mut proxy_db, NEW_PEER_KEY);
+
+        assert_eq!(new_peer_id, peer_id_from_public_key(NEW_PEER_KEY));
+        let peer_vpn_ip = proxy_db.vpn("wg0").unwrap().peers[NEW_PEER_KEY]
+            .vpn_ip()
+            .unwrap();
+        assert_eq!(proxy_db.internal_mapping[&peer_vpn_ip].id, new_peer_id);
+        assert!(!proxy_db.external_mapping.contains_key(&peer_id));
     }
 }
@@ -0,0 +1,199 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{env::get_env_var_or, models::GenericError};
+
+use super::{models::RegisteredPeer, proxy_db::ProxyDb, wireguard::WireguardBackend};
+
+/// What the reconciliation does with the orphans, i.e. the peers configured in WireGuard that can't be adopted
+/// and the records of the DB that don't belong to any peer.
+/// Read from the `PEER_RECONCILE_ORPHANS` env variable:
+/// - `keep` (default): the orphans are only reported
+/// - `remove`: the orphans are removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanPolicy {
+    Keep,
+    Remove,
+}
+
+impl OrphanPolicy {
+    pub fn from_env() -> Result<Self, GenericError> {
+        match get_env_var_or("PEER_RECONCILE_ORPHANS", "keep").as_str() {
+            "keep" => Ok(Self::Keep),
+            "remove" => Ok(Self::Remove),
+            other => Err(format!(
+                "Unknown PEER_RECONCILE_ORPHANS policy: {other}, expected one of keep, remove"
+            )),
+        }
+    }
+}
+
+/// An action taken by the reconciliation
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ReconcileAction {
+    /// A peer of the DB missing from WireGuard was added to WireGuard again
+    Reapplied { public_key: String },
+    /// A peer configured differently in WireGuard was configured again as in the DB
+    Updated { public_key: String },
+    /// A peer configured in WireGuard but unknown to the DB was added to the DB
+    Adopted {
+        public_key: String,
+        allowed_ips: Vec<IpAddr>,
+    },
+    /// A peer with a public IP but no ID was assigned one
+    Mapped { public_key: String, id: Uuid },
+    /// A peer configured in WireGuard that can't be adopted
+    OrphanPeer {
+        public_key: String,
+        reason: String,
+        removed: bool,
+    },
+    /// A record of the DB that doesn't belong to any peer
+    OrphanRecord { record: String, removed: bool },
+    /// An action that failed, which is tried again by the next reconciliation
+    Failed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        public_key: Option<String>,
+        error: String,
+    },
+}
+
+/// The actions taken by a reconciliation
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    /// The actions taken on the peers of each interface: interface name -> actions
+    pub interfaces: BTreeMap<String, Vec<ReconcileAction>>,
+    /// The actions taken on the ID and public IP mappings of the peers
+    pub mappings: Vec<ReconcileAction>,
+}
+
+impl ReconcileReport {
+    pub fn is_empty(&self) -> bool {
+        self.interfaces.values().all(|actions| actions.is_empty()) && self.mappings.is_empty()
+    }
+
+    pub fn log(&self) {
+        for (interface_name, actions) in &self.interfaces {
+            for action in actions {
+                println!("Reconcile: {interface_name}: {:?}", action);
+            }
+        }
+        for action in &self.mappings {
+            println!("Reconcile: mappings: {:?}", action);
+        }
+        if self.is_empty() {
+            println!("Reconcile: the DB is in sync with WireGuard");
+        }
+    }
+}
+
+/// Brings the DB and WireGuard back in sync, e.g. after the WireGuard volume was lost or peers were changed with `wg set`:
+/// - the VPN of each interface is compared with its live state, see [super::vpn::Vpn::reconcile]
+/// - the peers that have a public IP but no ID (e.g. the adopted ones) are mapped
+/// - the mappings of IPs that don't belong to any peer, and the IDs that don't point to their peer, are orphans
+///
+/// The DB is marked as changed if any action was taken
+pub fn reconcile<B: WireguardBackend>(
+    proxy_db: &mut ProxyDb<B>,
+    policy: OrphanPolicy,
+) -> ReconcileReport {
+    let remove_orphans = policy == OrphanPolicy::Remove;
+    let mut report = ReconcileReport::default();
+
+    for vpn in proxy_db.vpns.values_mut() {
+        let actions = match vpn.backend.show_dump(&vpn.interface_name) {
+            Ok(dump) => vpn.reconcile(&dump, remove_orphans),
+            Err(e) => vec![ReconcileAction::Failed {
+                public_key: None,
+                error: format!("Error reading the state of {}: {e}", vpn.interface_name),
+            }],
+        };
+        report
+            .interfaces
+            .insert(vpn.interface_name.clone(), actions);
+    }
+
+    report.mappings = reconcile_mappings(proxy_db, remove_orphans);
+
+    if !report.is_empty() {
+        proxy_db.mark_dirty();
+    }
+
+    report
+}
+
+/// Maps the peers that have a public IP but no ID and finds the orphan mappings
+fn reconcile_mappings<B: WireguardBackend>(
+    proxy_db: &mut ProxyDb<B>,
+    remove_orphans: bool,
+) -> Vec<ReconcileAction> {
+    let mut actions = Vec::new();
+
+    let unmapped_peers = proxy_db
+        .vpns
+        .values()
+        .flat_map(|vpn| vpn.peers.values().map(move |peer| (vpn, peer)))
+        .filter(|(_, peer)| {
+            peer.remote_address.is_some()
+                && !peer
+                    .allowed_ips
+                    .iter()
+                    .any(|ip| proxy_db.internal_mapping.contains_key(ip))
+        })
+        .map(|(vpn, peer)| (vpn.interface_name.clone(), peer.clone()))
+        .collect::<Vec<(String, RegisteredPeer)>>();
+    for (interface_name, peer) in unmapped_peers {
+        // we only kept the peers with a remote address above
+        let peer_public_ip = peer.remote_address.unwrap().ip().to_string();
+        let id = proxy_db.peer_id(&interface_name, &peer.public_key);
+        proxy_db.insert_peer(&interface_name, id, peer_public_ip, &peer);
+        actions.push(ReconcileAction::Mapped {
+            public_key: peer.public_key,
+            id,
+        });
+    }
+
+    let orphan_ips = proxy_db
+        .internal_mapping
+        .keys()
+        .filter(|ip| proxy_db.get_peer(**ip).is_none())
+        .copied()
+        .collect::<Vec<IpAddr>>();
+    for ip in orphan_ips {
+        if remove_orphans {
+            proxy_db.internal_mapping.remove(&ip);
+        }
+        actions.push(ReconcileAction::OrphanRecord {
+            record: format!("public IP mapping of {ip}"),
+            removed: remove_orphans,
+        });
+    }
+
+    // e.g. the IDs that a peer had before registering again
+    let orphan_ids = proxy_db
+        .external_mapping
+        .iter()
+        .filter(|(id, ip)| {
+            proxy_db
+                .internal_mapping
+                .get(ip)
+                .map(|peer_info| peer_info.id)
+                != Some(**id)
+        })
+        .map(|(id, _)| *id)
+        .collect::<Vec<Uuid>>();
+    for id in orphan_ids {
+        if remove_orphans {
+            proxy_db.external_mapping.remove(&id);
+        }
+        actions.push(ReconcileAction::OrphanRecord {
+            record: format!("ID {id}"),
+            removed: remove_orphans,
+        });
+    }
+
+    actions
+}
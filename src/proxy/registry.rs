@@ -0,0 +1,467 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::IpAddr,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::models::GenericError;
+
+use super::{
+    liveness::unix_now,
+    models::RegisteredPeer,
+    proxy_db::ProxyDb,
+    reservations::check_reservable,
+    vpn::Vpn,
+    wireguard::{
+        keys::{public_key_from_private, validate_key},
+        WireguardBackend,
+    },
+};
+
+/// The version of the format of the bundle, stored in its `version` field.
+/// Bump it whenever the format changes, so that a proxy never imports a bundle it can't read
+pub const BUNDLE_VERSION: u64 = 1;
+
+/// The peer registry of a proxy, exported by [export_bundle] to move its peers
+/// to another proxy (e.g. on a new instance) with [import_bundle]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistryBundle {
+    pub version: u64,
+    /// Seconds since the UNIX epoch when the bundle was exported
+    pub exported_at: u64,
+    pub interfaces: Vec<BundleInterface>,
+}
+
+/// An interface of the exporting proxy and its peers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleInterface {
+    pub name: String,
+    /// The public key of the interface, which the peers have in their config
+    pub public_key: String,
+    pub listen_port: u16,
+    /// The base64 encoded private key of the interface, only exported if asked,
+    /// since anyone who has it can impersonate the proxy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    pub peers: Vec<BundlePeer>,
+}
+
+/// A registered peer, with its ID and public IP if it's mapped
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundlePeer {
+    pub public_key: String,
+    pub preshared_key: Option<String>,
+    /// The VPN IPs of the peer, IPv4 first
+    pub allowed_ips: Vec<IpAddr>,
+    pub persistent_keepalive: Option<u16>,
+    pub id: Option<Uuid>,
+    pub public_ip: Option<String>,
+    /// Seconds since the UNIX epoch of the latest handshake with the exporting proxy
+    pub latest_handshake: Option<u64>,
+    /// Seconds since the UNIX epoch when the peer was registered
+    pub registered_at: Option<u64>,
+}
+
+impl BundlePeer {
+    fn from_registered<B: WireguardBackend>(proxy_db: &ProxyDb<B>, peer: &RegisteredPeer) -> Self {
+        let peer_info = peer
+            .allowed_ips
+            .iter()
+            .find_map(|peer_vpn_ip| proxy_db.internal_mapping.get(peer_vpn_ip));
+
+        Self {
+            public_key: peer.public_key.clone(),
+            preshared_key: peer.preshared_key.clone(),
+            allowed_ips: peer.allowed_ips.clone(),
+            persistent_keepalive: peer.persistent_keepalive,
+            id: peer_info.map(|peer_info| peer_info.id),
+            public_ip: peer_info.map(|peer_info| peer_info.public_ip.clone()),
+            latest_handshake: peer.latest_handshake,
+            registered_at: peer.registered_at,
+        }
+    }
+
+    /// The peer to register, which has no remote address until it connects to the importing proxy
+    fn to_registered(&self) -> RegisteredPeer {
+        RegisteredPeer {
+            public_key: self.public_key.clone(),
+            preshared_key: self.preshared_key.clone(),
+            remote_address: None,
+            allowed_ips: self.allowed_ips.clone(),
+            persistent_keepalive: self.persistent_keepalive,
+            latest_handshake: self.latest_handshake,
+            registered_at: self.registered_at.or(Some(unix_now())),
+        }
+    }
+}
+
+/// What [import_bundle] applied
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    /// The interfaces whose private key was replaced with the one of the bundle
+    pub server_keys: Vec<String>,
+    /// The public keys of the peers imported into each interface: interface name -> public keys
+    pub peers: BTreeMap<String, Vec<String>>,
+}
+
+impl ImportReport {
+    pub fn log(&self) {
+        for interface_name in &self.server_keys {
+            println!("Import: {interface_name}: restored the private key of the interface");
+        }
+        for (interface_name, public_keys) in &self.peers {
+            println!(
+                "Import: {interface_name}: imported {} peers",
+                public_keys.len()
+            );
+        }
+    }
+}
+
+/// Exports the peers of all the interfaces, with their VPN IPs, keys, IDs and public IPs.
+/// `include_server_keys`: whether to export the private keys of the interfaces too, so that the importing proxy
+/// can take over the interfaces without the peers changing their config
+pub fn export_bundle<B: WireguardBackend>(
+    proxy_db: &ProxyDb<B>,
+    include_server_keys: bool,
+) -> Result<RegistryBundle, GenericError> {
+    let mut interfaces = Vec::new();
+
+    for vpn in proxy_db.vpns.values() {
+        let private_key = if include_server_keys {
+            let private_key = vpn
+                .backend
+                .show_dump(&vpn.interface_name)
+                .map_err(|e| format!("Error reading the state of {}: {e}", vpn.interface_name))?
+                .interface
+                .private_key
+                .ok_or(format!(
+                    "Interface {} has no private key",
+                    vpn.interface_name
+                ))?;
+            Some(private_key)
+        } else {
+            None
+        };
+
+        interfaces.push(BundleInterface {
+            name: vpn.interface_name.clone(),
+            public_key: vpn.interface_public_key.clone(),
+            listen_port: vpn.listen_port,
+            private_key,
+            peers: vpn
+                .peers
+                .values()
+                .map(|peer| BundlePeer::from_registered(proxy_db, peer))
+                .collect(),
+        });
+    }
+
+    Ok(RegistryBundle {
+        version: BUNDLE_VERSION,
+        exported_at: unix_now(),
+        interfaces,
+    })
+}
+
+/// Checks that the bundle can be imported without changing anything:
+/// - it has the version supported by this proxy
+/// - its interfaces are managed by this proxy, and their private keys (if any) match their public keys
+/// - its peers have valid keys, and VPN IPs of the networks of their interface that are neither assigned
+///   nor reserved to other peers
+/// - its IDs are not assigned to other peers
+pub fn check_bundle<B: WireguardBackend>(
+    proxy_db: &ProxyDb<B>,
+    bundle: &RegistryBundle,
+) -> Result<(), GenericError> {
+    if bundle.version != BUNDLE_VERSION {
+        return Err(format!(
+            "The bundle has version {}, but this proxy supports version {BUNDLE_VERSION}",
+            bundle.version
+        ));
+    }
+
+    let mut interface_names = BTreeSet::new();
+    let mut ids = BTreeSet::new();
+    for interface in &bundle.interfaces {
+        if !interface_names.insert(&interface.name) {
+            return Err(format!(
+                "Interface {} is in the bundle more than once",
+                interface.name
+            ));
+        }
+
+        let vpn = proxy_db.vpn(&interface.name).ok_or(format!(
+            "Interface {} of the bundle is not managed by this proxy",
+            interface.name
+        ))?;
+
+        if let Some(private_key) = &interface.private_key {
+            if public_key_from_private(private_key)? != interface.public_key {
+                return Err(format!(
+                    "The private key of interface {} doesn't match its public key",
+                    interface.name
+                ));
+            }
+        }
+
+        let mut ips = BTreeSet::new();
+        for peer in &interface.peers {
+            check_peer(vpn, peer).map_err(|e| {
+                format!(
+                    "Invalid peer {} of interface {}: {e}",
+                    peer.public_key, interface.name
+                )
+            })?;
+
+            for ip in &peer.allowed_ips {
+                if !ips.insert(*ip) {
+                    return Err(format!(
+                        "IP {ip} of interface {} is assigned to more than one peer of the bundle",
+                        interface.name
+                    ));
+                }
+            }
+
+            if let Some(id) = peer.id {
+                if !ids.insert(id) {
+                    return Err(format!(
+                        "ID {id} is assigned to more than one peer of the bundle"
+                    ));
+                }
+                match proxy_db.external_mapping.get(&id) {
+                    Some(mapped_vpn_ip) if !peer.allowed_ips.contains(mapped_vpn_ip) => {
+                        return Err(format!(
+                            "ID {id} of peer {} is assigned to the peer with VPN IP {mapped_vpn_ip}",
+                            peer.public_key
+                        ))
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks the keys and the VPN IPs of a peer of the bundle against the VPN of its interface
+fn check_peer<B: WireguardBackend>(vpn: &Vpn<B>, peer: &BundlePeer) -> Result<(), GenericError> {
+    validate_key(&peer.public_key)?;
+    if let Some(preshared_key) = &peer.preshared_key {
+        validate_key(preshared_key)?;
+    }
+    if peer.id.is_some() != peer.public_ip.is_some() {
+        return Err("the ID and the public IP must be set together".to_string());
+    }
+
+    if peer.allowed_ips.is_empty() {
+        return Err("no VPN IP".to_string());
+    }
+    let reserved_ips = vpn
+        .reservations
+        .get(&peer.public_key)
+        .map(|reservation| reservation.ips())
+        .unwrap_or_default();
+    for ip in &peer.allowed_ips {
+        check_reservable(*ip, &vpn.config.network)?;
+
+        match vpn.assigned_ips.get(ip) {
+            Some(assigned_public_key) if assigned_public_key != &peer.public_key => {
+                return Err(format!("IP {ip} is assigned to {assigned_public_key}"))
+            }
+            _ => {}
+        }
+        if vpn.reservations.reserved_ips().contains(ip) && !reserved_ips.contains(ip) {
+            return Err(format!("IP {ip} is reserved for another peer"));
+        }
+    }
+
+    Ok(())
+}
+
+/// Applies a bundle exported by another proxy, see [export_bundle], to the DB and the WireGuard interfaces:
+/// - the private keys of the bundle, if any, replace the ones of the interfaces
+/// - the peers are added to their interfaces with the same VPN IPs and keys, replacing the peers
+///   with the same public key, see [Vpn::import_peer]
+/// - the IDs and the public IPs of the peers are mapped, so that they're reachable at the same subdomains
+///
+/// The whole bundle is checked before changing anything, see [check_bundle].
+/// The peers already registered that are not in the bundle are kept, and importing the same bundle again is harmless
+pub fn import_bundle<B: WireguardBackend>(
+    proxy_db: &mut ProxyDb<B>,
+    bundle: RegistryBundle,
+) -> Result<ImportReport, GenericError> {
+    check_bundle(proxy_db, &bundle)?;
+
+    // even if the import fails halfway, the DB must be saved with the peers applied so far
+    proxy_db.mark_dirty();
+
+    let mut report = ImportReport::default();
+    for interface in bundle.interfaces {
+        // the interfaces of the bundle are checked above
+        let vpn = proxy_db.vpn_mut(&interface.name).unwrap();
+
+        if let Some(private_key) = &interface.private_key {
+            vpn.backend
+                .set_private_key(&interface.name, private_key)
+                .map_err(|e| {
+                    format!("Error restoring the private key of {}: {e}", interface.name)
+                })?;
+            vpn.refresh_interface_info()?;
+            report.server_keys.push(interface.name.clone());
+        }
+
+        let peers = interface
+            .peers
+            .iter()
+            .map(|bundle_peer| (bundle_peer, bundle_peer.to_registered()))
+            .collect::<Vec<(&BundlePeer, RegisteredPeer)>>();
+        let mut imported = Vec::new();
+        for (_, peer) in &peers {
+            vpn.import_peer(peer.clone())?;
+            imported.push(peer.public_key.clone());
+        }
+        // the peers are already up, so we don't fail if they can't be persisted
+        if let Err(e) = vpn.backend.save_config(&interface.name) {
+            println!(
+                "Error saving {} config after importing the peers: {e}",
+                interface.name
+            );
+        }
+
+        for (bundle_peer, peer) in &peers {
+            if let (Some(id), Some(public_ip)) = (bundle_peer.id, &bundle_peer.public_ip) {
+                proxy_db.map_peer(id, public_ip.clone(), peer);
+            }
+        }
+
+        report.peers.insert(interface.name, imported);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::{
+        interfaces::InterfaceConfig,
+        wireguard::{keys::generate_keypair, memory::MemoryBackend},
+    };
+
+    use super::*;
+
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+    const PEER_PUBLIC_IP: &str = "203.0.113.7";
+
+    /// A proxy managing `wg0` on a new memory backend
+    fn new_proxy_db() -> ProxyDb<MemoryBackend> {
+        let interface = InterfaceConfig {
+            name: "wg0".to_string(),
+            ..Default::default()
+        };
+
+        ProxyDb::new(vec![interface], BTreeMap::new())
+    }
+
+    /// A proxy with its own server key and a registered peer, returning the ID of the peer
+    fn proxy_db_with_peer() -> (ProxyDb<MemoryBackend>, Uuid) {
+        let mut proxy_db = new_proxy_db();
+        let vpn = proxy_db.vpn_mut("wg0").unwrap();
+        let (private_key, _) = generate_keypair();
+        vpn.backend.set_private_key("wg0", &private_key).unwrap();
+        vpn.refresh_interface_info().unwrap();
+
+        let peer = vpn
+            .add_or_update_peer(PEER_KEY.to_string(), None, Some(25), None)
+            .unwrap();
+        let peer_id = proxy_db.peer_id("wg0", PEER_KEY);
+        proxy_db.insert_peer("wg0", peer_id, PEER_PUBLIC_IP.to_string(), &peer);
+
+        (proxy_db, peer_id)
+    }
+
+    #[test]
+    fn imports_an_exported_bundle() {
+        let (proxy_db, peer_id) = proxy_db_with_peer();
+        let bundle = export_bundle(&proxy_db, true).unwrap();
+        // the bundle goes through its JSON format, as when it's moved to another instance
+        let bundle: RegistryBundle =
+            serde_json::from_value(serde_json::to_value(&bundle).unwrap()).unwrap();
+
+        let mut new_proxy_db = new_proxy_db();
+        let report = import_bundle(&mut new_proxy_db, bundle).unwrap();
+
+        assert_eq!(report.server_keys, vec!["wg0".to_string()]);
+        assert_eq!(report.peers["wg0"], vec![PEER_KEY.to_string()]);
+
+        let vpn = new_proxy_db.vpn("wg0").unwrap();
+        let old_vpn = proxy_db.vpn("wg0").unwrap();
+        assert_eq!(vpn.interface_public_key, old_vpn.interface_public_key);
+        let peer = &vpn.peers[PEER_KEY];
+        assert_eq!(peer.allowed_ips, old_vpn.peers[PEER_KEY].allowed_ips);
+        assert_eq!(peer.persistent_keepalive, Some(25));
+        assert_eq!(vpn.assigned_ips[&peer.allowed_ips[0]], PEER_KEY);
+
+        let peer_vpn_ip = peer.vpn_ip().unwrap();
+        assert_eq!(new_proxy_db.external_mapping[&peer_id], peer_vpn_ip);
+        assert_eq!(new_proxy_db.internal_mapping[&peer_vpn_ip].id, peer_id);
+        assert_eq!(
+            new_proxy_db.internal_mapping[&peer_vpn_ip].public_ip,
+            PEER_PUBLIC_IP
+        );
+
+        // the peer is applied to the interface too
+        let dump = vpn.backend.show_dump("wg0").unwrap();
+        assert!(dump.find_peer(PEER_KEY).is_some());
+    }
+
+    #[test]
+    fn exports_server_keys_only_if_asked() {
+        let (proxy_db, _) = proxy_db_with_peer();
+
+        let bundle = export_bundle(&proxy_db, false).unwrap();
+
+        assert_eq!(bundle.version, BUNDLE_VERSION);
+        assert_eq!(bundle.interfaces[0].private_key, None);
+        assert!(serde_json::to_value(&bundle).unwrap()["interfaces"][0]
+            .get("private_key")
+            .is_none());
+    }
+
+    #[test]
+    fn importing_twice_is_harmless() {
+        let (proxy_db, _) = proxy_db_with_peer();
+        let bundle = export_bundle(&proxy_db, false).unwrap();
+
+        let mut new_proxy_db = new_proxy_db();
+        import_bundle(&mut new_proxy_db, bundle.clone()).unwrap();
+        import_bundle(&mut new_proxy_db, bundle).unwrap();
+
+        assert_eq!(new_proxy_db.vpn("wg0").unwrap().peers.len(), 1);
+        assert_eq!(new_proxy_db.external_mapping.len(), 1);
+    }
+
+    #[test]
+    fn rejects_conflicting_bundles() {
+        let (proxy_db, _) = proxy_db_with_peer();
+        let bundle = export_bundle(&proxy_db, false).unwrap();
+
+        // another peer already has the IP of the peer of the bundle
+        let mut conflicting_bundle = bundle.clone();
+        let (_, other_key) = generate_keypair();
+        conflicting_bundle.interfaces[0].peers[0].public_key = other_key;
+        conflicting_bundle.interfaces[0].peers[0].id = Some(Uuid::nil());
+        assert!(check_bundle(&proxy_db, &conflicting_bundle).is_err());
+
+        let mut newer_bundle = bundle.clone();
+        newer_bundle.version = BUNDLE_VERSION + 1;
+        assert!(check_bundle(&new_proxy_db(), &newer_bundle).is_err());
+
+        let mut unknown_interface_bundle = bundle;
+        unknown_interface_bundle.interfaces[0].name = "wg1".to_string();
+        assert!(check_bundle(&new_proxy_db(), &unknown_interface_bundle).is_err());
+    }
+}
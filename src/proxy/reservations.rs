@@ -0,0 +1,299 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{env::get_env_var_or_none, models::GenericError};
+
+use super::{interfaces::InterfaceConfig, network::VpnNetwork, wireguard::keys::validate_key};
+
+/// The VPN IPs (and optionally the ID) reserved for a peer
+#[derive(Debug, Clone, Deserialize)]
+pub struct Reservation {
+    pub public_key: String,
+    /// The IPv4 address of the peer, if not set it's assigned dynamically
+    pub ip: Option<Ipv4Addr>,
+    /// The IPv6 address of the peer, if not set it's assigned dynamically (if the VPN is dual-stack)
+    pub ipv6: Option<Ipv6Addr>,
+    /// The ID of the peer, if not set it's the stable v5 ID derived from its public key
+    pub id: Option<Uuid>,
+    /// The interface the reservation applies to, if not set the default one
+    pub interface: Option<String>,
+}
+
+impl Reservation {
+    /// The reserved VPN IPs, IPv4 first
+    pub fn ips(&self) -> Vec<IpAddr> {
+        let mut ips = Vec::new();
+        ips.extend(self.ip.map(IpAddr::V4));
+        ips.extend(self.ipv6.map(IpAddr::V6));
+
+        ips
+    }
+}
+
+/// The static reservations of the peers of an interface, read from the JSON file at `PEER_RESERVATIONS_PATH`, if set:
+/// ```json
+/// [
+///     { "public_key": "<public-key>", "ip": "10.13.13.10", "ipv6": "fd13:13:13::10", "id": "<uuid>", "interface": "wg0" }
+/// ]
+/// ```
+/// where `ipv6`, `id` and `interface` are optional. The reserved IPs are never assigned to other peers.
+#[derive(Debug, Clone, Default)]
+pub struct Reservations {
+    /// public key -> reservation
+    by_public_key: BTreeMap<String, Reservation>,
+    reserved_ips: BTreeSet<IpAddr>,
+}
+
+impl Reservations {
+    /// Reads the reservations and splits them by interface, the first interface being the default one.
+    /// Returns the reservations of each interface: interface name -> reservations
+    pub fn from_env(
+        interfaces: &[InterfaceConfig],
+    ) -> Result<BTreeMap<String, Self>, GenericError> {
+        let mut reservations = match get_env_var_or_none("PEER_RESERVATIONS_PATH") {
+            Some(path) => {
+                let reservations_json = fs::read_to_string(&path)
+                    .map_err(|e| format!("Error reading reservations from {path}: {e}"))?;
+                serde_json::from_str::<Vec<Reservation>>(&reservations_json)
+                    .map_err(|e| format!("Error parsing reservations from {path}: {e}"))?
+            }
+            None => Vec::new(),
+        };
+
+        // the ids are global, while the ips are checked by each interface
+        let mut reserved_ids = BTreeSet::new();
+        for reservation in &reservations {
+            if let Some(id) = reservation.id {
+                if !reserved_ids.insert(id) {
+                    return Err(format!(
+                        "Reservation of {}: ID {id} is reserved more than once",
+                        reservation.public_key
+                    ));
+                }
+            }
+        }
+
+        let default_interface = interfaces.first().map(|interface| interface.name.as_str());
+        let mut reservations_by_interface = BTreeMap::new();
+        for interface in interfaces {
+            let (interface_reservations, other_reservations) =
+                reservations.into_iter().partition(|reservation| {
+                    reservation.interface.as_deref().or(default_interface)
+                        == Some(interface.name.as_str())
+                });
+            reservations = other_reservations;
+
+            let interface_reservations = Self::new(interface_reservations, &interface.network)
+                .map_err(|e| {
+                    format!("Invalid reservations of interface {}: {e}", interface.name)
+                })?;
+            reservations_by_interface.insert(interface.name.clone(), interface_reservations);
+        }
+
+        if let Some(reservation) = reservations.first() {
+            return Err(format!(
+                "Reservation of {}: unknown interface {}",
+                reservation.public_key,
+                reservation.interface.as_deref().unwrap_or_default()
+            ));
+        }
+
+        Ok(reservations_by_interface)
+    }
+
+    /// Validates the reservations, which must have a valid public key and at least an IP of the VPN networks,
+    /// and must not share public keys, IPs or IDs
+    pub fn new(reservations: Vec<Reservation>, network: &VpnNetwork) -> Result<Self, GenericError> {
+        let mut instance = Self::default();
+        let mut reserved_ids = BTreeSet::new();
+
+        for reservation in reservations {
+            let public_key = reservation.public_key.clone();
+            validate_key(&public_key)
+                .map_err(|e| format!("Invalid public key {public_key} in reservations: {e}"))?;
+
+            let ips = reservation.ips();
+            if ips.is_empty() {
+                return Err(format!("Reservation of {public_key} has no IP"));
+            }
+            for ip in ips {
+                check_reservable(ip, network)
+                    .map_err(|e| format!("Invalid reservation of {public_key}: {e}"))?;
+
+                if !instance.reserved_ips.insert(ip) {
+                    return Err(format!(
+                        "Reservation of {public_key}: IP {ip} is reserved more than once"
+                    ));
+                }
+            }
+            if let Some(id) = reservation.id {
+                if !reserved_ids.insert(id) {
+                    return Err(format!(
+                        "Reservation of {public_key}: ID {id} is reserved more than once"
+                    ));
+                }
+            }
+
+            if instance
+                .by_public_key
+                .insert(public_key.clone(), reservation)
+                .is_some()
+            {
+                return Err(format!(
+                    "Public key {public_key} is reserved more than once"
+                ));
+            }
+        }
+
+        Ok(instance)
+    }
+
+    /// The reservation of the peer with the given public key, if any
+    pub fn get(&self, public_key: &str) -> Option<&Reservation> {
+        self.by_public_key.get(public_key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Reservation> {
+        self.by_public_key.values()
+    }
+
+    /// The IPs reserved to any peer, which can't be assigned dynamically
+    pub fn reserved_ips(&self) -> &BTreeSet<IpAddr> {
+        &self.reserved_ips
+    }
+}
+
+/// Checks that the IP is an address of the VPN networks that can be assigned to a peer
+pub fn check_reservable(ip: IpAddr, network: &VpnNetwork) -> Result<(), GenericError> {
+    let (ip_network, interface_addr) = match ip {
+        IpAddr::V4(_) => (
+            IpNet::V4(network.ipv4),
+            IpAddr::V4(network.ipv4_interface_address()),
+        ),
+        IpAddr::V6(_) => match (network.ipv6, network.ipv6_interface_address()) {
+            (Some(ipv6), Some(interface_addr)) => (IpNet::V6(ipv6), IpAddr::V6(interface_addr)),
+            _ => return Err(format!("IP {ip} is IPv6, but the VPN is IPv4 only")),
+        },
+    };
+
+    if !ip_network.contains(&ip) {
+        return Err(format!("IP {ip} is not in the VPN network {ip_network}"));
+    }
+    if ip == ip_network.network() || (ip.is_ipv4() && ip == ip_network.broadcast()) {
+        return Err(format!("IP {ip} is the network or broadcast address"));
+    }
+    if ip == interface_addr {
+        return Err(format!("IP {ip} is the address of the WireGuard interface"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+    const OTHER_PEER_KEY: &str = "CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=";
+
+    fn reservation(public_key: &str, ip: &str) -> Reservation {
+        Reservation {
+            public_key: public_key.to_string(),
+            ip: Some(ip.parse().unwrap()),
+            ipv6: None,
+            id: None,
+            interface: None,
+        }
+    }
+
+    fn check(reservations: Vec<Reservation>) -> Result<Reservations, GenericError> {
+        Reservations::new(reservations, &VpnNetwork::default())
+    }
+
+    #[test]
+    fn accepts_valid_reservations() {
+        let reservations = check(vec![
+            reservation(PEER_KEY, "10.13.13.10"),
+            Reservation {
+                id: Some(Uuid::from_u128(1)),
+                ..reservation(OTHER_PEER_KEY, "10.13.13.11")
+            },
+        ])
+        .unwrap();
+
+        assert_eq!(
+            reservations.get(PEER_KEY).unwrap().ips(),
+            vec![IpAddr::from([10, 13, 13, 10])]
+        );
+        assert_eq!(
+            reservations.reserved_ips(),
+            &BTreeSet::from([
+                IpAddr::from([10, 13, 13, 10]),
+                IpAddr::from([10, 13, 13, 11])
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        // public key
+        assert!(check(vec![
+            reservation(PEER_KEY, "10.13.13.10"),
+            reservation(PEER_KEY, "10.13.13.11"),
+        ])
+        .is_err());
+        // IP
+        assert!(check(vec![
+            reservation(PEER_KEY, "10.13.13.10"),
+            reservation(OTHER_PEER_KEY, "10.13.13.10"),
+        ])
+        .is_err());
+        // ID
+        let id = Some(Uuid::from_u128(1));
+        assert!(check(vec![
+            Reservation {
+                id,
+                ..reservation(PEER_KEY, "10.13.13.10")
+            },
+            Reservation {
+                id,
+                ..reservation(OTHER_PEER_KEY, "10.13.13.11")
+            },
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_unassignable_ips() {
+        // out of the network
+        assert!(check(vec![reservation(PEER_KEY, "10.14.14.10")]).is_err());
+        // the interface, network and broadcast addresses
+        assert!(check(vec![reservation(PEER_KEY, "10.13.13.1")]).is_err());
+        assert!(check(vec![reservation(PEER_KEY, "10.13.13.0")]).is_err());
+        assert!(check(vec![reservation(PEER_KEY, "10.13.13.255")]).is_err());
+        // IPv6 in an IPv4 only VPN
+        assert!(check(vec![Reservation {
+            ip: None,
+            ipv6: Some("fd13:13:13::10".parse().unwrap()),
+            ..reservation(PEER_KEY, "10.13.13.10")
+        }])
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_reservations_without_ips_or_valid_keys() {
+        assert!(check(vec![Reservation {
+            ip: None,
+            ..reservation(PEER_KEY, "10.13.13.10")
+        }])
+        .is_err());
+        assert!(check(vec![reservation("not-a-key", "10.13.13.10")]).is_err());
+    }
+}
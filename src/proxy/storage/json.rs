@@ -0,0 +1,374 @@
+use std::{
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+};
+
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    env::get_env_var_or,
+    models::GenericError,
+    proxy::{proxy_db::ProxyDb, wireguard::WireguardBackend},
+};
+
+use super::{
+    schema::{db_from_json, db_to_json},
+    snapshot::{DbSnapshot, DbState},
+    DbStorage, StoredPeer,
+};
+
+const DEFAULT_DB_BACKUPS: &str = "3";
+
+/// The number of previous versions of the JSON DB to keep,
+/// read from the `DB_BACKUPS` env variable (default: `3`, `0` disables the backups)
+pub fn backups_from_env() -> Result<usize, GenericError> {
+    get_env_var_or("DB_BACKUPS", DEFAULT_DB_BACKUPS)
+        .parse::<usize>()
+        .map_err(|e| format!("Invalid DB_BACKUPS: {e}"))
+}
+
+/// Stores the whole DB in a JSON file, rewritten on every save.
+///
+/// The file is replaced atomically, so that a crash never leaves a truncated DB,
+/// and the previous versions are kept as `<path>.1` (the newest) to `<path>.<backups>`
+#[derive(Debug)]
+pub struct JsonStorage {
+    path: String,
+    backups: usize,
+    /// The DB as last saved (or loaded), which the snapshots are applied to,
+    /// `None` until the DB is loaded or looked up
+    state: Mutex<Option<DbState>>,
+}
+
+impl JsonStorage {
+    pub fn new(path: String, backups: usize) -> Self {
+        Self {
+            path,
+            backups,
+            state: Mutex::new(None),
+        }
+    }
+
+    fn backup_path(&self, index: usize) -> String {
+        format!("{}.{index}", self.path)
+    }
+
+    /// Reads the DB from the file at the path, `None` if it doesn't exist
+    fn read<B: WireguardBackend>(path: &str) -> Result<Option<ProxyDb<B>>, GenericError> {
+        let db_json = match fs::read_to_string(path) {
+            Ok(db_json) => db_json,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Error reading DB from {path}: {e}")),
+        };
+
+        println!("Loading DB from {path}...");
+        let db: Value = serde_json::from_str(&db_json)
+            .map_err(|e| format!("Error parsing DB from {path}: {e}"))?;
+
+        db_from_json(db)
+            .map(Some)
+            .map_err(|e| format!("Error loading DB from {path}: {e}"))
+    }
+
+    /// Shifts the backups by one, dropping the oldest, and makes the current DB the newest backup.
+    /// The current DB was replaced atomically, so it's complete and can be linked instead of copied
+    fn rotate_backups(&self) -> Result<(), GenericError> {
+        if self.backups == 0 || !Path::new(&self.path).exists() {
+            return Ok(());
+        }
+
+        for index in (1..self.backups).rev() {
+            let backup_path = self.backup_path(index);
+            if Path::new(&backup_path).exists() {
+                fs::rename(&backup_path, self.backup_path(index + 1))
+                    .map_err(|e| format!("Error rotating DB backup {backup_path}: {e}"))?;
+            }
+        }
+
+        let newest_backup_path = self.backup_path(1);
+        // the newest backup is still there if the DB keeps a single backup
+        let _ = fs::remove_file(&newest_backup_path);
+        fs::hard_link(&self.path, &newest_backup_path)
+            .or_else(|_| fs::copy(&self.path, &newest_backup_path).map(|_| ()))
+            .map_err(|e| format!("Error backing up DB to {newest_backup_path}: {e}"))
+    }
+}
+
+/// Writes the file atomically: the content is written to a temporary file, flushed to the disk
+/// and then renamed to the path, so that the file at the path is always either the old or the new one
+fn write_atomically(path: &str, content: &[u8]) -> Result<(), GenericError> {
+    let temp_path = format!("{path}.tmp");
+
+    let mut file =
+        File::create(&temp_path).map_err(|e| format!("Error creating {temp_path}: {e}"))?;
+    file.write_all(content)
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Error writing {temp_path}: {e}"))?;
+
+    fs::rename(&temp_path, path)
+        .map_err(|e| format!("Error renaming {temp_path} to {path}: {e}"))?;
+
+    // the rename is durable only once the directory is flushed too
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| format!("Error flushing directory {}: {e}", dir.display()))
+}
+
+impl<B: WireguardBackend> DbStorage<B> for JsonStorage {
+    /// Loads the DB, see [JsonStorage::read_db_or_backups]
+    fn load(&self) -> Result<Option<ProxyDb<B>>, GenericError> {
+        let db = self.read_db_or_backups()?;
+        *self.state.lock().unwrap() = Some(db.as_ref().map(DbState::of).unwrap_or_default());
+
+        Ok(db)
+    }
+
+    fn save(&self, snapshot: DbSnapshot) -> Result<(), GenericError> {
+        let mut state = self.state.lock().unwrap();
+        let state = state.get_or_insert_with(DbState::default);
+        state.apply(snapshot);
+        let db_json = db_to_json(state)?.to_string();
+
+        // the backups are not worth failing the save
+        if let Err(e) = self.rotate_backups() {
+            println!("{e}");
+        }
+
+        write_atomically(&self.path, db_json.as_bytes())
+    }
+    fn find_peer_by_id(&self, id: Uuid) -> Result<Option<StoredPeer>, GenericError> {
+        self.find_peer::<B>(|state| state.find_peer_by_id(id))
+    }
+
+    fn find_peer_by_vpn_ip(&self, vpn_ip: IpAddr) -> Result<Option<StoredPeer>, GenericError> {
+        self.find_peer::<B>(|state| state.find_peer_by_vpn_ip(vpn_ip))
+    }
+
+    fn find_peer_by_public_key(
+        &self,
+        public_key: &str,
+    ) -> Result<Option<StoredPeer>, GenericError> {
+        self.find_peer::<B>(|state| state.find_peer_by_public_key(public_key))
+    }
+}
+
+impl JsonStorage {
+    /// Finds a peer in the DB, which is read from the file if it wasn't loaded,
+    /// e.g. by the lookups of the subcommands, see [crate::commands::Command]
+    fn find_peer<B: WireguardBackend>(
+        &self,
+        find: impl FnOnce(&DbState) -> Option<StoredPeer>,
+    ) -> Result<Option<StoredPeer>, GenericError> {
+        let mut state = self.state.lock().unwrap();
+        if state.is_none() {
+            let db = Self::read::<B>(&self.path)?;
+            *state = Some(db.as_ref().map(DbState::of).unwrap_or_default());
+        }
+
+        // we just read the DB if it was missing
+        Ok(find(state.as_ref().unwrap()))
+    }
+
+    /// Reads the DB from the file or, if it can't be read, from the newest valid backup.
+    /// The invalid DB is moved to `<path>.corrupted`, so that it doesn't replace the valid backups
+    /// (if it can't be moved, the backups are tried anyway).
+    /// Returns `None` if there's no valid DB, so that it's rebuilt from WireGuard
+    fn read_db_or_backups<B: WireguardBackend>(&self) -> Result<Option<ProxyDb<B>>, GenericError> {
+        match Self::read(&self.path) {
+            Ok(Some(db)) => return Ok(Some(db)),
+            Ok(None) => {}
+            Err(e) => {
+                let corrupted_path = format!("{}.corrupted", self.path);
+                println!("The DB is not valid, moving it to {corrupted_path}: {e}");
+                if let Err(e) = fs::rename(&self.path, &corrupted_path) {
+                    println!("Error moving the DB to {corrupted_path}: {e}");
+                }
+            }
+        }
+
+        // the DB may also be missing, e.g. if it was deleted by hand
+        for index in 1..=self.backups {
+            let backup_path = self.backup_path(index);
+            match Self::read(&backup_path) {
+                Ok(Some(db)) => {
+                    println!("Loaded the DB from backup {backup_path}");
+                    return Ok(Some(db));
+                }
+                Ok(None) => {}
+                Err(e) => println!("The DB backup is not valid: {e}"),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use crate::proxy::wireguard::memory::MemoryBackend;
+
+    use super::*;
+
+    /// A storage in a new temporary directory, which is deleted when dropped
+    fn new_storage(backups: usize) -> (TempDir, JsonStorage) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.json").to_str().unwrap().to_string();
+
+        (dir, JsonStorage::new(path, backups))
+    }
+
+    fn fixture() -> ProxyDb<MemoryBackend> {
+        db_from_json(serde_json::from_str(include_str!("fixtures/v1.json")).unwrap()).unwrap()
+    }
+
+    /// Saves the whole DB, as the persister does after a failed save
+    fn save_all(storage: &JsonStorage, db: &mut ProxyDb<MemoryBackend>) {
+        db.mark_unsaved();
+        DbStorage::<MemoryBackend>::save(storage, db.take_changes()).unwrap();
+    }
+
+    /// Saves the versions `1..=count` of the DB, each one marked with its number in the listen port of `wg0`
+    fn save_versions(storage: &JsonStorage, count: u16) {
+        let mut db = fixture();
+        save_all(storage, &mut db);
+        for version in 1..=count {
+            db.vpn_mut("wg0").unwrap().listen_port = version;
+            DbStorage::<MemoryBackend>::save(storage, db.take_changes()).unwrap();
+        }
+    }
+
+    /// The number of the version of the DB saved at the path, see [save_versions]
+    fn saved_version(path: &str) -> Option<u64> {
+        let db_json = fs::read_to_string(path).ok()?;
+        serde_json::from_str::<Value>(&db_json).unwrap()["vpns"]["wg0"]["listen_port"].as_u64()
+    }
+
+    #[test]
+    fn writes_the_db_atomically() {
+        let (dir, storage) = new_storage(0);
+
+        save_versions(&storage, 2);
+
+        assert_eq!(saved_version(&storage.path), Some(2));
+        // only the DB is left, without temporary files or backups
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn keeps_a_single_backup() {
+        let (_dir, storage) = new_storage(1);
+
+        save_versions(&storage, 2);
+
+        assert_eq!(saved_version(&storage.path), Some(2));
+        assert_eq!(saved_version(&storage.backup_path(1)), Some(1));
+        assert!(!Path::new(&storage.backup_path(2)).exists());
+    }
+
+    #[test]
+    fn rotates_the_backups() {
+        let (_dir, storage) = new_storage(3);
+
+        save_versions(&storage, 4);
+
+        assert_eq!(saved_version(&storage.path), Some(4));
+        assert_eq!(saved_version(&storage.backup_path(1)), Some(3));
+        assert_eq!(saved_version(&storage.backup_path(2)), Some(2));
+        assert_eq!(saved_version(&storage.backup_path(3)), Some(1));
+        assert!(!Path::new(&storage.backup_path(4)).exists());
+    }
+
+    #[test]
+    fn saves_the_changes_on_top_of_the_loaded_db() {
+        let (_dir, storage) = new_storage(0);
+        save_versions(&storage, 1);
+
+        let mut db: ProxyDb<MemoryBackend> = storage.load().unwrap().unwrap();
+        db.remove_vpn("wg1");
+        DbStorage::<MemoryBackend>::save(&storage, db.take_changes()).unwrap();
+
+        let saved_json = fs::read_to_string(&storage.path).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&saved_json).unwrap(),
+            db_to_json(&db).unwrap()
+        );
+        assert_eq!(saved_version(&storage.path), Some(1));
+    }
+
+    #[test]
+    fn finds_the_peers_without_loading_the_db() {
+        let (_dir, storage) = new_storage(0);
+        save_versions(&storage, 1);
+        let storage = JsonStorage::new(storage.path.clone(), 0);
+        let storage: &dyn DbStorage<MemoryBackend> = &storage;
+
+        let by_id = storage
+            .find_peer_by_id("1cede1d0-3737-4c92-adce-0669e667c659".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_id.interface_name, "wg0");
+        assert_eq!(
+            by_id.peer.public_key,
+            "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc="
+        );
+        assert!(storage
+            .find_peer_by_vpn_ip("10.13.13.3".parse().unwrap())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn recovers_a_truncated_db_from_the_newest_backup() {
+        let (_dir, storage) = new_storage(3);
+        save_versions(&storage, 1);
+        let db_json = fs::read_to_string(&storage.path).unwrap();
+        fs::write(&storage.path, &db_json[..db_json.len() / 2]).unwrap();
+
+        let db: ProxyDb<MemoryBackend> = storage.load().unwrap().unwrap();
+
+        assert_eq!(db.vpns.len(), 2);
+        // the truncated DB is kept aside
+        assert!(!Path::new(&storage.path).exists());
+        assert_eq!(
+            fs::read_to_string(format!("{}.corrupted", storage.path)).unwrap(),
+            db_json[..db_json.len() / 2]
+        );
+    }
+
+    #[test]
+    fn recovers_from_the_backups_if_the_db_cant_be_moved() {
+        let (_dir, storage) = new_storage(3);
+        save_versions(&storage, 1);
+        fs::write(&storage.path, "{").unwrap();
+        // a file can't replace a non-empty directory
+        let corrupted_path = format!("{}.corrupted", storage.path);
+        fs::create_dir_all(format!("{corrupted_path}/previous")).unwrap();
+
+        let db: Option<ProxyDb<MemoryBackend>> = storage.load().unwrap();
+
+        assert_eq!(db.unwrap().vpns.len(), 2);
+    }
+
+    #[test]
+    fn loads_nothing_without_a_valid_db() {
+        let (_dir, storage) = new_storage(3);
+        assert!(DbStorage::<MemoryBackend>::load(&storage)
+            .unwrap()
+            .is_none());
+
+        fs::write(&storage.path, "{").unwrap();
+        assert!(DbStorage::<MemoryBackend>::load(&storage)
+            .unwrap()
+            .is_none());
+    }
+}
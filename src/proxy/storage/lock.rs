@@ -0,0 +1,52 @@
+use std::fs::{File, OpenOptions, TryLockError};
+
+use crate::models::GenericError;
+
+/// An exclusive lock on the DB, held by the proxy while it runs and by the subcommands that save the DB,
+/// so that they never save it behind each other's back: the changes saved by one would be overwritten
+/// by the other, see [crate::commands::Command].
+///
+/// The lock is taken on the `<path>.lock` file next to the DB, which is left there,
+/// and it's released when dropped or when the process exits, even if it's killed
+#[derive(Debug)]
+pub struct DbLock {
+    _file: File,
+}
+
+impl DbLock {
+    /// Locks the DB at the path, failing if it's already locked, e.g. by a running proxy
+    pub fn acquire(db_path: &str) -> Result<Self, GenericError> {
+        let lock_path = format!("{db_path}.lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|e| format!("Error opening DB lock {lock_path}: {e}"))?;
+
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(format!(
+                "The DB {db_path} is locked by another process, e.g. a running proxy: stop it and try again"
+            )),
+            Err(TryLockError::Error(e)) => Err(format!("Error locking DB {db_path}: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_the_db_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db.json").to_str().unwrap().to_string();
+
+        let lock = DbLock::acquire(&db_path).unwrap();
+        assert!(DbLock::acquire(&db_path).is_err());
+
+        drop(lock);
+        assert!(DbLock::acquire(&db_path).is_ok());
+    }
+}
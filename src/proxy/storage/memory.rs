@@ -0,0 +1,135 @@
+use std::{net::IpAddr, sync::Mutex};
+
+use uuid::Uuid;
+
+use crate::{
+    models::GenericError,
+    proxy::{proxy_db::ProxyDb, wireguard::WireguardBackend},
+};
+
+use super::{
+    schema::{db_from_json, db_to_json},
+    snapshot::{DbSnapshot, DbState},
+    DbStorage, StoredPeer,
+};
+
+/// Keeps the last saved DB in memory, e.g. for tests or when the DB can be rebuilt from WireGuard.
+/// Nothing survives a restart of the proxy
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    /// The DB as last saved, `None` if it was never saved
+    saved: Mutex<Option<DbState>>,
+}
+
+impl<B: WireguardBackend> DbStorage<B> for MemoryStorage {
+    fn load(&self) -> Result<Option<ProxyDb<B>>, GenericError> {
+        match self.saved.lock().unwrap().as_ref() {
+            Some(state) => db_from_json(db_to_json(state)?).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn save(&self, snapshot: DbSnapshot) -> Result<(), GenericError> {
+        self.saved
+            .lock()
+            .unwrap()
+            .get_or_insert_with(DbState::default)
+            .apply(snapshot);
+
+        Ok(())
+    }
+    fn find_peer_by_id(&self, id: Uuid) -> Result<Option<StoredPeer>, GenericError> {
+        Ok(self
+            .saved
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|state| state.find_peer_by_id(id)))
+    }
+
+    fn find_peer_by_vpn_ip(&self, vpn_ip: IpAddr) -> Result<Option<StoredPeer>, GenericError> {
+        Ok(self
+            .saved
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|state| state.find_peer_by_vpn_ip(vpn_ip)))
+    }
+
+    fn find_peer_by_public_key(
+        &self,
+        public_key: &str,
+    ) -> Result<Option<StoredPeer>, GenericError> {
+        Ok(self
+            .saved
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|state| state.find_peer_by_public_key(public_key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::wireguard::memory::MemoryBackend;
+
+    use super::*;
+
+    fn fixture() -> ProxyDb<MemoryBackend> {
+        db_from_json(serde_json::from_str(include_str!("fixtures/v1.json")).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn loads_nothing_before_the_first_save() {
+        let storage = MemoryStorage::default();
+
+        assert!(DbStorage::<MemoryBackend>::load(&storage)
+            .unwrap()
+            .is_none());
+        assert!(
+            DbStorage::<MemoryBackend>::find_peer_by_public_key(&storage, "unknown")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn loads_the_saved_db() {
+        let mut db = fixture();
+        let storage = MemoryStorage::default();
+        let storage: &dyn DbStorage<MemoryBackend> = &storage;
+
+        db.mark_unsaved();
+        storage.save(db.take_changes()).unwrap();
+        db.remove_vpn("wg1");
+        storage.save(db.take_changes()).unwrap();
+        let loaded = storage.load().unwrap().unwrap();
+
+        assert_eq!(db_to_json(&loaded), db_to_json(&db));
+        assert!(loaded.vpn("wg1").is_none());
+    }
+
+    #[test]
+    fn finds_the_saved_peers() {
+        let mut db = fixture();
+        let storage = MemoryStorage::default();
+        db.mark_unsaved();
+        DbStorage::<MemoryBackend>::save(&storage, db.take_changes()).unwrap();
+        let storage: &dyn DbStorage<MemoryBackend> = &storage;
+
+        let by_vpn_ip = storage
+            .find_peer_by_vpn_ip("10.14.14.2".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_vpn_ip.interface_name, "wg1");
+        let by_id = storage
+            .find_peer_by_id("07ca4f59-5047-536f-a1de-e0f4aed77db8".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_id.peer.public_key, by_vpn_ip.peer.public_key);
+        assert!(storage
+            .find_peer_by_id(Uuid::from_u128(1))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,122 @@
+use std::{fmt::Debug, net::IpAddr, sync::Arc};
+
+use uuid::Uuid;
+
+use crate::{
+    env::{get_env_var_or, get_env_var_or_none},
+    models::GenericError,
+};
+
+use super::{models::RegisteredPeer, proxy_db::ProxyDb, wireguard::WireguardBackend};
+
+use self::{
+    json::{backups_from_env, JsonStorage},
+    lock::DbLock,
+    memory::MemoryStorage,
+    snapshot::DbSnapshot,
+    sqlite::SqliteStorage,
+};
+
+pub mod json;
+pub mod lock;
+pub mod memory;
+pub mod schema;
+pub mod snapshot;
+pub mod sqlite;
+pub mod tracked_map;
+
+/// A peer found in the storage, see [DbStorage::find_peer_by_id]
+#[derive(Debug, Clone)]
+pub struct StoredPeer {
+    /// The interface the peer is registered to
+    pub interface_name: String,
+    pub peer: RegisteredPeer,
+}
+
+/// Where the [ProxyDb] is persisted.
+///
+/// The backend and the configuration of the VPNs are not persisted,
+/// the loaded DB is configured by [ProxyDb::load_db].
+///
+/// The lookups read the DB as last saved (or loaded), which is behind the [ProxyDb] by the changes
+/// not flushed yet, see [super::persister]: the proxy serves the requests from the [ProxyDb],
+/// the lookups are meant for the tools that only have the storage
+pub trait DbStorage<B: WireguardBackend>: Debug + Send + Sync {
+    /// Loads the DB, `None` if it was never saved (or it can't be recovered)
+    fn load(&self) -> Result<Option<ProxyDb<B>>, GenericError>;
+
+    /// Persists the changes of the DB since the previous snapshot, see [DbSnapshot].
+    /// Called without locking the DB, see [ProxyDb::take_snapshot]
+    fn save(&self, snapshot: DbSnapshot) -> Result<(), GenericError>;
+
+    /// The peer the ID is mapped to, see [ProxyDb::external_mapping]
+    fn find_peer_by_id(&self, id: Uuid) -> Result<Option<StoredPeer>, GenericError>;
+
+    /// The peer the VPN IP is assigned to, see [crate::proxy::vpn::Vpn::assigned_ips]
+    fn find_peer_by_vpn_ip(&self, vpn_ip: IpAddr) -> Result<Option<StoredPeer>, GenericError>;
+
+    /// The peer with the public key, among the peers of all the VPNs
+    fn find_peer_by_public_key(&self, public_key: &str)
+        -> Result<Option<StoredPeer>, GenericError>;
+}
+
+/// The available [DbStorage] implementations, selected with the `DB_STORAGE` env variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbStorageKind {
+    /// The whole DB in a JSON file, see [json::JsonStorage]
+    Json,
+    /// A SQLite database, see [sqlite::SqliteStorage]
+    Sqlite,
+    /// Nothing is persisted, see [memory::MemoryStorage]
+    Memory,
+}
+
+impl DbStorageKind {
+    /// Reads the storage kind from the `DB_STORAGE` env variable, defaulting to `json`
+    pub fn from_env() -> Result<Self, GenericError> {
+        match get_env_var_or("DB_STORAGE", "json").as_str() {
+            "json" => Ok(Self::Json),
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            other => Err(format!(
+                "Unknown DB storage: {other}, expected one of json, sqlite, memory"
+            )),
+        }
+    }
+}
+
+/// The path of the DB, set in the `DB_PATH` env variable (default: `data/db.json` or `data/db.sqlite3`),
+/// `None` if the storage has no file
+fn db_path_from_env(kind: DbStorageKind) -> Option<String> {
+    let default_path = match kind {
+        DbStorageKind::Json => "data/db.json",
+        DbStorageKind::Sqlite => "data/db.sqlite3",
+        DbStorageKind::Memory => return None,
+    };
+
+    Some(get_env_var_or_none("DB_PATH").unwrap_or(default_path.to_string()))
+}
+
+/// Opens the storage selected with the `DB_STORAGE` env variable, at the path of [db_path_from_env]
+pub fn storage_from_env<B: WireguardBackend>() -> Result<Arc<dyn DbStorage<B>>, GenericError> {
+    let kind = DbStorageKind::from_env()?;
+    println!("DB storage: {:?}", kind);
+
+    let storage: Arc<dyn DbStorage<B>> = match (kind, db_path_from_env(kind)) {
+        (DbStorageKind::Json, Some(path)) => Arc::new(JsonStorage::new(path, backups_from_env()?)),
+        (DbStorageKind::Sqlite, Some(path)) => Arc::new(SqliteStorage::open(&path)?),
+        (DbStorageKind::Memory, _) | (_, None) => Arc::new(MemoryStorage::default()),
+    };
+
+    Ok(storage)
+}
+
+/// Locks the DB of the storage selected with the `DB_STORAGE` env variable, see [DbLock].
+/// `None` if the storage has no file, since nothing else can save it
+pub fn lock_db_from_env() -> Result<Option<DbLock>, GenericError> {
+    let kind = DbStorageKind::from_env()?;
+
+    db_path_from_env(kind)
+        .map(|path| DbLock::acquire(&path))
+        .transpose()
+}
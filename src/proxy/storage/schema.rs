@@ -0,0 +1,203 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    models::GenericError,
+    proxy::{proxy_db::ProxyDb, wireguard::WireguardBackend},
+};
+
+/// The version of the format of the serialized DB, stored in its `version` field.
+///
+/// The DB is the serialization of [ProxyDb], [crate::proxy::vpn::Vpn], [crate::proxy::models::PeerInfo]
+/// and [crate::proxy::models::RegisteredPeer]: bump the version and add a migration to [MIGRATIONS]
+/// whenever one of them changes, so that the DBs of the existing deployments can still be loaded
+pub const DB_VERSION: u64 = 1;
+
+/// Upgrades a serialized DB to the next version
+type Migration = fn(&mut Map<String, Value>);
+
+/// The migrations of the DB: the one at index `i` upgrades version `i` to version `i + 1`
+const MIGRATIONS: [Migration; DB_VERSION as usize] = [migrate_v0_to_v1];
+
+/// Serializes the DB in the current format, with its version.
+/// `db`: a [ProxyDb] or the [super::snapshot::DbState] of a storage
+pub fn db_to_json(db: &impl Serialize) -> Result<Value, GenericError> {
+    let mut db = serde_json::to_value(db).map_err(|e| format!("Error serializing DB: {e}"))?;
+    if let Some(db) = db.as_object_mut() {
+        db.insert("version".to_string(), Value::from(DB_VERSION));
+    }
+
+    Ok(db)
+}
+
+/// Deserializes a DB of any version, upgrading it to the current format
+pub fn db_from_json<B: WireguardBackend>(mut db: Value) -> Result<ProxyDb<B>, GenericError> {
+    let db_object = db
+        .as_object_mut()
+        .ok_or("The DB is not a JSON object".to_string())?;
+
+    let version = version_of(db_object)?;
+    if version > DB_VERSION {
+        return Err(format!(
+            "The DB has version {version}, which is newer than the version {DB_VERSION} supported by this proxy"
+        ));
+    }
+
+    for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        println!(
+            "Migrating the DB from version {from_version} to version {}",
+            from_version + 1
+        );
+        migration(db_object);
+    }
+    db_object.remove("version");
+
+    serde_json::from_value(db).map_err(|e| format!("Error parsing DB of version {version}: {e}"))
+}
+
+/// The version of the DB. The DBs saved before the version was stored have no `version` field:
+/// - version 0: a single interface, with its VPN in the `vpn` field
+/// - version 1: multiple interfaces, with their VPNs in the `vpns` field
+fn version_of(db: &Map<String, Value>) -> Result<u64, GenericError> {
+    match db.get("version") {
+        Some(version) => version
+            .as_u64()
+            .ok_or(format!("Invalid DB version {version}")),
+        None if db.contains_key("vpn") => Ok(0),
+        None => Ok(1),
+    }
+}
+
+/// Inserts the field in the object, unless it's already there
+fn insert_missing(object: &mut Map<String, Value>, field: &str, value: Value) {
+    object.entry(field).or_insert(value);
+}
+
+/// Version 0 is the format of the proxy managing a single interface, whose VPN is in the `vpn` field.
+/// Version 1 manages multiple interfaces, with the VPNs in the `vpns` field by interface name.
+///
+/// The fields added to version 0 over time are filled too:
+/// - the `listen_port` (`0`, i.e. unknown) and the `released_ips` of the VPN
+/// - the `persistent_keepalive`, the `latest_handshake` and the `registered_at` of the peers
+///   (`null`, the registration time is set to the load time by [ProxyDb::load_db])
+fn migrate_v0_to_v1(db: &mut Map<String, Value>) {
+    let mut vpns = Map::new();
+
+    if let Some(Value::Object(mut vpn)) = db.remove("vpn") {
+        insert_missing(&mut vpn, "listen_port", Value::from(0));
+        insert_missing(&mut vpn, "released_ips", Value::Object(Map::new()));
+        if let Some(Value::Object(peers)) = vpn.get_mut("peers") {
+            for peer in peers.values_mut().filter_map(Value::as_object_mut) {
+                insert_missing(peer, "persistent_keepalive", Value::Null);
+                insert_missing(peer, "latest_handshake", Value::Null);
+                insert_missing(peer, "registered_at", Value::Null);
+            }
+        }
+
+        let interface_name = vpn
+            .get("interface_name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        vpns.insert(interface_name, Value::Object(vpn));
+    }
+
+    db.insert("vpns".to_string(), Value::Object(vpns));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use uuid::Uuid;
+
+    use crate::proxy::wireguard::memory::MemoryBackend;
+
+    use super::*;
+
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+    const PEER_ID: &str = "1cede1d0-3737-4c92-adce-0669e667c659";
+
+    fn load_fixture(fixture: &str) -> ProxyDb<MemoryBackend> {
+        db_from_json(serde_json::from_str(fixture).unwrap()).unwrap()
+    }
+
+    /// Checks the peer registered in all the fixtures
+    fn assert_peer(db: &ProxyDb<MemoryBackend>) {
+        let vpn_ip: IpAddr = "10.13.13.2".parse().unwrap();
+        let peer_id = Uuid::parse_str(PEER_ID).unwrap();
+
+        let vpn = db.vpn("wg0").unwrap();
+        let peer = &vpn.peers[PEER_KEY];
+        assert_eq!(peer.allowed_ips[0], vpn_ip);
+        assert_eq!(vpn.assigned_ips[&vpn_ip], PEER_KEY);
+        assert_eq!(db.internal_mapping[&vpn_ip].id, peer_id);
+        assert_eq!(db.internal_mapping[&vpn_ip].public_ip, "203.0.113.7");
+        assert_eq!(db.external_mapping[&peer_id], vpn_ip);
+    }
+
+    #[test]
+    fn loads_v0() {
+        let db = load_fixture(include_str!("fixtures/v0.json"));
+
+        assert_peer(&db);
+        let vpn = db.vpn("wg0").unwrap();
+        assert_eq!(vpn.listen_port, 0);
+        assert!(vpn.released_ips.is_empty());
+        let peer = &vpn.peers[PEER_KEY];
+        assert_eq!(peer.persistent_keepalive, None);
+        assert_eq!(peer.latest_handshake, None);
+        assert_eq!(peer.registered_at, None);
+    }
+
+    #[test]
+    fn loads_v0_with_later_fields() {
+        let db = load_fixture(include_str!("fixtures/v0_later_fields.json"));
+
+        assert_peer(&db);
+        let vpn = db.vpn("wg0").unwrap();
+        assert_eq!(vpn.listen_port, 51820);
+        assert_eq!(vpn.released_ips.len(), 1);
+        let peer = &vpn.peers[PEER_KEY];
+        assert_eq!(peer.persistent_keepalive, Some(25));
+        assert_eq!(peer.latest_handshake, Some(1700000100));
+        assert_eq!(peer.registered_at, Some(1700000000));
+        assert_eq!(peer.ipv6(), Some("fd13:13:13::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn loads_v1_unversioned() {
+        let db = load_fixture(include_str!("fixtures/v1_unversioned.json"));
+
+        assert_peer(&db);
+        assert_eq!(db.vpns.len(), 2);
+        assert_eq!(db.vpn("wg1").unwrap().peers.len(), 1);
+    }
+
+    #[test]
+    fn loads_v1() {
+        let db = load_fixture(include_str!("fixtures/v1.json"));
+
+        assert_peer(&db);
+        assert_eq!(db.vpns.len(), 2);
+    }
+
+    #[test]
+    fn migrated_db_is_saved_with_the_current_version() {
+        let db = load_fixture(include_str!("fixtures/v0.json"));
+        let saved = db_to_json(&db).unwrap();
+
+        assert_eq!(saved["version"], DB_VERSION);
+        assert!(saved.get("vpn").is_none());
+        // loading it again doesn't migrate it
+        assert_peer(&db_from_json::<MemoryBackend>(saved).unwrap());
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut db: Value = serde_json::from_str(include_str!("fixtures/v1.json")).unwrap();
+        db["version"] = Value::from(DB_VERSION + 1);
+
+        assert!(db_from_json::<MemoryBackend>(db).is_err());
+    }
+}
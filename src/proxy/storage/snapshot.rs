@@ -0,0 +1,214 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::proxy::{
+    models::{AssignedIpsMap, PeerInfo, RegisteredPeer, RegisteredPeersMap, ReleasedIpsMap},
+    proxy_db::ProxyDb,
+};
+
+use super::StoredPeer;
+
+/// The entries of a map changed since the previous snapshot: key -> value, `None` if removed
+pub type Changes<K, V> = Vec<(K, Option<V>)>;
+
+/// The changes of the DB since the previous snapshot, see [ProxyDb::take_snapshot],
+/// so that a save costs as much as the changes, not as the whole DB
+#[derive(Debug, Default)]
+pub struct DbSnapshot {
+    /// Whether the snapshot has the whole DB, which replaces the saved one,
+    /// e.g. when the DB is rebuilt from WireGuard or a save failed
+    pub full: bool,
+    /// The interfaces whose VPN was removed, with all its peers and ips
+    pub removed_interfaces: Vec<String>,
+    /// The VPNs of the interfaces, see [VpnSnapshot]
+    pub vpns: Vec<VpnSnapshot>,
+    /// See [ProxyDb::internal_mapping]
+    pub internal_mapping: Changes<IpAddr, PeerInfo>,
+    /// See [ProxyDb::external_mapping]
+    pub external_mapping: Changes<Uuid, IpAddr>,
+}
+
+/// A VPN in a [DbSnapshot]: its interface, which is always there, and the changes of its maps
+#[derive(Debug)]
+pub struct VpnSnapshot {
+    pub interface_name: String,
+    pub interface_public_key: String,
+    pub listen_port: u16,
+    pub peers: Changes<String, RegisteredPeer>,
+    pub assigned_ips: Changes<IpAddr, String>,
+    pub released_ips: Changes<IpAddr, u64>,
+}
+
+/// The whole DB, for the storages that write it at once: the snapshots are applied to it.
+/// It's serialized in the same format of [ProxyDb]
+#[derive(Debug, Default, Serialize)]
+pub struct DbState {
+    internal_mapping: BTreeMap<IpAddr, PeerInfo>,
+    external_mapping: BTreeMap<Uuid, IpAddr>,
+    vpns: BTreeMap<String, VpnState>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct VpnState {
+    interface_name: String,
+    interface_public_key: String,
+    listen_port: u16,
+    peers: RegisteredPeersMap,
+    assigned_ips: AssignedIpsMap,
+    released_ips: ReleasedIpsMap,
+}
+
+impl DbState {
+    /// The state of a loaded DB
+    pub fn of<B>(db: &ProxyDb<B>) -> Self {
+        Self {
+            internal_mapping: (*db.internal_mapping).clone(),
+            external_mapping: (*db.external_mapping).clone(),
+            vpns: db
+                .vpns
+                .iter()
+                .map(|(interface_name, vpn)| {
+                    let vpn_state = VpnState {
+                        interface_name: vpn.interface_name.clone(),
+                        interface_public_key: vpn.interface_public_key.clone(),
+                        listen_port: vpn.listen_port,
+                        peers: (*vpn.peers).clone(),
+                        assigned_ips: (*vpn.assigned_ips).clone(),
+                        released_ips: (*vpn.released_ips).clone(),
+                    };
+                    (interface_name.clone(), vpn_state)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn apply(&mut self, snapshot: DbSnapshot) {
+        if snapshot.full {
+            *self = Self::default();
+        }
+
+        for interface_name in &snapshot.removed_interfaces {
+            self.vpns.remove(interface_name);
+        }
+        for vpn in snapshot.vpns {
+            let vpn_state = self.vpns.entry(vpn.interface_name.clone()).or_default();
+            vpn_state.interface_name = vpn.interface_name;
+            vpn_state.interface_public_key = vpn.interface_public_key;
+            vpn_state.listen_port = vpn.listen_port;
+            apply_changes(&mut vpn_state.peers, vpn.peers);
+            apply_changes(&mut vpn_state.assigned_ips, vpn.assigned_ips);
+            apply_changes(&mut vpn_state.released_ips, vpn.released_ips);
+        }
+
+        apply_changes(&mut self.internal_mapping, snapshot.internal_mapping);
+        apply_changes(&mut self.external_mapping, snapshot.external_mapping);
+    }
+
+    /// See [super::DbStorage::find_peer_by_id]
+    pub fn find_peer_by_id(&self, id: Uuid) -> Option<StoredPeer> {
+        self.external_mapping
+            .get(&id)
+            .and_then(|vpn_ip| self.find_peer_by_vpn_ip(*vpn_ip))
+    }
+
+    /// See [super::DbStorage::find_peer_by_vpn_ip]
+    pub fn find_peer_by_vpn_ip(&self, vpn_ip: IpAddr) -> Option<StoredPeer> {
+        self.vpns.values().find_map(|vpn| {
+            let public_key = vpn.assigned_ips.get(&vpn_ip)?;
+            vpn.stored_peer(public_key)
+        })
+    }
+
+    /// See [super::DbStorage::find_peer_by_public_key]
+    pub fn find_peer_by_public_key(&self, public_key: &str) -> Option<StoredPeer> {
+        self.vpns
+            .values()
+            .find_map(|vpn| vpn.stored_peer(public_key))
+    }
+}
+
+impl VpnState {
+    fn stored_peer(&self, public_key: &str) -> Option<StoredPeer> {
+        self.peers.get(public_key).map(|peer| StoredPeer {
+            interface_name: self.interface_name.clone(),
+            peer: peer.clone(),
+        })
+    }
+}
+
+fn apply_changes<K: Ord, V>(map: &mut BTreeMap<K, V>, changes: Changes<K, V>) {
+    for (key, value) in changes {
+        match value {
+            Some(value) => map.insert(key, value),
+            None => map.remove(&key),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::{
+        storage::schema::{db_from_json, db_to_json},
+        wireguard::memory::MemoryBackend,
+    };
+
+    use super::*;
+
+    fn fixture() -> ProxyDb<MemoryBackend> {
+        db_from_json(serde_json::from_str(include_str!("fixtures/v1.json")).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn serializes_the_state_as_the_db() {
+        let db = fixture();
+
+        assert_eq!(db_to_json(&DbState::of(&db)), db_to_json(&db));
+    }
+
+    #[test]
+    fn applies_the_changes_of_the_db() {
+        let mut db = fixture();
+        let mut state = DbState::of(&db);
+
+        let vpn_ip = "10.13.13.3".parse::<IpAddr>().unwrap();
+        let peer_id = Uuid::from_u128(1);
+        db.internal_mapping.insert(
+            vpn_ip,
+            PeerInfo {
+                id: peer_id,
+                public_ip: "192.0.2.1".to_string(),
+            },
+        );
+        db.external_mapping.insert(peer_id, vpn_ip);
+        db.vpn_mut("wg0").unwrap().listen_port = 51822;
+        db.remove_vpn("wg1");
+        let snapshot = db.take_changes();
+
+        assert!(!snapshot.full);
+        assert_eq!(snapshot.removed_interfaces, vec!["wg1".to_string()]);
+        // the new mappings and the ones of the peer of the removed VPN
+        assert_eq!(snapshot.internal_mapping.len(), 2);
+        assert_eq!(snapshot.external_mapping.len(), 2);
+        state.apply(snapshot);
+        assert_eq!(db_to_json(&state), db_to_json(&db));
+        assert!(db.take_changes().internal_mapping.is_empty());
+    }
+
+    #[test]
+    fn replaces_the_state_with_a_full_snapshot() {
+        let mut db = fixture();
+        let mut state = DbState::of(&db);
+
+        db.remove_vpn("wg1");
+        db.take_changes();
+        db.mark_unsaved();
+        let snapshot = db.take_changes();
+
+        assert!(snapshot.full);
+        assert!(snapshot.removed_interfaces.is_empty());
+        state.apply(snapshot);
+        assert_eq!(db_to_json(&state), db_to_json(&db));
+    }
+}
@@ -0,0 +1,712 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use rusqlite::{params_from_iter, types::Value as SqlValue, Connection, Row, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    models::GenericError,
+    proxy::{
+        models::{PeerInfo, RegisteredPeer},
+        proxy_db::ProxyDb,
+        vpn::Vpn,
+        wireguard::WireguardBackend,
+    },
+};
+
+use super::{
+    snapshot::{Changes, DbSnapshot, VpnSnapshot},
+    DbStorage, StoredPeer,
+};
+
+/// The migrations of the schema of the database: the one at index `i` upgrades version `i` to version `i + 1`.
+/// The version is stored in the `user_version` of the database.
+///
+/// Add a migration whenever the tables change, and update [Table] and the rows accordingly, see [vpn_row]
+const MIGRATIONS: [&str; 1] = [
+    // the databases created before the version was stored already have the tables of version 1
+    "
+CREATE TABLE IF NOT EXISTS vpns (
+    interface_name TEXT PRIMARY KEY,
+    interface_public_key TEXT NOT NULL,
+    listen_port INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS peers (
+    public_key TEXT PRIMARY KEY,
+    interface_name TEXT NOT NULL,
+    preshared_key TEXT,
+    remote_address TEXT,
+    allowed_ips TEXT NOT NULL,
+    persistent_keepalive INTEGER,
+    latest_handshake INTEGER,
+    registered_at INTEGER
+);
+CREATE INDEX IF NOT EXISTS peers_interface_name ON peers (interface_name);
+CREATE TABLE IF NOT EXISTS assigned_ips (
+    ip TEXT PRIMARY KEY,
+    interface_name TEXT NOT NULL,
+    public_key TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS assigned_ips_public_key ON assigned_ips (public_key);
+CREATE TABLE IF NOT EXISTS released_ips (
+    ip TEXT PRIMARY KEY,
+    interface_name TEXT NOT NULL,
+    released_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS peer_public_ips (
+    vpn_ip TEXT PRIMARY KEY,
+    id TEXT NOT NULL,
+    public_ip TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS peer_public_ips_id ON peer_public_ips (id);
+CREATE TABLE IF NOT EXISTS peer_ids (
+    id TEXT PRIMARY KEY,
+    vpn_ip TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS peer_ids_vpn_ip ON peer_ids (vpn_ip);
+",
+];
+
+/// A table of the DB, whose first column is the primary key
+struct Table {
+    name: &'static str,
+    columns: &'static [&'static str],
+}
+
+const VPNS: Table = Table {
+    name: "vpns",
+    columns: &["interface_name", "interface_public_key", "listen_port"],
+};
+const PEERS: Table = Table {
+    name: "peers",
+    columns: &[
+        "public_key",
+        "interface_name",
+        "preshared_key",
+        "remote_address",
+        "allowed_ips",
+        "persistent_keepalive",
+        "latest_handshake",
+        "registered_at",
+    ],
+};
+const ASSIGNED_IPS: Table = Table {
+    name: "assigned_ips",
+    columns: &["ip", "interface_name", "public_key"],
+};
+const RELEASED_IPS: Table = Table {
+    name: "released_ips",
+    columns: &["ip", "interface_name", "released_at"],
+};
+/// [ProxyDb::internal_mapping]
+const PEER_PUBLIC_IPS: Table = Table {
+    name: "peer_public_ips",
+    columns: &["vpn_ip", "id", "public_ip"],
+};
+/// [ProxyDb::external_mapping]
+const PEER_IDS: Table = Table {
+    name: "peer_ids",
+    columns: &["id", "vpn_ip"],
+};
+
+const TABLES: [&Table; 6] = [
+    &VPNS,
+    &PEERS,
+    &ASSIGNED_IPS,
+    &RELEASED_IPS,
+    &PEER_PUBLIC_IPS,
+    &PEER_IDS,
+];
+
+/// Stores the DB in a SQLite database, with a table for the VPNs, the peers, their IPs and their mappings,
+/// indexed by public key, VPN IP and ID, which the lookups of the peers use.
+///
+/// Every save writes only the rows changed since the previous one, see [DbSnapshot], in a single transaction
+#[derive(Debug)]
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    /// Opens the database at the path, creating it if needed, and upgrades its schema to the current version
+    pub fn open(path: &str) -> Result<Self, GenericError> {
+        let mut connection =
+            Connection::open(path).map_err(|e| format!("Error opening SQLite DB {path}: {e}"))?;
+        migrate(&mut connection).map_err(|e| format!("Error migrating SQLite DB {path}: {e}"))?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+/// Applies the migrations the database is missing, each one in its own transaction
+fn migrate(connection: &mut Connection) -> Result<(), GenericError> {
+    let version: usize = connection
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(sql_error)?;
+    if version > MIGRATIONS.len() {
+        return Err(format!(
+            "The schema has version {version}, which is newer than the version {} supported by this proxy",
+            MIGRATIONS.len()
+        ));
+    }
+
+    for (from_version, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        println!(
+            "Migrating the SQLite DB from version {from_version} to version {}",
+            from_version + 1
+        );
+
+        let transaction = connection.transaction().map_err(sql_error)?;
+        transaction.execute_batch(migration).map_err(sql_error)?;
+        // the pragma doesn't support parameters
+        transaction
+            .execute_batch(&format!("PRAGMA user_version = {}", from_version + 1))
+            .map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)?;
+    }
+
+    Ok(())
+}
+
+fn sql_error(e: rusqlite::Error) -> GenericError {
+    format!("SQLite error: {e}")
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, GenericError>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value {value} in SQLite DB: {e}"))
+}
+
+fn text(value: impl ToString) -> SqlValue {
+    SqlValue::Text(value.to_string())
+}
+
+fn optional_text(value: Option<impl ToString>) -> SqlValue {
+    value.map(text).unwrap_or(SqlValue::Null)
+}
+
+fn optional_integer(value: Option<impl Into<i64>>) -> SqlValue {
+    value
+        .map(|value| SqlValue::Integer(value.into()))
+        .unwrap_or(SqlValue::Null)
+}
+
+fn vpn_row(vpn: &VpnSnapshot) -> Vec<SqlValue> {
+    vec![
+        text(&vpn.interface_name),
+        text(&vpn.interface_public_key),
+        SqlValue::Integer(vpn.listen_port.into()),
+    ]
+}
+
+fn peer_row(interface_name: &str, peer: &RegisteredPeer) -> Vec<SqlValue> {
+    vec![
+        text(&peer.public_key),
+        text(interface_name),
+        optional_text(peer.preshared_key.as_ref()),
+        optional_text(peer.remote_address),
+        // a peer has at most 2 ips, so we don't need a table for them
+        text(serde_json::to_string(&peer.allowed_ips).unwrap()),
+        optional_integer(peer.persistent_keepalive),
+        // seconds since the UNIX epoch fit in an i64
+        optional_integer(peer.latest_handshake.map(|secs| secs as i64)),
+        optional_integer(peer.registered_at.map(|secs| secs as i64)),
+    ]
+}
+
+fn assigned_ip_row(interface_name: &str, ip: &IpAddr, public_key: &str) -> Vec<SqlValue> {
+    vec![text(ip), text(interface_name), text(public_key)]
+}
+
+fn released_ip_row(interface_name: &str, ip: &IpAddr, released_at: u64) -> Vec<SqlValue> {
+    vec![
+        text(ip),
+        text(interface_name),
+        SqlValue::Integer(released_at as i64),
+    ]
+}
+
+fn peer_public_ip_row(vpn_ip: &IpAddr, peer_info: &PeerInfo) -> Vec<SqlValue> {
+    vec![text(vpn_ip), text(peer_info.id), text(&peer_info.public_ip)]
+}
+
+fn peer_id_row(id: &Uuid, vpn_ip: &IpAddr) -> Vec<SqlValue> {
+    vec![text(id), text(vpn_ip)]
+}
+
+/// Inserts the row into the table, replacing the one with the same primary key
+fn upsert(
+    transaction: &Transaction,
+    table: &Table,
+    row: Vec<SqlValue>,
+) -> Result<(), GenericError> {
+    transaction
+        .prepare_cached(&format!(
+            "INSERT OR REPLACE INTO {} ({}) VALUES ({})",
+            table.name,
+            table.columns.join(", "),
+            vec!["?"; table.columns.len()].join(", ")
+        ))
+        .and_then(|mut statement| statement.execute(params_from_iter(row)))
+        .map_err(sql_error)?;
+
+    Ok(())
+}
+
+/// Writes the changes of a table: the changed entries are replaced, the removed ones are deleted
+fn write_changes<K: ToString, V>(
+    transaction: &Transaction,
+    table: &Table,
+    changes: &Changes<K, V>,
+    row: impl Fn(&K, &V) -> Vec<SqlValue>,
+) -> Result<(), GenericError> {
+    for (key, value) in changes {
+        match value {
+            Some(value) => upsert(transaction, table, row(key, value))?,
+            None => {
+                transaction
+                    .prepare_cached(&format!(
+                        "DELETE FROM {} WHERE {} = ?",
+                        table.name, table.columns[0]
+                    ))
+                    .and_then(|mut statement| statement.execute([key.to_string()]))
+                    .map_err(sql_error)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The columns of [PEERS] read by [stored_peer_from_row]
+const PEER_COLUMNS: &str =
+    "peers.public_key, peers.interface_name, peers.preshared_key, peers.remote_address, \
+    peers.allowed_ips, peers.persistent_keepalive, peers.latest_handshake, peers.registered_at";
+
+/// Reads the peer from a row with the [PEER_COLUMNS]
+fn stored_peer_from_row(row: &Row) -> Result<StoredPeer, GenericError> {
+    let remote_address: Option<String> = row.get(3).map_err(sql_error)?;
+    let allowed_ips: String = row.get(4).map_err(sql_error)?;
+    let latest_handshake: Option<i64> = row.get(6).map_err(sql_error)?;
+    let registered_at: Option<i64> = row.get(7).map_err(sql_error)?;
+    let peer = RegisteredPeer {
+        public_key: row.get(0).map_err(sql_error)?,
+        preshared_key: row.get(2).map_err(sql_error)?,
+        remote_address: remote_address
+            .map(|addr| parse::<SocketAddr>(&addr))
+            .transpose()?,
+        allowed_ips: serde_json::from_str(&allowed_ips)
+            .map_err(|e| format!("Invalid allowed ips {allowed_ips} in SQLite DB: {e}"))?,
+        persistent_keepalive: row.get(5).map_err(sql_error)?,
+        latest_handshake: latest_handshake.map(|secs| secs as u64),
+        registered_at: registered_at.map(|secs| secs as u64),
+    };
+
+    Ok(StoredPeer {
+        interface_name: row.get(1).map_err(sql_error)?,
+        peer,
+    })
+}
+
+/// Finds a peer using the indexes of the tables.
+/// `tables`: the tables to join to the peers, with the condition on the single parameter
+fn find_peer(
+    connection: &Connection,
+    tables: &str,
+    parameter: String,
+) -> Result<Option<StoredPeer>, GenericError> {
+    let mut statement = connection
+        .prepare_cached(&format!("SELECT {PEER_COLUMNS} FROM {tables}"))
+        .map_err(sql_error)?;
+    let mut rows = statement.query([parameter]).map_err(sql_error)?;
+
+    match rows.next().map_err(sql_error)? {
+        Some(row) => stored_peer_from_row(row).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The VPN of the interface of a row
+fn vpn_mut<B: WireguardBackend>(
+    db: &mut ProxyDb<B>,
+    interface_name: String,
+) -> Result<&mut Vpn<B>, GenericError> {
+    db.vpn_mut(&interface_name)
+        .ok_or(format!("Unknown interface {interface_name} in SQLite DB"))
+}
+
+/// Reads the DB from the tables, `None` if there's no VPN
+fn read_db<B: WireguardBackend>(
+    connection: &Connection,
+) -> Result<Option<ProxyDb<B>>, GenericError> {
+    let mut db = ProxyDb::<B>::default();
+
+    let mut statement = connection
+        .prepare("SELECT interface_name, interface_public_key, listen_port FROM vpns")
+        .map_err(sql_error)?;
+    let mut rows = statement.query([]).map_err(sql_error)?;
+    while let Some(row) = rows.next().map_err(sql_error)? {
+        let vpn = Vpn {
+            interface_name: row.get(0).map_err(sql_error)?,
+            interface_public_key: row.get(1).map_err(sql_error)?,
+            listen_port: row.get(2).map_err(sql_error)?,
+            ..Default::default()
+        };
+        db.vpns.insert(vpn.interface_name.clone(), vpn);
+    }
+    if db.vpns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut statement = connection
+        .prepare(&format!("SELECT {PEER_COLUMNS} FROM peers"))
+        .map_err(sql_error)?;
+    let mut rows = statement.query([]).map_err(sql_error)?;
+    while let Some(row) = rows.next().map_err(sql_error)? {
+        let StoredPeer {
+            interface_name,
+            peer,
+        } = stored_peer_from_row(row)?;
+        vpn_mut(&mut db, interface_name)?
+            .peers
+            .insert(peer.public_key.clone(), peer);
+    }
+
+    let mut statement = connection
+        .prepare("SELECT ip, interface_name, public_key FROM assigned_ips")
+        .map_err(sql_error)?;
+    let mut rows = statement.query([]).map_err(sql_error)?;
+    while let Some(row) = rows.next().map_err(sql_error)? {
+        let ip: String = row.get(0).map_err(sql_error)?;
+        vpn_mut(&mut db, row.get(1).map_err(sql_error)?)?
+            .assigned_ips
+            .insert(parse::<IpAddr>(&ip)?, row.get(2).map_err(sql_error)?);
+    }
+
+    let mut statement = connection
+        .prepare("SELECT ip, interface_name, released_at FROM released_ips")
+        .map_err(sql_error)?;
+    let mut rows = statement.query([]).map_err(sql_error)?;
+    while let Some(row) = rows.next().map_err(sql_error)? {
+        let ip: String = row.get(0).map_err(sql_error)?;
+        let released_at: i64 = row.get(2).map_err(sql_error)?;
+        vpn_mut(&mut db, row.get(1).map_err(sql_error)?)?
+            .released_ips
+            .insert(parse::<IpAddr>(&ip)?, released_at as u64);
+    }
+
+    let mut statement = connection
+        .prepare("SELECT vpn_ip, id, public_ip FROM peer_public_ips")
+        .map_err(sql_error)?;
+    let mut rows = statement.query([]).map_err(sql_error)?;
+    while let Some(row) = rows.next().map_err(sql_error)? {
+        let vpn_ip: String = row.get(0).map_err(sql_error)?;
+        let id: String = row.get(1).map_err(sql_error)?;
+        db.internal_mapping.insert(
+            parse::<IpAddr>(&vpn_ip)?,
+            PeerInfo {
+                id: parse::<Uuid>(&id)?,
+                public_ip: row.get(2).map_err(sql_error)?,
+            },
+        );
+    }
+
+    let mut statement = connection
+        .prepare("SELECT id, vpn_ip FROM peer_ids")
+        .map_err(sql_error)?;
+    let mut rows = statement.query([]).map_err(sql_error)?;
+    while let Some(row) = rows.next().map_err(sql_error)? {
+        let id: String = row.get(0).map_err(sql_error)?;
+        let vpn_ip: String = row.get(1).map_err(sql_error)?;
+        db.external_mapping
+            .insert(parse::<Uuid>(&id)?, parse::<IpAddr>(&vpn_ip)?);
+    }
+
+    Ok(Some(db))
+}
+
+impl<B: WireguardBackend> DbStorage<B> for SqliteStorage {
+    fn load(&self) -> Result<Option<ProxyDb<B>>, GenericError> {
+        read_db::<B>(&self.connection.lock().unwrap())
+    }
+
+    fn save(&self, snapshot: DbSnapshot) -> Result<(), GenericError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sql_error)?;
+
+        if snapshot.full {
+            for table in TABLES {
+                transaction
+                    .execute(&format!("DELETE FROM {}", table.name), [])
+                    .map_err(sql_error)?;
+            }
+        }
+        for interface_name in &snapshot.removed_interfaces {
+            for table in [&VPNS, &PEERS, &ASSIGNED_IPS, &RELEASED_IPS] {
+                transaction
+                    .execute(
+                        &format!("DELETE FROM {} WHERE interface_name = ?", table.name),
+                        [interface_name],
+                    )
+                    .map_err(sql_error)?;
+            }
+        }
+
+        for vpn in &snapshot.vpns {
+            let interface_name = vpn.interface_name.as_str();
+            // the interface of the VPN may change on every restart, and there are only a few VPNs
+            upsert(&transaction, &VPNS, vpn_row(vpn))?;
+            write_changes(&transaction, &PEERS, &vpn.peers, |_, peer| {
+                peer_row(interface_name, peer)
+            })?;
+            write_changes(
+                &transaction,
+                &ASSIGNED_IPS,
+                &vpn.assigned_ips,
+                |ip, public_key| assigned_ip_row(interface_name, ip, public_key),
+            )?;
+            write_changes(
+                &transaction,
+                &RELEASED_IPS,
+                &vpn.released_ips,
+                |ip, released_at| released_ip_row(interface_name, ip, *released_at),
+            )?;
+        }
+        write_changes(
+            &transaction,
+            &PEER_PUBLIC_IPS,
+            &snapshot.internal_mapping,
+            peer_public_ip_row,
+        )?;
+        write_changes(
+            &transaction,
+            &PEER_IDS,
+            &snapshot.external_mapping,
+            peer_id_row,
+        )?;
+
+        transaction.commit().map_err(sql_error)
+    }
+
+    fn find_peer_by_id(&self, id: Uuid) -> Result<Option<StoredPeer>, GenericError> {
+        find_peer(
+            &self.connection.lock().unwrap(),
+            "peer_ids \
+             JOIN assigned_ips ON assigned_ips.ip = peer_ids.vpn_ip \
+             JOIN peers ON peers.public_key = assigned_ips.public_key \
+             WHERE peer_ids.id = ?",
+            id.to_string(),
+        )
+    }
+
+    fn find_peer_by_vpn_ip(&self, vpn_ip: IpAddr) -> Result<Option<StoredPeer>, GenericError> {
+        find_peer(
+            &self.connection.lock().unwrap(),
+            "assigned_ips \
+             JOIN peers ON peers.public_key = assigned_ips.public_key \
+             WHERE assigned_ips.ip = ?",
+            vpn_ip.to_string(),
+        )
+    }
+
+    fn find_peer_by_public_key(
+        &self,
+        public_key: &str,
+    ) -> Result<Option<StoredPeer>, GenericError> {
+        find_peer(
+            &self.connection.lock().unwrap(),
+            "peers WHERE peers.public_key = ?",
+            public_key.to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::{
+        storage::schema::{db_from_json, db_to_json},
+        wireguard::memory::MemoryBackend,
+    };
+
+    use super::*;
+
+    fn schema_version(storage: &SqliteStorage) -> usize {
+        storage
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    fn fixture() -> ProxyDb<MemoryBackend> {
+        db_from_json(serde_json::from_str(include_str!("fixtures/v1.json")).unwrap()).unwrap()
+    }
+
+    /// Saves the whole DB, as the persister does after a failed save
+    fn save_all(storage: &SqliteStorage, db: &mut ProxyDb<MemoryBackend>) {
+        db.mark_unsaved();
+        DbStorage::<MemoryBackend>::save(storage, db.take_changes()).unwrap();
+    }
+
+    fn load(storage: &SqliteStorage) -> ProxyDb<MemoryBackend> {
+        storage.load().unwrap().unwrap()
+    }
+
+    #[test]
+    fn creates_the_current_schema() {
+        let storage = SqliteStorage::open(":memory:").unwrap();
+
+        assert_eq!(schema_version(&storage), MIGRATIONS.len());
+        assert!(DbStorage::<MemoryBackend>::load(&storage)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn saves_and_loads_the_db() {
+        let mut db = fixture();
+        let storage = SqliteStorage::open(":memory:").unwrap();
+
+        save_all(&storage, &mut db);
+
+        assert_eq!(db_to_json(&load(&storage)), db_to_json(&db));
+    }
+
+    #[test]
+    fn deletes_the_removed_rows() {
+        let mut db = fixture();
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        save_all(&storage, &mut db);
+
+        db.remove_vpn("wg1");
+        DbStorage::<MemoryBackend>::save(&storage, db.take_changes()).unwrap();
+        let loaded = load(&storage);
+
+        assert_eq!(db_to_json(&loaded), db_to_json(&db));
+        assert!(loaded.vpn("wg1").is_none());
+        assert_eq!(loaded.internal_mapping.len(), 1);
+    }
+
+    #[test]
+    fn writes_only_the_changed_rows() {
+        let mut db = fixture();
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        save_all(&storage, &mut db);
+        // a row that a save of the whole DB would overwrite
+        storage
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE peers SET latest_handshake = 1 WHERE interface_name = 'wg1'",
+                [],
+            )
+            .unwrap();
+
+        let vpn = db.vpn_mut("wg0").unwrap();
+        let public_key = vpn.peers.keys().next().unwrap().clone();
+        vpn.peers.get_mut(&public_key).unwrap().latest_handshake = Some(1700000200);
+        DbStorage::<MemoryBackend>::save(&storage, db.take_changes()).unwrap();
+        let loaded = load(&storage);
+
+        assert_eq!(
+            loaded.vpn("wg0").unwrap().peers[&public_key].latest_handshake,
+            Some(1700000200)
+        );
+        let wg1_peer = loaded.vpn("wg1").unwrap().peers.values().next().unwrap();
+        assert_eq!(wg1_peer.latest_handshake, Some(1));
+    }
+
+    #[test]
+    fn replaces_the_db_with_a_full_snapshot() {
+        let mut db = fixture();
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        save_all(&storage, &mut db);
+
+        let mut new_db = fixture();
+        new_db.remove_vpn("wg1");
+        new_db.take_changes();
+        save_all(&storage, &mut new_db);
+
+        assert_eq!(db_to_json(&load(&storage)), db_to_json(&new_db));
+    }
+
+    #[test]
+    fn finds_the_peers_with_the_indexes() {
+        let mut db = fixture();
+        let storage = SqliteStorage::open(":memory:").unwrap();
+        save_all(&storage, &mut db);
+        let storage: &dyn DbStorage<MemoryBackend> = &storage;
+
+        let by_id = storage
+            .find_peer_by_id("07ca4f59-5047-536f-a1de-e0f4aed77db8".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_id.interface_name, "wg1");
+        assert_eq!(
+            by_id.peer.public_key,
+            "CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg="
+        );
+        let by_vpn_ip = storage
+            .find_peer_by_vpn_ip("10.13.13.2".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_vpn_ip.interface_name, "wg0");
+        assert_eq!(
+            by_vpn_ip.peer.allowed_ips,
+            vec!["10.13.13.2".parse::<IpAddr>().unwrap()]
+        );
+        let by_public_key = storage
+            .find_peer_by_public_key("CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=")
+            .unwrap()
+            .unwrap();
+        assert_eq!(by_public_key.interface_name, "wg1");
+
+        assert!(storage
+            .find_peer_by_id(Uuid::from_u128(1))
+            .unwrap()
+            .is_none());
+        assert!(storage
+            .find_peer_by_vpn_ip("10.13.13.3".parse().unwrap())
+            .unwrap()
+            .is_none());
+        assert!(storage
+            .find_peer_by_public_key("unknown")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn upgrades_unversioned_schemas() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(MIGRATIONS[0]).unwrap();
+
+        migrate(&mut connection).unwrap();
+
+        let version: usize = connection
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn rejects_newer_schemas() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        connection
+            .execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1))
+            .unwrap();
+
+        assert!(migrate(&mut connection).is_err());
+    }
+}
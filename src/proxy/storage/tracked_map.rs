@@ -0,0 +1,107 @@
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    ops::Deref,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A map of the DB that records the keys changed since the previous [TrackedMap::take_changes],
+/// so that the storages save only the entries that changed, see [super::snapshot::DbSnapshot].
+///
+/// The map is read through [Deref], while all the changes go through the methods below,
+/// so that none of them can be missed. It's serialized as the inner map
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+#[serde(bound(
+    serialize = "K: Serialize, V: Serialize",
+    deserialize = "K: Ord + Deserialize<'de>, V: Deserialize<'de>"
+))]
+pub struct TrackedMap<K, V> {
+    map: BTreeMap<K, V>,
+    /// The keys inserted, changed or removed since the previous [TrackedMap::take_changes]
+    #[serde(skip)]
+    changed: BTreeSet<K>,
+}
+
+impl<K, V> Default for TrackedMap<K, V> {
+    fn default() -> Self {
+        Self {
+            map: BTreeMap::new(),
+            changed: BTreeSet::new(),
+        }
+    }
+}
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for TrackedMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.map.fmt(f)
+    }
+}
+
+impl<K, V> Deref for TrackedMap<K, V> {
+    type Target = BTreeMap<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.map
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> TrackedMap<K, V> {
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.changed.insert(key.clone());
+        self.map.insert(key, value)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let (key, value) = self.map.remove_entry(key)?;
+        self.changed.insert(key);
+
+        Some(value)
+    }
+
+    /// The value of the key to change, which is recorded as changed even if it's left as it is
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let key = self.map.get_key_value(key)?.0.clone();
+        let value = self.map.get_mut(key.borrow());
+        self.changed.insert(key);
+
+        value
+    }
+
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let changed = &mut self.changed;
+        self.map.retain(|key, value| {
+            let keep = f(key, value);
+            if !keep {
+                changed.insert(key.clone());
+            }
+            keep
+        });
+    }
+
+    /// Records all the keys as changed, e.g. to save the whole map again
+    pub fn mark_all_changed(&mut self) {
+        self.changed.extend(self.map.keys().cloned());
+    }
+
+    /// The entries changed since the previous call, with their current value (`None` if removed)
+    pub fn take_changes(&mut self) -> Vec<(K, Option<V>)> {
+        std::mem::take(&mut self.changed)
+            .into_iter()
+            .map(|key| {
+                let value = self.map.get(&key).cloned();
+                (key, value)
+            })
+            .collect()
+    }
+}
@@ -1,304 +1,969 @@
 use std::{
     collections::BTreeMap,
-    net::{Ipv4Addr, SocketAddr},
-    str::FromStr,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
 };
 
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
 use crate::models::GenericError;
 
 use super::{
-    docker::wg_docker_command,
-    ip::next_available_ipv4_address,
-    models::{AssignedIpsMap, RegisteredPeer, RegisteredPeersMap},
+    interfaces::InterfaceConfig,
+    ip::next_available_address,
+    liveness::unix_now,
+    models::{RegisteredPeer, RegisteredPeersMap},
+    network::VpnNetwork,
+    reconcile::ReconcileAction,
+    reservations::Reservations,
+    storage::{snapshot::VpnSnapshot, tracked_map::TrackedMap},
+    wireguard::{
+        dump::{WgDump, WgDumpPeer},
+        PeerConfig, WireguardBackend,
+    },
 };
 
-const WG_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
-// this is the address reserved for the wireguard interface
-const WG_FIRST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 13, 13, 1);
-
-/// Checks if Wireguard is running
-pub fn check_vpn() -> Result<String, GenericError> {
-    wg_docker_command(vec!["show"], false)
+/// Checks if Wireguard is running, returning the names of its interfaces
+pub fn check_vpn<B: WireguardBackend>(backend: &B) -> Result<Vec<String>, GenericError> {
+    backend.interface_names()
 }
 
-/// Gets the interface name of the VPN
-pub fn get_interface_name() -> Result<String, GenericError> {
-    let output = wg_docker_command(vec!["show", "interfaces"], false);
+/// Checks that the addresses of the interface match the networks of the VPN, see [VpnNetwork::check_interface_addresses]
+pub fn check_vpn_network<B: WireguardBackend>(
+    backend: &B,
+    interface_name: &str,
+    network: &VpnNetwork,
+) -> Result<(), GenericError> {
+    let interface_addresses = backend.interface_addresses(interface_name)?;
 
-    match output {
-        Ok(result) => {
-            // `result` should just contain the interface name, let's trim it to be sure
-            Ok(result.trim().to_string())
-        }
-        Err(e) => panic!("Error getting interface name: {}", e),
-    }
+    network.check_interface_addresses(&interface_addresses)
 }
 
-/// Gets the public key of the VPN
-/// This is the public key of the interface
-pub fn get_public_key(interface_name: &str) -> Result<String, GenericError> {
-    let output = wg_docker_command(vec!["show", interface_name, "public-key"], false);
+/// Converts a peer of the dump to a [RegisteredPeer].
+/// `registered_at`: when the peer was registered, the dump doesn't know it
+/// Returns `None` if the peer has no allowed ip, since we can't reach it
+fn registered_peer_from_dump(
+    peer: &WgDumpPeer,
+    registered_at: Option<u64>,
+) -> Option<RegisteredPeer> {
+    let allowed_ips = peer
+        .allowed_ips
+        .iter()
+        .map(|allowed_ip| allowed_ip.addr())
+        .collect::<Vec<IpAddr>>();
+
+    if allowed_ips.is_empty() {
+        println!("No IP found for peer {}, skipping...", peer.public_key);
+        return None;
+    }
 
-    match output {
-        Ok(result) => {
-            // `result` should just contain the public key, let's trim it to be sure
-            Ok(result.trim().to_string())
-        }
-        Err(e) => panic!("Error getting public key: {}", e),
+    Some(RegisteredPeer {
+        public_key: peer.public_key.clone(),
+        preshared_key: peer.preshared_key.clone(),
+        remote_address: peer.endpoint,
+        allowed_ips,
+        persistent_keepalive: peer.persistent_keepalive,
+        latest_handshake: peer.latest_handshake,
+        registered_at,
+    })
+}
+
+/// The configuration of a registered peer to apply to the interface.
+/// `endpoint`: the endpoint to set, if any, otherwise WireGuard keeps the one it knows
+fn peer_config(peer: &RegisteredPeer, endpoint: Option<SocketAddr>) -> PeerConfig {
+    PeerConfig {
+        public_key: peer.public_key.clone(),
+        allowed_ips: peer.allowed_ips.clone(),
+        preshared_key: peer.preshared_key.clone(),
+        persistent_keepalive: peer.persistent_keepalive,
+        endpoint,
     }
 }
 
+/// Whether the peer is configured in the interface as in the DB.
+/// The endpoint is not compared, since WireGuard updates it when the peer roams
+fn matches_dump(peer: &RegisteredPeer, dump_peer: &WgDumpPeer) -> bool {
+    let mut allowed_ips = peer.allowed_ips.clone();
+    allowed_ips.sort();
+    let mut dump_allowed_ips = dump_peer
+        .allowed_ips
+        .iter()
+        .map(|allowed_ip| allowed_ip.addr())
+        .collect::<Vec<IpAddr>>();
+    dump_allowed_ips.sort();
+
+    allowed_ips == dump_allowed_ips
+        && peer.preshared_key == dump_peer.preshared_key
+        && peer.persistent_keepalive == dump_peer.persistent_keepalive
+}
+
 /// Get the peer configuration from the VPN
-/// It executes the command `wg show wg0 dump` and extracts the peer config
-pub fn get_peer_config_by_vpn_ip(peer_vpn_ip: Ipv4Addr) -> Result<RegisteredPeer, GenericError> {
-    let output = wg_docker_command(vec!["show", "wg0", "dump"], false);
-
-    match output {
-        Ok(result) => {
-            // first line is the interface config, so we skip it
-            let lines = result.lines().skip(1);
-
-            for line in lines {
-                let mut split = line.split('\t');
-
-                let public_key = split.next().unwrap();
-                let preshared_key = split.next().unwrap();
-                let remote_address = split.next().unwrap();
-                let allowed_ips = split.next().unwrap();
-
-                let allowed_ips = allowed_ips
-                    .split(',')
-                    // TODO: handle unwrap
-                    .map(|ip| Ipv4Addr::from_str(ip.split('/').next().unwrap()).unwrap())
-                    .collect::<Vec<Ipv4Addr>>();
-
-                if allowed_ips.contains(&peer_vpn_ip) {
-                    return Ok(RegisteredPeer {
-                        public_key: public_key.to_string(),
-                        preshared_key: if preshared_key == "" || preshared_key == "(none)" {
-                            None
-                        } else {
-                            Some(preshared_key.to_string())
-                        },
-                        remote_address: if remote_address == "" || remote_address == "(none)" {
-                            None
-                        } else {
-                            Some(
-                                remote_address
-                                    .parse()
-                                    .expect("Error parsing remote ip for peer"),
-                            )
-                        },
-                        allowed_ips,
-                    });
-                }
-            }
-            Err(format!("Peer {} not found", peer_vpn_ip))
-        }
-        Err(e) => Err(format!("Error getting peer config: {}", e)),
-    }
+/// It reads the dump of the interface (`wg show <interface> dump`) and extracts the peer config,
+/// without the registration time, which only the VPN knows
+pub fn get_peer_config_by_vpn_ip<B: WireguardBackend>(
+    backend: &B,
+    interface_name: &str,
+    peer_vpn_ip: IpAddr,
+) -> Result<RegisteredPeer, GenericError> {
+    let dump = backend
+        .show_dump(interface_name)
+        .map_err(|e| format!("Error getting peer config: {}", e))?;
+
+    dump.find_peer_by_ip(peer_vpn_ip)
+        .and_then(|peer| registered_peer_from_dump(peer, None))
+        .ok_or(format!("Peer {} not found", peer_vpn_ip))
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct Vpn {
+/// The VPN of a WireGuard interface and its peers
+#[derive(Default, Serialize, Deserialize)]
+#[serde(bound = "B: WireguardBackend")]
+pub struct Vpn<B> {
+    /// The backend used to control the WireGuard interface,
+    /// shared so that it can be used without locking the DB
+    #[serde(skip)]
+    pub backend: Arc<B>,
+    /// The configuration of the interface, including the networks the ips of the peers are assigned from,
+    /// not persisted in the DB since it's read from the env variables
+    #[serde(skip)]
+    pub config: InterfaceConfig,
+    /// The static reservations of the peers, read from the env variables like the configuration
+    #[serde(skip)]
+    pub reservations: Reservations,
     pub interface_name: String,
     pub interface_public_key: String,
+    /// The UDP port the interface listens on, `0` if unknown
+    pub listen_port: u16,
     /// The peers of the VPN: peer public key -> peer
-    pub peers: RegisteredPeersMap,
+    pub peers: TrackedMap<String, RegisteredPeer>,
     /// The assigned ips of the peers: ip -> peer public key
-    pub assigned_ips: AssignedIpsMap,
+    pub assigned_ips: TrackedMap<IpAddr, String>,
+    /// The ips of the removed peers that are in quarantine, see [VpnNetwork]
+    pub released_ips: TrackedMap<IpAddr, u64>,
 }
 
-impl Vpn {
-    pub fn new() -> Result<Self, GenericError> {
-        match get_interface_name() {
-            Ok(interface_name) => match get_public_key(interface_name.as_str()) {
-                Ok(public_key) => {
-                    let mut vpn = Self {
-                        interface_name,
-                        interface_public_key: public_key,
-                        peers: BTreeMap::new(),
-                        assigned_ips: BTreeMap::new(),
-                    };
-
-                    match vpn.get_registered_peers() {
-                        Ok(peers) => {
-                            vpn.peers = peers;
-                            Ok(vpn)
-                        }
-                        Err(e) => Err(e),
-                    }
-                }
-                Err(e) => Err(format!("Error getting public key: {}", e)),
-            },
-            Err(e) => Err(format!("Error creating VPN: {}", e)),
+/// Leaves out the backend, which may hold the private keys of the interfaces,
+/// while the secrets of the configuration and of the peers are redacted by their own impls
+impl<B> fmt::Debug for Vpn<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Vpn")
+            .field("config", &self.config)
+            .field("reservations", &self.reservations)
+            .field("interface_name", &self.interface_name)
+            .field("interface_public_key", &self.interface_public_key)
+            .field("listen_port", &self.listen_port)
+            .field("peers", &self.peers)
+            .field("assigned_ips", &self.assigned_ips)
+            .field("released_ips", &self.released_ips)
+            .finish()
+    }
+}
+
+impl<B: WireguardBackend> Vpn<B> {
+    pub fn new(
+        backend: Arc<B>,
+        config: InterfaceConfig,
+        reservations: Reservations,
+    ) -> Result<Self, GenericError> {
+        let interface_info = backend
+            .interface_info(&config.name)
+            .map_err(|e| format!("Error creating VPN: {}", e))?;
+
+        let mut vpn = Self {
+            backend,
+            config,
+            reservations,
+            interface_name: interface_info.name,
+            interface_public_key: interface_info.public_key,
+            listen_port: interface_info.listen_port,
+            peers: TrackedMap::default(),
+            assigned_ips: TrackedMap::default(),
+            released_ips: TrackedMap::default(),
+        };
+
+        for (public_key, peer) in vpn.get_registered_peers()? {
+            vpn.peers.insert(public_key, peer);
+        }
+
+        Ok(vpn)
+    }
+
+    /// Reads the public key and the listen port of the interface again,
+    /// since they may have changed while the proxy was not running
+    pub fn refresh_interface_info(&mut self) -> Result<(), GenericError> {
+        let interface_info = self
+            .backend
+            .interface_info(&self.interface_name)
+            .map_err(|e| format!("Error refreshing {}: {e}", self.interface_name))?;
+
+        self.interface_public_key = interface_info.public_key;
+        self.listen_port = interface_info.listen_port;
+
+        Ok(())
+    }
+
+    /// The interface of the VPN and the changes of its peers and ips since the previous call, see [DbSnapshot]
+    pub fn take_changes(&mut self) -> VpnSnapshot {
+        VpnSnapshot {
+            interface_name: self.interface_name.clone(),
+            interface_public_key: self.interface_public_key.clone(),
+            listen_port: self.listen_port,
+            peers: self.peers.take_changes(),
+            assigned_ips: self.assigned_ips.take_changes(),
+            released_ips: self.released_ips.take_changes(),
         }
     }
 
+    /// Records all the peers and ips as changed, so that they are all in the next snapshot
+    pub fn mark_all_changed(&mut self) {
+        self.peers.mark_all_changed();
+        self.assigned_ips.mark_all_changed();
+        self.released_ips.mark_all_changed();
+    }
+
     /// Gets the registered peers of the VPN
-    /// and saves them to the `peers` field
+    /// and saves their ips to the `assigned_ips` field
     pub fn get_registered_peers(&mut self) -> Result<RegisteredPeersMap, GenericError> {
-        let output = wg_docker_command(vec!["show", self.interface_name.as_str(), "dump"], false);
-
-        match output {
-            Ok(result) => {
-                let mut peers: RegisteredPeersMap = BTreeMap::new();
-
-                // first line is the interface config, so we skip it
-                result.lines().skip(1).for_each(|peer| {
-                    let mut split = peer.split('\t');
-
-                    let public_key = split.next().unwrap();
-                    let preshared_key = split.next().unwrap();
-                    let remote_address = split.next().unwrap();
-                    let allowed_ips = split.next().unwrap();
-
-                    let allowed_ips = allowed_ips.split(',').fold(
-                        Vec::new(),
-                        |mut allowed_ips: Vec<Ipv4Addr>, ip| {
-                            if ip == "" || ip == "(none)" {
-                                println!("No ip found for peer {public_key}, skipping...");
-                            } else {
-                                allowed_ips.push(
-                                    // `ip` should be in the format of `ip/mask`, so we need to remove the mask
-                                    Ipv4Addr::from_str(ip.split('/').next().unwrap())
-                                        .expect(format!("Error parsing ip {ip}").as_str()),
-                                )
-                            }
+        let dump = self
+            .backend
+            .show_dump(self.interface_name.as_str())
+            .map_err(|e| format!("Error getting registered peers: {}", e))?;
+
+        let mut peers: RegisteredPeersMap = BTreeMap::new();
+
+        // the peers found on the interface are considered registered now
+        let now = unix_now();
+        for peer in dump
+            .peers
+            .iter()
+            .filter_map(|peer| registered_peer_from_dump(peer, Some(now)))
+        {
+            for ip in &peer.allowed_ips {
+                self.assigned_ips.insert(*ip, peer.public_key.clone());
+            }
+            peers.insert(peer.public_key.clone(), peer);
+        }
 
-                            allowed_ips
-                        },
-                    );
+        Ok(peers)
+    }
 
-                    if allowed_ips.len() == 0 {
-                        println!("No ip found for peer {public_key}, skipping...");
-                    } else {
-                        peers.insert(
-                            public_key.to_string(),
-                            RegisteredPeer {
-                                public_key: public_key.to_string(),
-                                preshared_key: if preshared_key == "" || preshared_key == "(none)" {
-                                    None
-                                } else {
-                                    Some(preshared_key.to_string())
-                                },
-                                remote_address: if remote_address == ""
-                                    || remote_address == "(none)"
-                                {
-                                    None
-                                } else {
-                                    Some(
-                                        remote_address
-                                            .parse()
-                                            .expect("Error parsing remote ip for peer"),
-                                    )
-                                },
-                                allowed_ips: allowed_ips.clone(),
-                            },
-                        );
+    /// Updates the remote address and the latest handshake of the known peers
+    /// with the ones read from the VPN
+    pub fn apply_dump(&mut self, dump: &WgDump) {
+        for dump_peer in &dump.peers {
+            let changed = self.peers.get(&dump_peer.public_key).is_some_and(|peer| {
+                peer.remote_address != dump_peer.endpoint
+                    || peer.latest_handshake != dump_peer.latest_handshake
+            });
+            // the peers are recorded as changed only if they are, so that the others aren't saved again
+            if !changed {
+                continue;
+            }
+            if let Some(peer) = self.peers.get_mut(&dump_peer.public_key) {
+                peer.remote_address = dump_peer.endpoint;
+                peer.latest_handshake = dump_peer.latest_handshake;
+            }
+        }
+    }
 
+    /// Compares the peers of the VPN with the dump of the interface and brings them back in sync:
+    /// - the peers missing from the interface are added to it again, the ones configured differently are updated
+    /// - the peers of the interface unknown to the VPN are adopted, unless they have no IP,
+    ///   an IP outside the networks of the VPN or an IP assigned to another peer: those are orphans
+    /// - the assigned ips that don't belong to their peer are orphan records
+    ///
+    /// `remove_orphans`: whether the orphans are removed or only reported.
+    /// Returns the actions taken
+    pub fn reconcile(&mut self, dump: &WgDump, remove_orphans: bool) -> Vec<ReconcileAction> {
+        let mut actions = Vec::new();
+        let mut config_changed = false;
+
+        for peer in self.peers.values() {
+            let (peer_config, action) = match dump.find_peer(&peer.public_key) {
+                // the peer has an endpoint only once it has connected, see [Vpn::rotate_peer_key]
+                None => (
+                    peer_config(peer, peer.latest_handshake.and(peer.remote_address)),
+                    ReconcileAction::Reapplied {
+                        public_key: peer.public_key.clone(),
+                    },
+                ),
+                Some(dump_peer) if !matches_dump(peer, dump_peer) => (
+                    peer_config(peer, None),
+                    ReconcileAction::Updated {
+                        public_key: peer.public_key.clone(),
+                    },
+                ),
+                Some(_) => continue,
+            };
+
+            match self.backend.set_peer(&self.interface_name, &peer_config) {
+                Ok(_) => {
+                    config_changed = true;
+                    actions.push(action);
+                }
+                Err(e) => actions.push(ReconcileAction::Failed {
+                    public_key: Some(peer.public_key.clone()),
+                    error: format!("Error applying peer: {e}"),
+                }),
+            }
+        }
+
+        for dump_peer in &dump.peers {
+            if self.peers.contains_key(&dump_peer.public_key) {
+                continue;
+            }
+
+            let peer = registered_peer_from_dump(dump_peer, Some(unix_now()));
+            let orphan_reason = match &peer {
+                None => Some("it has no IP".to_string()),
+                Some(peer) => peer.allowed_ips.iter().find_map(|ip| {
+                    if !self.config.network.contains(*ip) {
+                        Some(format!("IP {ip} is not in the VPN networks"))
+                    } else {
                         self.assigned_ips
-                            .insert(allowed_ips[0].clone(), public_key.to_string());
+                            .get(ip)
+                            .map(|public_key| format!("IP {ip} is assigned to {public_key}"))
                     }
+                }),
+            };
+
+            match (peer, orphan_reason) {
+                (Some(peer), None) => {
+                    self.assign_ips(&peer);
+                    actions.push(ReconcileAction::Adopted {
+                        public_key: peer.public_key.clone(),
+                        allowed_ips: peer.allowed_ips.clone(),
+                    });
+                    self.peers.insert(peer.public_key.clone(), peer);
+                }
+                (_, reason) => {
+                    let reason = reason.unwrap_or_default();
+                    let removed = remove_orphans
+                        && match self
+                            .backend
+                            .remove_peer(&self.interface_name, &dump_peer.public_key)
+                        {
+                            Ok(_) => {
+                                config_changed = true;
+                                true
+                            }
+                            Err(e) => {
+                                actions.push(ReconcileAction::Failed {
+                                    public_key: Some(dump_peer.public_key.clone()),
+                                    error: format!("Error removing orphan peer: {e}"),
+                                });
+                                false
+                            }
+                        };
+                    actions.push(ReconcileAction::OrphanPeer {
+                        public_key: dump_peer.public_key.clone(),
+                        reason,
+                        removed,
+                    });
+                }
+            }
+        }
+
+        let orphan_ips = self
+            .assigned_ips
+            .iter()
+            .filter(|(ip, public_key)| {
+                !self
+                    .peers
+                    .get(*public_key)
+                    .is_some_and(|peer| peer.allowed_ips.contains(ip))
+            })
+            .map(|(ip, public_key)| (*ip, public_key.clone()))
+            .collect::<Vec<(IpAddr, String)>>();
+        for (ip, public_key) in orphan_ips {
+            if remove_orphans {
+                self.release_ips(&[ip]);
+            }
+            actions.push(ReconcileAction::OrphanRecord {
+                record: format!("IP {ip} assigned to {public_key}"),
+                removed: remove_orphans,
+            });
+        }
+
+        // the peers are already live, so we don't fail if the config can't be persisted
+        if config_changed {
+            if let Err(e) = self.backend.save_config(&self.interface_name) {
+                actions.push(ReconcileAction::Failed {
+                    public_key: None,
+                    error: format!("Error saving {} config: {e}", self.interface_name),
                 });
+            }
+        }
+
+        self.apply_dump(dump);
 
-                Ok(peers)
+        actions
+    }
+
+    /// Gets the known peer with the given internal vpn ip, without reading the VPN
+    pub fn get_peer(&self, peer_vpn_ip: IpAddr) -> Option<&RegisteredPeer> {
+        self.assigned_ips
+            .get(&peer_vpn_ip)
+            .and_then(|public_key| self.peers.get(public_key))
+    }
+
+    /// Removes a peer from the VPN and releases its ips
+    /// `public_key`: the public key of the peer to remove
+    /// returns the removed peer
+    pub fn remove_peer(&mut self, public_key: &str) -> Result<RegisteredPeer, GenericError> {
+        if !self.peers.contains_key(public_key) {
+            return Err(format!("Peer with public key {public_key} not found"));
+        }
+
+        self.backend
+            .remove_peer(self.interface_name.as_str(), public_key)
+            .map_err(|e| format!("Error removing peer with public key {public_key}: {e}"))?;
+
+        // the peer is already gone, so we don't fail if the config can't be persisted
+        if let Err(e) = self.backend.save_config(self.interface_name.as_str()) {
+            println!(
+                "Error saving {} config after removing peer {public_key}: {e}",
+                self.interface_name
+            );
+        }
+
+        // we checked that the peer exists above
+        let peer = self.peers.remove(public_key).unwrap();
+        self.assigned_ips
+            .retain(|_, assigned_public_key| assigned_public_key != public_key);
+        self.release_ips(&peer.allowed_ips);
+
+        Ok(peer)
+    }
+
+    /// Replaces the public key of a registered peer, keeping its VPN IPs
+    /// `old_public_key`: the current public key of the peer
+    /// `new_public_key`: the public key that replaces it
+    /// `preshared_key`: the preshared key to use with the new public key
+    pub fn rotate_peer_key(
+        &mut self,
+        old_public_key: &str,
+        new_public_key: String,
+        preshared_key: Option<String>,
+    ) -> Result<RegisteredPeer, GenericError> {
+        let old_peer = match self.peers.get(old_public_key) {
+            Some(peer) => peer,
+            None => return Err(format!("Peer with public key {old_public_key} not found")),
+        };
+        if self.peers.contains_key(&new_public_key) {
+            return Err(format!(
+                "Peer with public key {new_public_key} is already registered"
+            ));
+        }
+
+        let peer = RegisteredPeer {
+            public_key: new_public_key,
+            preshared_key,
+            // no handshake has been made with the new key yet,
+            // and the new key is registered now
+            latest_handshake: None,
+            registered_at: Some(unix_now()),
+            ..old_peer.clone()
+        };
+
+        // the remote address is the WireGuard endpoint only once the peer has connected,
+        // before it's the address the peer registered from.
+        // We keep it, so that the interface can reach the peer as soon as it switches to the new key
+        let endpoint = old_peer.latest_handshake.and(old_peer.remote_address);
+
+        self.backend
+            .replace_peer(
+                self.interface_name.as_str(),
+                old_public_key,
+                &peer_config(&peer, endpoint),
+            )
+            .map_err(|e| {
+                format!(
+                    "Error replacing public key {old_public_key} with {}: {e}",
+                    peer.public_key
+                )
+            })?;
+
+        // the new key is already live, so we don't fail if the config can't be persisted
+        if let Err(e) = self.backend.save_config(self.interface_name.as_str()) {
+            println!(
+                "Error saving {} config after replacing public key {old_public_key}: {e}",
+                self.interface_name
+            );
+        }
+
+        self.peers.remove(old_public_key);
+        for ip in &peer.allowed_ips {
+            self.assigned_ips.insert(*ip, peer.public_key.clone());
+        }
+        self.peers.insert(peer.public_key.clone(), peer.clone());
+
+        Ok(peer)
+    }
+
+    /// The next address of the network that can be assigned to a peer, see [next_available_address]
+    fn next_address(
+        &mut self,
+        network: IpNet,
+        interface_addr: IpAddr,
+    ) -> Result<IpAddr, GenericError> {
+        // the released ips whose quarantine is over can be assigned again
+        let quarantine_start =
+            unix_now().saturating_sub(self.config.network.ip_quarantine.as_secs());
+        self.released_ips
+            .retain(|_, released_at| *released_at > quarantine_start);
+
+        next_available_address(
+            network,
+            interface_addr,
+            &self.assigned_ips,
+            &self.released_ips,
+            self.reservations.reserved_ips(),
+        )
+    }
+
+    /// The IPv4 address to assign to a peer
+    fn next_ipv4_address(&mut self) -> Result<IpAddr, GenericError> {
+        let network = self.config.network;
+
+        self.next_address(
+            IpNet::V4(network.ipv4),
+            IpAddr::V4(network.ipv4_interface_address()),
+        )
+    }
+
+    /// The IPv6 address to assign to a peer, `None` if the VPN is IPv4 only
+    fn next_ipv6_address(&mut self) -> Result<Option<IpAddr>, GenericError> {
+        let network = self.config.network;
+
+        match (network.ipv6, network.ipv6_interface_address()) {
+            (Some(network), Some(interface_addr)) => self
+                .next_address(IpNet::V6(network), IpAddr::V6(interface_addr))
+                .map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// The VPN IPs to assign to a peer: its reserved ips, if any, replace the ones of the same family,
+    /// and an ip is assigned for each family of the VPN the peer has no ip of, so that:
+    /// - new peers get an IPv4 address and, if the VPN is dual-stack, an IPv6 address
+    /// - peers registered before IPv6 was enabled get their IPv6 address when they register again
+    fn peer_ips(&mut self, public_key: &str) -> Result<Vec<IpAddr>, GenericError> {
+        let mut ips = self
+            .peers
+            .get(public_key)
+            .map(|peer| peer.allowed_ips.clone())
+            .unwrap_or_default();
+
+        if let Some(reservation) = self.reservations.get(public_key) {
+            for reserved_ip in reservation.ips() {
+                ips.retain(|ip| ip.is_ipv4() != reserved_ip.is_ipv4());
+                ips.push(reserved_ip);
             }
-            Err(e) => panic!("Error getting registered peers: {}", e),
         }
+
+        if !ips.iter().any(IpAddr::is_ipv4) {
+            ips.push(self.next_ipv4_address()?);
+        }
+        if !ips.iter().any(IpAddr::is_ipv6) {
+            ips.extend(self.next_ipv6_address()?);
+        }
+        // the IPv4 address is the first one, like in the peers we assign
+        ips.sort_by_key(IpAddr::is_ipv6);
+
+        Ok(ips)
+    }
+
+    /// The reserved ips of the peer with the given public key that are assigned to other peers,
+    /// e.g. because they registered before the reservation was added
+    pub fn reservation_conflicts(&self, public_key: &str) -> Vec<String> {
+        self.reservations
+            .get(public_key)
+            .map(|reservation| reservation.ips())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|ip| match self.assigned_ips.get(&ip) {
+                Some(assigned_public_key) if assigned_public_key != public_key => Some(format!(
+                    "IP {ip} reserved for {public_key} is assigned to {assigned_public_key}"
+                )),
+                _ => None,
+            })
+            .collect()
     }
 
-    /// Adds a peer to the VPN. If the peer already exists, updates its the remote address.
-    /// If the peer is new, it automatically assigns the next available vpn ip to the peer
+    /// Maps the ips of the peer to its public key
+    fn assign_ips(&mut self, peer: &RegisteredPeer) {
+        for ip in &peer.allowed_ips {
+            self.assigned_ips.insert(*ip, peer.public_key.clone());
+            self.released_ips.remove(ip);
+        }
+    }
+
+    /// Releases the ips, putting them in quarantine if enabled
+    fn release_ips(&mut self, ips: &[IpAddr]) {
+        let now = unix_now();
+        for ip in ips {
+            self.assigned_ips.remove(ip);
+            if !self.config.network.ip_quarantine.is_zero() {
+                self.released_ips.insert(*ip, now);
+            }
+        }
+    }
+
+    /// Adds a peer to the VPN. If the peer already exists, updates its remote address, preshared key and keepalive.
+    /// The VPN ips of the peer are chosen by [Vpn::peer_ips], honouring its reservation
     /// `public_key`: the public key of the peer to add to the vpn
     /// `preshared_key`: the preshared key of the peer to add to the vpn
+    /// `persistent_keepalive`: the seconds between the keepalive packets sent to the peer, if any
     pub fn add_or_update_peer(
         &mut self,
         public_key: String,
         preshared_key: Option<String>,
+        persistent_keepalive: Option<u16>,
         remote_address: Option<SocketAddr>,
     ) -> Result<RegisteredPeer, GenericError> {
-        // if peer already exists, update its remote address and return it
+        let conflicts = self.reservation_conflicts(&public_key);
+        if !conflicts.is_empty() {
+            return Err(conflicts.join(", "));
+        }
+
+        let allowed_ips = self
+            .peer_ips(&public_key)
+            .map_err(|e| format!("Error assigning VPN IPs to peer {public_key}: {e}"))?;
+
+        // if peer already exists, update it and return it
 
-        match self.peers.get_mut(&public_key) {
+        match self.peers.get(&public_key).cloned() {
             Some(peer) => {
-                peer.remote_address = remote_address;
-                peer.preshared_key = preshared_key;
+                let updated_peer = RegisteredPeer {
+                    remote_address,
+                    preshared_key,
+                    persistent_keepalive,
+                    allowed_ips,
+                    ..peer.clone()
+                };
+
+                if peer.preshared_key != updated_peer.preshared_key
+                    || peer.persistent_keepalive != updated_peer.persistent_keepalive
+                    || peer.allowed_ips != updated_peer.allowed_ips
+                {
+                    self.backend
+                        .set_peer(
+                            self.interface_name.as_str(),
+                            &peer_config(&updated_peer, None),
+                        )
+                        .map_err(|e| format!("Error updating peer {public_key}: {e}"))?;
+
+                    if let Err(e) = self.backend.save_config(self.interface_name.as_str()) {
+                        println!(
+                            "Error saving {} config after updating peer {public_key}: {e}",
+                            self.interface_name
+                        );
+                    }
+                }
 
-                Ok(peer.clone())
+                // the ips replaced by the reserved ones are free now
+                let replaced_ips = peer
+                    .allowed_ips
+                    .iter()
+                    .filter(|ip| !updated_peer.allowed_ips.contains(ip))
+                    .copied()
+                    .collect::<Vec<IpAddr>>();
+                self.release_ips(&replaced_ips);
+                self.assign_ips(&updated_peer);
+                self.peers.insert(public_key, updated_peer.clone());
+
+                Ok(updated_peer)
             }
             None => {
                 // otherwise, add the peer to the vpn
-                let ip_addr =
-                    next_available_ipv4_address(&self.assigned_ips, WG_NETMASK, WG_FIRST_ADDR);
-
-                match ip_addr {
-                    Some(ip_addr) => {
-                        match wg_docker_command(
-                            vec![
-                                "set",
-                                self.interface_name.as_str(),
-                                "peer",
-                                public_key.as_str(),
-                                "allowed-ips",
-                                ip_addr.to_string().as_str(),
-                            ],
-                            false,
-                        ) {
-                            Ok(_) => {
-                                // we need to restart the interface to apply the changes
-                                wg_docker_command(vec!["down", self.interface_name.as_str()], true)
-                                    .expect("Error restarting interface");
-                                wg_docker_command(vec!["up", self.interface_name.as_str()], true)
-                                    .expect("Error restarting interface");
-
-                                let peer = RegisteredPeer {
-                                    public_key,
-                                    preshared_key,
-                                    remote_address,
-                                    allowed_ips: vec![ip_addr],
-                                };
-
-                                self.peers.insert(peer.public_key.clone(), peer.clone());
-                                self.assigned_ips.insert(ip_addr, peer.public_key.clone());
-
-                                Ok(peer)
-                            }
-                            Err(e) => Err(format!(
-                                "Error adding peer with public key {public_key}: {e}"
-                            )
-                            .to_string()),
+                let peer = RegisteredPeer {
+                    public_key,
+                    preshared_key,
+                    remote_address,
+                    allowed_ips,
+                    persistent_keepalive,
+                    latest_handshake: None,
+                    registered_at: Some(unix_now()),
+                };
+
+                match self
+                    .backend
+                    .set_peer(self.interface_name.as_str(), &peer_config(&peer, None))
+                {
+                    Ok(_) => {
+                        // `wg set` applies the peer live, without disrupting the other peers,
+                        // we just need to persist the configuration for the next restart.
+                        // The peer is already up, so we don't fail if it can't be persisted
+                        if let Err(e) = self.backend.save_config(self.interface_name.as_str()) {
+                            println!(
+                                "Error saving {} config after adding peer {}: {e}",
+                                self.interface_name, peer.public_key
+                            );
                         }
+
+                        self.peers.insert(peer.public_key.clone(), peer.clone());
+                        self.assign_ips(&peer);
+
+                        Ok(peer)
                     }
-                    None => Err(format!(
-                        "Error adding peer with public key {public_key}: No available ip address"
-                    )
-                    .to_string()),
+                    Err(e) => Err(format!(
+                        "Error adding peer with public key {}: {e}",
+                        peer.public_key
+                    )),
                 }
             }
         }
     }
 
-    /// searches for the peer with the given internal vpn ip
-    /// and updates the internal list of peers
+    /// Adds a peer exported by another proxy with its VPN ips and keys unchanged, see [super::registry],
+    /// replacing the peer with the same public key, if any.
+    /// The configuration of the interface is saved by the caller, once all the peers are imported
+    pub fn import_peer(&mut self, peer: RegisteredPeer) -> Result<(), GenericError> {
+        self.backend
+            .set_peer(self.interface_name.as_str(), &peer_config(&peer, None))
+            .map_err(|e| format!("Error importing peer {}: {e}", peer.public_key))?;
+
+        if let Some(old_peer) = self.peers.get(&peer.public_key) {
+            let replaced_ips = old_peer
+                .allowed_ips
+                .iter()
+                .filter(|ip| !peer.allowed_ips.contains(ip))
+                .copied()
+                .collect::<Vec<IpAddr>>();
+            self.release_ips(&replaced_ips);
+        }
+        self.assign_ips(&peer);
+        self.peers.insert(peer.public_key.clone(), peer);
+
+        Ok(())
+    }
+
+    /// searches for the peer with the given internal vpn ip in the VPN
+    /// and updates the internal list of peers with its remote address and latest handshake
     /// `ip`: the internal vpn ip of the peer to search for
     /// returns the peer with the given internal vpn ip
-    /// TODO: extremely inefficient, improve this
+    /// This reads the whole VPN state, prefer [Vpn::get_peer] for known peers
     pub fn refresh_and_get_peer(
         &mut self,
-        peer_vpn_ip: Ipv4Addr,
+        peer_vpn_ip: IpAddr,
     ) -> Result<RegisteredPeer, GenericError> {
         // we update the internal list of peers
         // and then we search for the peer with the given internal vpn ip
-        match get_peer_config_by_vpn_ip(peer_vpn_ip) {
-            Ok(peer_config) => self.add_or_update_peer(
-                peer_config.public_key,
-                peer_config.preshared_key,
-                peer_config.remote_address,
-            ),
-            Err(e) => Err(format!("Error getting peer config: {}", e)),
+        let peer_config =
+            get_peer_config_by_vpn_ip(self.backend.as_ref(), &self.interface_name, peer_vpn_ip)
+                .map_err(|e| format!("Error getting peer config: {}", e))?;
+
+        match self.peers.get_mut(&peer_config.public_key) {
+            Some(peer) => {
+                peer.remote_address = peer_config.remote_address;
+                peer.preshared_key = peer_config.preshared_key;
+                peer.persistent_keepalive = peer_config.persistent_keepalive;
+                peer.latest_handshake = peer_config.latest_handshake;
+
+                Ok(peer.clone())
+            }
+            None => {
+                // the peer is already configured in the VPN, so we just keep track of it
+                let peer_config = RegisteredPeer {
+                    registered_at: Some(unix_now()),
+                    ..peer_config
+                };
+                for ip in &peer_config.allowed_ips {
+                    self.assigned_ips
+                        .insert(*ip, peer_config.public_key.clone());
+                }
+                self.peers
+                    .insert(peer_config.public_key.clone(), peer_config.clone());
+
+                Ok(peer_config)
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::proxy::{reservations::Reservation, wireguard::memory::MemoryBackend};
+
+    use super::*;
+
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+    const NEW_PEER_KEY: &str = "CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=";
+
+    /// The VPN of `wg0` on a new memory backend, in the default network
+    fn new_vpn() -> Vpn<MemoryBackend> {
+        let config = InterfaceConfig {
+            name: "wg0".to_string(),
+            ..Default::default()
+        };
+
+        Vpn::new(
+            Arc::new(MemoryBackend::new(vec!["wg0".to_string()])),
+            config,
+            Reservations::default(),
+        )
+        .unwrap()
+    }
+
+    /// A VPN with a peer registered long ago
+    fn vpn_with_old_peer() -> Vpn<MemoryBackend> {
+        let mut vpn = new_vpn();
+        vpn.add_or_update_peer(PEER_KEY.to_string(), None, None, None)
+            .unwrap();
+        vpn.peers.get_mut(PEER_KEY).unwrap().registered_at = Some(1);
+
+        vpn
+    }
+
+    /// A VPN with the IP `ip` reserved to the peer with the public key `public_key`
+    fn reserve(vpn: &mut Vpn<MemoryBackend>, public_key: &str, ip: &str) {
+        let reservation = Reservation {
+            public_key: public_key.to_string(),
+            ip: Some(ip.parse().unwrap()),
+            ipv6: None,
+            id: None,
+            interface: None,
+        };
+        vpn.reservations = Reservations::new(vec![reservation], &vpn.config.network).unwrap();
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn assigns_the_reserved_ips() {
+        let mut vpn = new_vpn();
+        reserve(&mut vpn, PEER_KEY, "10.13.13.2");
+
+        let other_peer = vpn
+            .add_or_update_peer(NEW_PEER_KEY.to_string(), None, None, None)
+            .unwrap();
+        let peer = vpn
+            .add_or_update_peer(PEER_KEY.to_string(), None, None, None)
+            .unwrap();
+
+        // the reserved IP is skipped by the other peers
+        assert_eq!(other_peer.allowed_ips, vec![ip("10.13.13.3")]);
+        assert_eq!(peer.allowed_ips, vec![ip("10.13.13.2")]);
+        assert_eq!(vpn.assigned_ips[&ip("10.13.13.2")], PEER_KEY);
+    }
+
+    #[test]
+    fn reserved_ips_replace_the_assigned_ones() {
+        let mut vpn = new_vpn();
+        vpn.add_or_update_peer(PEER_KEY.to_string(), None, None, None)
+            .unwrap();
+        reserve(&mut vpn, PEER_KEY, "10.13.13.10");
+
+        let peer = vpn
+            .add_or_update_peer(PEER_KEY.to_string(), None, None, None)
+            .unwrap();
+
+        assert_eq!(peer.allowed_ips, vec![ip("10.13.13.10")]);
+        assert_eq!(vpn.assigned_ips[&ip("10.13.13.10")], PEER_KEY);
+        // the previous IP is released and assigned to the next peer
+        assert!(!vpn.assigned_ips.contains_key(&ip("10.13.13.2")));
+        let other_peer = vpn
+            .add_or_update_peer(NEW_PEER_KEY.to_string(), None, None, None)
+            .unwrap();
+        assert_eq!(other_peer.allowed_ips, vec![ip("10.13.13.2")]);
+        // the interface is updated too
+        let dump = vpn.backend.show_dump("wg0").unwrap();
+        assert_eq!(
+            dump.find_peer(PEER_KEY).unwrap().allowed_ips,
+            vec!["10.13.13.10/32".parse::<IpNet>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn reports_reserved_ips_held_by_other_peers() {
+        let mut vpn = new_vpn();
+        vpn.add_or_update_peer(NEW_PEER_KEY.to_string(), None, None, None)
+            .unwrap();
+        reserve(&mut vpn, PEER_KEY, "10.13.13.2");
+
+        assert_eq!(
+            vpn.reservation_conflicts(PEER_KEY),
+            vec![format!(
+                "IP 10.13.13.2 reserved for {PEER_KEY} is assigned to {NEW_PEER_KEY}"
+            )]
+        );
+        assert!(vpn.reservation_conflicts(NEW_PEER_KEY).is_empty());
+
+        // once the other peer is removed, the reserved peer can register
+        vpn.remove_peer(NEW_PEER_KEY).unwrap();
+        assert!(vpn.reservation_conflicts(PEER_KEY).is_empty());
+    }
+
+    #[test]
+    fn debug_output_has_no_secrets() {
+        let mut vpn = new_vpn();
+        vpn.config.registration_token = Some("registration-token".to_string());
+        vpn.add_or_update_peer(
+            PEER_KEY.to_string(),
+            Some(NEW_PEER_KEY.to_string()),
+            None,
+            None,
+        )
+        .unwrap();
+
+        let debug = format!("{vpn:?}");
+
+        assert!(debug.contains(PEER_KEY));
+        assert!(!debug.contains(NEW_PEER_KEY));
+        assert!(!debug.contains("registration-token"));
+    }
+
+    #[test]
+    fn rotation_registers_the_new_key_now() {
+        let mut vpn = vpn_with_old_peer();
+
+        let peer = vpn
+            .rotate_peer_key(PEER_KEY, NEW_PEER_KEY.to_string(), None)
+            .unwrap();
+
+        assert!(peer.registered_at.unwrap() >= unix_now() - 1);
+        assert_eq!(vpn.peers[NEW_PEER_KEY].registered_at, peer.registered_at);
+    }
+
+    #[test]
+    fn reading_the_interface_keeps_the_registration_time() {
+        let mut vpn = vpn_with_old_peer();
+        let peer_vpn_ip = vpn.peers[PEER_KEY].vpn_ip().unwrap();
+
+        let peer = vpn.refresh_and_get_peer(peer_vpn_ip).unwrap();
+        let dump = vpn.backend.show_dump("wg0").unwrap();
+        vpn.apply_dump(&dump);
+        vpn.reconcile(&dump, false);
+
+        assert_eq!(peer.registered_at, Some(1));
+        assert_eq!(vpn.peers[PEER_KEY].registered_at, Some(1));
+    }
+
+    #[test]
+    fn peers_found_on_the_interface_are_registered_now() {
+        let mut vpn = new_vpn();
+        vpn.backend
+            .set_peer(
+                "wg0",
+                &PeerConfig {
+                    public_key: PEER_KEY.to_string(),
+                    allowed_ips: vec!["10.13.13.2".parse().unwrap()],
+                    preshared_key: None,
+                    persistent_keepalive: None,
+                    endpoint: None,
+                },
+            )
+            .unwrap();
+
+        let peer = vpn
+            .refresh_and_get_peer("10.13.13.2".parse().unwrap())
+            .unwrap();
+
+        assert!(peer.registered_at.unwrap() >= unix_now() - 1);
+        assert_eq!(vpn.peers[PEER_KEY].registered_at, peer.registered_at);
+    }
+}
@@ -0,0 +1,185 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::models::GenericError;
+
+use super::{dump::WgDump, InterfaceInfo, PeerConfig, WireguardBackend};
+
+/// Backends that control WireGuard through the `wg` and `wg-quick` command line tools.
+///
+/// Every [WgCli] implementor is also a [WireguardBackend], so that implementors only
+/// have to define how the commands are executed.
+pub trait WgCli {
+    /// Runs a [wg command](https://manpages.debian.org/unstable/wireguard-tools/wg.8.en.html)
+    /// (or a [wg-quick command](https://manpages.debian.org/unstable/wireguard-tools/wg-quick.8.en.html) if `use_wg_quick` is `true`)
+    /// and returns its stdout
+    fn wg_command(&self, args: Vec<&str>, use_wg_quick: bool) -> Result<String, GenericError>;
+
+    /// Runs a wg command writing `stdin` to it, so that keys can be passed as `/dev/stdin`
+    /// instead of being written to a file or showing up in the command line
+    fn wg_command_with_stdin(&self, args: Vec<&str>, stdin: &str) -> Result<String, GenericError>;
+
+    /// Runs an [ip command](https://man7.org/linux/man-pages/man8/ip.8.html) where the interface lives
+    /// and returns its stdout
+    fn ip_command(&self, args: Vec<&str>) -> Result<String, GenericError>;
+}
+
+/// Parses the addresses in the output of `ip -o address show dev <interface>`, which has a line per address like:
+/// `4: wg0    inet 10.13.13.1/32 scope global wg0\       valid_lft forever preferred_lft forever`
+fn parse_ip_addresses(output: &str) -> Result<Vec<IpNet>, GenericError> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            words.find(|word| *word == "inet" || *word == "inet6")?;
+            words.next()
+        })
+        .map(|address| {
+            // point-to-point addresses are shown without the prefix length
+            address
+                .parse::<IpNet>()
+                .or_else(|_| address.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|e| format!("Invalid interface address {address}: {e}"))
+        })
+        .collect()
+}
+
+/// Runs a `wg set` command with the given arguments followed by the ones that configure the peer.
+/// `wg` reads the preshared key from a file: the key is written to `/dev/stdin`,
+/// while `/dev/null` removes the preshared key of the peer
+fn wg_set_peer<T: WgCli>(cli: &T, args: Vec<&str>, peer: &PeerConfig) -> Result<(), GenericError> {
+    let allowed_ips = peer
+        .allowed_ips
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<String>>()
+        .join(",");
+    let persistent_keepalive = peer
+        .persistent_keepalive
+        .map(|secs| secs.to_string())
+        .unwrap_or("off".to_string());
+    let endpoint = peer.endpoint.map(|endpoint| endpoint.to_string());
+
+    let mut args = args.into_iter().collect::<Vec<&str>>();
+    args.extend([
+        "peer",
+        peer.public_key.as_str(),
+        "allowed-ips",
+        allowed_ips.as_str(),
+        "persistent-keepalive",
+        persistent_keepalive.as_str(),
+    ]);
+    if let Some(endpoint) = endpoint.as_deref() {
+        args.extend(["endpoint", endpoint]);
+    }
+
+    match peer.preshared_key.as_deref() {
+        Some(preshared_key) => {
+            args.extend(["preshared-key", "/dev/stdin"]);
+            cli.wg_command_with_stdin(args, preshared_key)
+        }
+        None => {
+            args.extend(["preshared-key", "/dev/null"]);
+            cli.wg_command(args, false)
+        }
+    }
+    .map(|_| ())
+}
+
+impl<T> WireguardBackend for T
+where
+    T: WgCli + std::fmt::Debug + Default + Send + Sync + 'static,
+{
+    fn show_dump(&self, interface_name: &str) -> Result<WgDump, GenericError> {
+        self.wg_command(vec!["show", interface_name, "dump"], false)?
+            .parse::<WgDump>()
+            .map_err(|e| e.to_string())
+    }
+
+    fn set_peer(&self, interface_name: &str, peer: &PeerConfig) -> Result<(), GenericError> {
+        wg_set_peer(self, vec!["set", interface_name], peer)
+    }
+
+    fn replace_peer(
+        &self,
+        interface_name: &str,
+        old_public_key: &str,
+        peer: &PeerConfig,
+    ) -> Result<(), GenericError> {
+        // a single `wg set` applies all the changes to the interface at once
+        wg_set_peer(
+            self,
+            vec!["set", interface_name, "peer", old_public_key, "remove"],
+            peer,
+        )
+    }
+
+    fn remove_peer(&self, interface_name: &str, public_key: &str) -> Result<(), GenericError> {
+        self.wg_command(
+            vec!["set", interface_name, "peer", public_key, "remove"],
+            false,
+        )
+        .map(|_| ())
+    }
+
+    fn interface_names(&self) -> Result<Vec<String>, GenericError> {
+        // the output contains the names of the interfaces separated by spaces
+        let names = self
+            .wg_command(vec!["show", "interfaces"], false)
+            .map_err(|e| format!("Error getting interface names: {e}"))?
+            .split_whitespace()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+
+        if names.is_empty() {
+            return Err("Error getting interface names: no interface is up".to_string());
+        }
+
+        Ok(names)
+    }
+
+    fn interface_info(&self, interface_name: &str) -> Result<InterfaceInfo, GenericError> {
+        let public_key = self
+            .wg_command(vec!["show", interface_name, "public-key"], false)
+            .map_err(|e| format!("Error getting public key of {interface_name}: {e}"))?
+            .trim()
+            .to_string();
+
+        let listen_port = self
+            .wg_command(vec!["show", interface_name, "listen-port"], false)
+            .map_err(|e| format!("Error getting listen port of {interface_name}: {e}"))?
+            .trim()
+            .parse::<u16>()
+            .map_err(|e| format!("Invalid listen port of {interface_name}: {e}"))?;
+
+        Ok(InterfaceInfo {
+            name: interface_name.to_string(),
+            public_key,
+            listen_port,
+        })
+    }
+
+    fn set_private_key(&self, interface_name: &str, private_key: &str) -> Result<(), GenericError> {
+        // like the preshared key, `wg` reads the private key from a file
+        self.wg_command_with_stdin(
+            vec!["set", interface_name, "private-key", "/dev/stdin"],
+            private_key,
+        )
+        .map(|_| ())
+        .map_err(|e| format!("Error setting private key of {interface_name}: {e}"))
+    }
+
+    fn interface_addresses(&self, interface_name: &str) -> Result<Vec<IpNet>, GenericError> {
+        let output = self
+            .ip_command(vec!["-o", "address", "show", "dev", interface_name])
+            .map_err(|e| format!("Error getting the addresses of {interface_name}: {e}"))?;
+
+        parse_ip_addresses(&output)
+    }
+
+    fn save_config(&self, interface_name: &str) -> Result<(), GenericError> {
+        self.wg_command(vec!["save", interface_name], true)
+            .map(|_| ())
+    }
+}
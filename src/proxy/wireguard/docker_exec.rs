@@ -0,0 +1,37 @@
+use crate::{env::get_env_var, models::GenericError, proxy::docker::docker_exec};
+
+use super::cli::WgCli;
+
+/// Controls WireGuard running in another container, creating exec instances through the Docker Engine API.
+/// The container is the one specified in the `WIREGUARD_CONTAINER_NAME` env variable.
+#[derive(Debug, Default)]
+pub struct DockerExecBackend;
+
+impl WgCli for DockerExecBackend {
+    fn wg_command(&self, args: Vec<&str>, use_wg_quick: bool) -> Result<String, GenericError> {
+        let wireguard_container_name = get_env_var("WIREGUARD_CONTAINER_NAME");
+
+        let mut command = vec![if use_wg_quick { "wg-quick" } else { "wg" }];
+        command.extend(args);
+
+        docker_exec(&wireguard_container_name, command, None).map_err(|e| e.to_string())
+    }
+
+    fn wg_command_with_stdin(&self, args: Vec<&str>, stdin: &str) -> Result<String, GenericError> {
+        let wireguard_container_name = get_env_var("WIREGUARD_CONTAINER_NAME");
+
+        let mut command = vec!["wg"];
+        command.extend(args);
+
+        docker_exec(&wireguard_container_name, command, Some(stdin)).map_err(|e| e.to_string())
+    }
+
+    fn ip_command(&self, args: Vec<&str>) -> Result<String, GenericError> {
+        let wireguard_container_name = get_env_var("WIREGUARD_CONTAINER_NAME");
+
+        let mut command = vec!["ip"];
+        command.extend(args);
+
+        docker_exec(&wireguard_container_name, command, None).map_err(|e| e.to_string())
+    }
+}
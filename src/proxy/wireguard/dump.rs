@@ -0,0 +1,325 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use ipnet::IpNet;
+
+/// The interface line of [`wg show <interface> dump`](https://manpages.debian.org/unstable/wireguard-tools/wg.8.en.html#show)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WgDumpInterface {
+    /// The base64 encoded private key of the interface
+    pub private_key: Option<String>,
+    /// The base64 encoded public key of the interface
+    pub public_key: Option<String>,
+    pub listen_port: u16,
+    pub fwmark: Option<u32>,
+}
+
+/// A peer line of [`wg show <interface> dump`](https://manpages.debian.org/unstable/wireguard-tools/wg.8.en.html#show)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WgDumpPeer {
+    /// The base64 encoded public key of the peer
+    pub public_key: String,
+    /// The base64 encoded preshared key of the peer
+    pub preshared_key: Option<String>,
+    /// The last known public address of the peer
+    pub endpoint: Option<SocketAddr>,
+    pub allowed_ips: Vec<IpNet>,
+    /// Seconds since the UNIX epoch of the most recent handshake, `None` if the peer never completed one
+    pub latest_handshake: Option<u64>,
+    /// Bytes received from the peer
+    pub transfer_rx: u64,
+    /// Bytes sent to the peer
+    pub transfer_tx: u64,
+    /// The persistent keepalive interval in seconds, `None` if disabled
+    pub persistent_keepalive: Option<u16>,
+}
+
+/// The parsed output of `wg show <interface> dump`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WgDump {
+    pub interface: WgDumpInterface,
+    pub peers: Vec<WgDumpPeer>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WgDumpError {
+    /// The dump doesn't even contain the interface line
+    MissingInterface,
+    /// A line of the dump can't be parsed. `line` starts from 1
+    InvalidLine { line: usize, reason: String },
+}
+
+impl fmt::Display for WgDumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WgDumpError::MissingInterface => write!(f, "Invalid dump: missing interface line"),
+            WgDumpError::InvalidLine { line, reason } => {
+                write!(f, "Invalid dump at line {line}: {reason}")
+            }
+        }
+    }
+}
+
+impl WgDump {
+    /// Returns the peer with the given public key
+    pub fn find_peer(&self, public_key: &str) -> Option<&WgDumpPeer> {
+        self.peers.iter().find(|peer| peer.public_key == public_key)
+    }
+
+    /// Returns the peer that has the given ip among its allowed ips
+    pub fn find_peer_by_ip(&self, ip: IpAddr) -> Option<&WgDumpPeer> {
+        self.peers.iter().find(|peer| {
+            peer.allowed_ips
+                .iter()
+                .any(|allowed_ip| allowed_ip.addr() == ip)
+        })
+    }
+}
+
+/// `wg` prints `(none)` for missing values
+fn optional(value: &str) -> Option<&str> {
+    match value {
+        "" | "(none)" => None,
+        value => Some(value),
+    }
+}
+
+fn parse_field<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse::<T>()
+        .map_err(|e| format!("invalid {name} {value}: {e}"))
+}
+
+/// Parses an allowed ip, which is printed as `ip/cidr` (a plain ip is considered a single host)
+fn parse_allowed_ip(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|e| format!("invalid allowed ip {value}: {e}"))
+}
+
+/// `wg` prints `off` for a disabled fwmark, otherwise the hex value
+fn parse_fwmark(value: &str) -> Result<Option<u32>, String> {
+    match value {
+        "off" | "0" => Ok(None),
+        value => u32::from_str_radix(value.trim_start_matches("0x"), 16)
+            .map(Some)
+            .map_err(|e| format!("invalid fwmark {value}: {e}")),
+    }
+}
+
+fn split_columns(line: &str, expected: usize) -> Result<Vec<&str>, String> {
+    let columns = line.split('\t').collect::<Vec<&str>>();
+
+    if columns.len() == expected {
+        Ok(columns)
+    } else {
+        Err(format!(
+            "expected {expected} tab separated columns, found {}",
+            columns.len()
+        ))
+    }
+}
+
+impl FromStr for WgDumpInterface {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let columns = split_columns(line, 4)?;
+
+        Ok(WgDumpInterface {
+            private_key: optional(columns[0]).map(|key| key.to_string()),
+            public_key: optional(columns[1]).map(|key| key.to_string()),
+            listen_port: parse_field("listen port", columns[2])?,
+            fwmark: parse_fwmark(columns[3])?,
+        })
+    }
+}
+
+impl FromStr for WgDumpPeer {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let columns = split_columns(line, 8)?;
+
+        let allowed_ips = match optional(columns[3]) {
+            Some(allowed_ips) => allowed_ips
+                .split(',')
+                .map(parse_allowed_ip)
+                .collect::<Result<Vec<IpNet>, String>>()?,
+            None => vec![],
+        };
+
+        Ok(WgDumpPeer {
+            public_key: optional(columns[0])
+                .ok_or("missing public key")?
+                .to_string(),
+            preshared_key: optional(columns[1]).map(|key| key.to_string()),
+            endpoint: optional(columns[2])
+                .map(|endpoint| parse_field("endpoint", endpoint))
+                .transpose()?,
+            allowed_ips,
+            latest_handshake: match parse_field("latest handshake", columns[4])? {
+                0 => None,
+                timestamp => Some(timestamp),
+            },
+            transfer_rx: parse_field("transfer rx", columns[5])?,
+            transfer_tx: parse_field("transfer tx", columns[6])?,
+            persistent_keepalive: match columns[7] {
+                "off" | "0" => None,
+                interval => Some(parse_field("persistent keepalive", interval)?),
+            },
+        })
+    }
+}
+
+impl FromStr for WgDump {
+    type Err = WgDumpError;
+
+    fn from_str(dump: &str) -> Result<Self, Self::Err> {
+        let mut lines = dump
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty());
+
+        // first line is the interface config, the others are the peers
+        let (index, interface_line) = lines.next().ok_or(WgDumpError::MissingInterface)?;
+        let interface = interface_line
+            .parse::<WgDumpInterface>()
+            .map_err(|reason| WgDumpError::InvalidLine {
+                line: index + 1,
+                reason,
+            })?;
+
+        let peers = lines
+            .map(|(index, line)| {
+                line.parse::<WgDumpPeer>()
+                    .map_err(|reason| WgDumpError::InvalidLine {
+                        line: index + 1,
+                        reason,
+                    })
+            })
+            .collect::<Result<Vec<WgDumpPeer>, WgDumpError>>()?;
+
+        Ok(WgDump { interface, peers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRIVATE_KEY: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+    const PUBLIC_KEY: &str = "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE=";
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+    const OTHER_PEER_KEY: &str = "CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=";
+
+    /// The output of `wg show wg0 dump`: a connected dual-stack peer and a peer that never connected
+    fn dump() -> String {
+        format!(
+            "{PRIVATE_KEY}\t{PUBLIC_KEY}\t51820\toff\n\
+             {PEER_KEY}\t{OTHER_PEER_KEY}\t203.0.113.7:51820\t10.13.13.2/32,fd13:13:13::2/128\t1700000000\t2048\t1024\t25\n\
+             {OTHER_PEER_KEY}\t(none)\t(none)\t10.13.13.3/32\t0\t0\t0\toff\n"
+        )
+    }
+
+    #[test]
+    fn parses_a_dump() {
+        let dump = dump().parse::<WgDump>().unwrap();
+
+        assert_eq!(
+            dump.interface,
+            WgDumpInterface {
+                private_key: Some(PRIVATE_KEY.to_string()),
+                public_key: Some(PUBLIC_KEY.to_string()),
+                listen_port: 51820,
+                fwmark: None,
+            }
+        );
+        assert_eq!(
+            dump.peers[0],
+            WgDumpPeer {
+                public_key: PEER_KEY.to_string(),
+                preshared_key: Some(OTHER_PEER_KEY.to_string()),
+                endpoint: Some("203.0.113.7:51820".parse().unwrap()),
+                allowed_ips: vec![
+                    "10.13.13.2/32".parse().unwrap(),
+                    "fd13:13:13::2/128".parse().unwrap()
+                ],
+                latest_handshake: Some(1700000000),
+                transfer_rx: 2048,
+                transfer_tx: 1024,
+                persistent_keepalive: Some(25),
+            }
+        );
+        assert_eq!(
+            dump.find_peer_by_ip("fd13:13:13::2".parse().unwrap()),
+            Some(&dump.peers[0])
+        );
+    }
+
+    #[test]
+    fn parses_missing_values() {
+        let dump = dump().parse::<WgDump>().unwrap();
+
+        let peer = dump.find_peer(OTHER_PEER_KEY).unwrap();
+        assert_eq!(peer.preshared_key, None);
+        assert_eq!(peer.endpoint, None);
+        assert_eq!(peer.latest_handshake, None);
+        assert_eq!(peer.persistent_keepalive, None);
+    }
+
+    #[test]
+    fn parses_ipv6_endpoints_and_fwmarks() {
+        let dump = format!(
+            "(none)\t(none)\t0\t0xca6c\n\
+             {PEER_KEY}\t(none)\t[2001:db8::7]:51820\t(none)\t0\t0\t0\toff\n"
+        )
+        .parse::<WgDump>()
+        .unwrap();
+
+        assert_eq!(dump.interface.private_key, None);
+        assert_eq!(dump.interface.fwmark, Some(0xca6c));
+        assert_eq!(
+            dump.peers[0].endpoint,
+            Some("[2001:db8::7]:51820".parse().unwrap())
+        );
+        assert!(dump.peers[0].allowed_ips.is_empty());
+    }
+
+    #[test]
+    fn rejects_lines_with_the_wrong_column_count() {
+        let dump = format!(
+            "{PRIVATE_KEY}\t{PUBLIC_KEY}\t51820\toff\n{PEER_KEY}\t(none)\t(none)\t10.13.13.2/32\t0\n"
+        );
+
+        assert!(matches!(
+            dump.parse::<WgDump>(),
+            Err(WgDumpError::InvalidLine { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let dump = format!(
+            "{PRIVATE_KEY}\t{PUBLIC_KEY}\t51820\toff\n{PEER_KEY}\t(none)\t(none)\t10.13.13.2/32\tyesterday\t0\t0\toff\n"
+        );
+
+        assert!(matches!(
+            dump.parse::<WgDump>(),
+            Err(WgDumpError::InvalidLine { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_empty_dumps() {
+        assert_eq!("".parse::<WgDump>(), Err(WgDumpError::MissingInterface));
+        assert_eq!("\n\n".parse::<WgDump>(), Err(WgDumpError::MissingInterface));
+    }
+}
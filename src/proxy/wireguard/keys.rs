@@ -0,0 +1,62 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::{rngs::OsRng, RngCore};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::models::GenericError;
+
+/// Length in bytes of WireGuard keys (private, public and preshared)
+pub const KEY_LEN: usize = 32;
+
+fn decode_base64_key(key: &str) -> Result<[u8; KEY_LEN], GenericError> {
+    STANDARD
+        .decode(key)
+        .map_err(|e| format!("Invalid key: {e}"))?
+        .try_into()
+        .map_err(|_| format!("Invalid key: expected {KEY_LEN} bytes"))
+}
+
+/// Checks that the key is a base64 encoded WireGuard key
+pub fn validate_key(key: &str) -> Result<(), GenericError> {
+    decode_base64_key(key).map(|_| ())
+}
+
+/// Generates a random base64 encoded preshared key, like `wg genpsk`
+pub fn generate_preshared_key() -> String {
+    let mut key = [0u8; KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+
+    STANDARD.encode(key)
+}
+
+/// Generates a random x25519 keypair, like `wg genkey | tee private.key | wg pubkey`.
+/// Returns the base64 encoded private and public keys
+pub fn generate_keypair() -> (String, String) {
+    let secret = StaticSecret::random_from_rng(OsRng);
+
+    (
+        STANDARD.encode(secret.to_bytes()),
+        STANDARD.encode(PublicKey::from(&secret).as_bytes()),
+    )
+}
+
+/// Converts a base64 encoded key (the format used by `wg`) to the hex format used by the UAPI
+pub fn base64_to_hex(key: &str) -> Result<String, GenericError> {
+    decode_base64_key(key).map(hex::encode)
+}
+
+/// Converts a hex encoded key (the format used by the UAPI) to the base64 format used by `wg`
+pub fn hex_to_base64(key: &str) -> Result<String, GenericError> {
+    let bytes: [u8; KEY_LEN] = hex::decode(key)
+        .map_err(|e| format!("Invalid hex key: {e}"))?
+        .try_into()
+        .map_err(|_| format!("Invalid hex key: expected {KEY_LEN} bytes"))?;
+
+    Ok(STANDARD.encode(bytes))
+}
+
+/// Derives the base64 encoded public key from a base64 encoded private key, like `wg pubkey`
+pub fn public_key_from_private(private_key: &str) -> Result<String, GenericError> {
+    let secret = StaticSecret::from(decode_base64_key(private_key)?);
+
+    Ok(STANDARD.encode(PublicKey::from(&secret).as_bytes()))
+}
@@ -0,0 +1,71 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use crate::models::GenericError;
+
+use super::cli::WgCli;
+
+/// Controls WireGuard running on the same host (or container) of the proxy,
+/// invoking `wg` and `wg-quick` directly.
+/// The proxy must have the `NET_ADMIN` capability for this to work.
+#[derive(Debug, Default)]
+pub struct LocalBackend;
+
+impl LocalBackend {
+    /// Runs the program with the given arguments, writing `stdin` to it if set, and returns its stdout
+    fn run(program: &str, args: Vec<&str>, stdin: Option<&str>) -> Result<String, GenericError> {
+        let mut child = Command::new(program)
+            .args(&args)
+            .stdin(if stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to execute {program}: {e}"))?;
+
+        if let (Some(stdin), Some(mut child_stdin)) = (stdin, child.stdin.take()) {
+            // the pipe is closed when `child_stdin` is dropped, so the program sees the end of its input
+            child_stdin
+                .write_all(stdin.as_bytes())
+                .map_err(|e| format!("Failed to write to {program} stdin: {e}"))?;
+        }
+
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to execute {program}: {e}"))?;
+
+        if output.status.success() {
+            Ok(String::from_utf8_lossy(&output.stdout).to_string())
+        } else {
+            let err = format!(
+                "{program} {} exited with status: {}, stderr:\n{}",
+                args.join(" "),
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+
+            println!("Error executing {program} command: {err}");
+
+            Err(err)
+        }
+    }
+}
+
+impl WgCli for LocalBackend {
+    fn wg_command(&self, args: Vec<&str>, use_wg_quick: bool) -> Result<String, GenericError> {
+        Self::run(if use_wg_quick { "wg-quick" } else { "wg" }, args, None)
+    }
+
+    fn wg_command_with_stdin(&self, args: Vec<&str>, stdin: &str) -> Result<String, GenericError> {
+        Self::run("wg", args, Some(stdin))
+    }
+
+    fn ip_command(&self, args: Vec<&str>) -> Result<String, GenericError> {
+        Self::run("ip", args, None)
+    }
+}
@@ -0,0 +1,213 @@
+use std::{collections::BTreeMap, net::IpAddr, sync::Mutex};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ipnet::IpNet;
+
+use crate::{env::get_env_var_or, models::GenericError};
+
+use super::{
+    dump::{WgDump, WgDumpInterface, WgDumpPeer},
+    keys::{public_key_from_private, validate_key, KEY_LEN},
+    InterfaceInfo, PeerConfig, WireguardBackend,
+};
+
+const DEFAULT_MEMORY_INTERFACES: &str = "wg0";
+const MEMORY_FIRST_LISTEN_PORT: u16 = 51820;
+
+#[derive(Debug, Clone)]
+struct MemoryPeer {
+    allowed_ips: Vec<IpAddr>,
+    preshared_key: Option<String>,
+    persistent_keepalive: Option<u16>,
+}
+
+impl From<&PeerConfig> for MemoryPeer {
+    fn from(peer: &PeerConfig) -> Self {
+        Self {
+            allowed_ips: peer.allowed_ips.clone(),
+            preshared_key: peer.preshared_key.clone(),
+            persistent_keepalive: peer.persistent_keepalive,
+        }
+    }
+}
+
+/// A fake backend that keeps the peers in memory, useful for tests and local development.
+/// It exposes the interfaces listed in the `WIREGUARD_MEMORY_INTERFACES` env variable, separated by commas
+/// (default: `wg0`), and their peers never connect, so they have no endpoint.
+#[derive(Debug)]
+pub struct MemoryBackend {
+    /// The names of the interfaces, in the order they were listed
+    interface_names: Vec<String>,
+    /// The peers of each interface: interface name -> public key -> peer
+    peers: Mutex<BTreeMap<String, BTreeMap<String, MemoryPeer>>>,
+    /// The private keys set with [WireguardBackend::set_private_key]: interface name -> private key
+    private_keys: Mutex<BTreeMap<String, String>>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new(
+            get_env_var_or("WIREGUARD_MEMORY_INTERFACES", DEFAULT_MEMORY_INTERFACES)
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect(),
+        )
+    }
+}
+
+impl MemoryBackend {
+    pub fn new(interface_names: Vec<String>) -> Self {
+        let peers = interface_names
+            .iter()
+            .map(|name| (name.clone(), BTreeMap::new()))
+            .collect();
+
+        Self {
+            interface_names,
+            peers: Mutex::new(peers),
+            private_keys: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The position of the interface in the list, from which its public key and listen port are derived
+    fn interface_index(&self, interface_name: &str) -> Result<usize, GenericError> {
+        self.interface_names
+            .iter()
+            .position(|name| name == interface_name)
+            .ok_or(format!(
+                "Unable to access interface {interface_name}: No such device"
+            ))
+    }
+
+    /// A syntactically valid public key, different for each interface
+    /// (the first interface gets 32 zero bytes, base64 encoded)
+    fn interface_public_key(index: usize) -> String {
+        STANDARD.encode([index as u8; KEY_LEN])
+    }
+
+    /// The private key set on the interface, if any: until it's set, the interface has no private key
+    fn private_key(&self, interface_name: &str) -> Option<String> {
+        self.private_keys
+            .lock()
+            .unwrap()
+            .get(interface_name)
+            .cloned()
+    }
+
+    fn check_interface(&self, interface_name: &str) -> Result<(), GenericError> {
+        self.interface_index(interface_name).map(|_| ())
+    }
+
+    /// Runs `f` on the peers of the interface
+    fn with_peers<T>(
+        &self,
+        interface_name: &str,
+        f: impl FnOnce(&mut BTreeMap<String, MemoryPeer>) -> T,
+    ) -> Result<T, GenericError> {
+        self.check_interface(interface_name)?;
+
+        let mut peers = self.peers.lock().unwrap();
+        // every listed interface has its peers map
+        Ok(f(peers.get_mut(interface_name).unwrap()))
+    }
+}
+
+impl WireguardBackend for MemoryBackend {
+    fn show_dump(&self, interface_name: &str) -> Result<WgDump, GenericError> {
+        let info = self.interface_info(interface_name)?;
+
+        let peers = self.with_peers(interface_name, |peers| {
+            peers
+                .iter()
+                .map(|(public_key, peer)| WgDumpPeer {
+                    public_key: public_key.clone(),
+                    preshared_key: peer.preshared_key.clone(),
+                    persistent_keepalive: peer.persistent_keepalive,
+                    allowed_ips: peer.allowed_ips.iter().map(|ip| (*ip).into()).collect(),
+                    ..Default::default()
+                })
+                .collect()
+        })?;
+
+        Ok(WgDump {
+            interface: WgDumpInterface {
+                private_key: self.private_key(interface_name),
+                public_key: Some(info.public_key),
+                listen_port: info.listen_port,
+                fwmark: None,
+            },
+            peers,
+        })
+    }
+
+    fn set_peer(&self, interface_name: &str, peer: &PeerConfig) -> Result<(), GenericError> {
+        self.with_peers(interface_name, |peers| {
+            peers.insert(peer.public_key.clone(), MemoryPeer::from(peer));
+        })
+    }
+
+    fn replace_peer(
+        &self,
+        interface_name: &str,
+        old_public_key: &str,
+        peer: &PeerConfig,
+    ) -> Result<(), GenericError> {
+        self.with_peers(interface_name, |peers| {
+            peers.remove(old_public_key);
+            peers.insert(peer.public_key.clone(), MemoryPeer::from(peer));
+        })
+    }
+
+    fn remove_peer(&self, interface_name: &str, public_key: &str) -> Result<(), GenericError> {
+        self.with_peers(interface_name, |peers| {
+            peers.remove(public_key);
+        })
+    }
+
+    fn interface_names(&self) -> Result<Vec<String>, GenericError> {
+        if self.interface_names.is_empty() {
+            return Err("Error getting interface names: no interface is up".to_string());
+        }
+
+        Ok(self.interface_names.clone())
+    }
+
+    fn interface_info(&self, interface_name: &str) -> Result<InterfaceInfo, GenericError> {
+        let index = self.interface_index(interface_name)?;
+        let public_key = match self.private_key(interface_name) {
+            Some(private_key) => public_key_from_private(&private_key)?,
+            None => Self::interface_public_key(index),
+        };
+
+        Ok(InterfaceInfo {
+            name: interface_name.to_string(),
+            public_key,
+            listen_port: MEMORY_FIRST_LISTEN_PORT + index as u16,
+        })
+    }
+
+    fn set_private_key(&self, interface_name: &str, private_key: &str) -> Result<(), GenericError> {
+        self.check_interface(interface_name)?;
+        validate_key(private_key)?;
+
+        self.private_keys
+            .lock()
+            .unwrap()
+            .insert(interface_name.to_string(), private_key.to_string());
+
+        Ok(())
+    }
+
+    fn interface_addresses(&self, interface_name: &str) -> Result<Vec<IpNet>, GenericError> {
+        self.check_interface(interface_name)?;
+
+        // the interface doesn't exist, so it has no addresses to check
+        Ok(Vec::new())
+    }
+
+    fn save_config(&self, interface_name: &str) -> Result<(), GenericError> {
+        // there is nothing to persist
+        self.check_interface(interface_name)
+    }
+}
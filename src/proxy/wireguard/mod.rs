@@ -0,0 +1,111 @@
+use std::{
+    fmt::Debug,
+    net::{IpAddr, SocketAddr},
+};
+
+use ipnet::IpNet;
+
+use crate::{env::get_env_var_or, models::GenericError};
+
+use self::dump::WgDump;
+
+mod cli;
+pub mod docker_exec;
+pub mod dump;
+pub mod keys;
+pub mod local;
+pub mod memory;
+pub mod uapi;
+
+/// An interface managed by the proxy, as reported by the backend
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub public_key: String,
+    /// The UDP port the interface listens on for the peers
+    pub listen_port: u16,
+}
+
+/// The configuration of a peer, as applied to the interface
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    pub public_key: String,
+    /// The addresses of the peer, each one routed to the peer as a single host (`/32` or `/128`)
+    pub allowed_ips: Vec<IpAddr>,
+    /// `None` removes the preshared key of the peer
+    pub preshared_key: Option<String>,
+    /// Seconds between the keepalive packets sent to the peer, `None` disables them
+    pub persistent_keepalive: Option<u16>,
+    /// `None` leaves the endpoint of the peer as it is
+    pub endpoint: Option<SocketAddr>,
+}
+
+/// The operations the proxy needs to control a WireGuard interface.
+///
+/// Backends must be constructible with [Default], since they are not persisted in the DB
+/// and are recreated (from env variables, if needed) every time the DB is loaded from disk.
+pub trait WireguardBackend: Debug + Default + Send + Sync + 'static {
+    /// Returns the state of the interface and its peers, as reported by [`wg show <interface> dump`](https://manpages.debian.org/unstable/wireguard-tools/wg.8.en.html#show)
+    fn show_dump(&self, interface_name: &str) -> Result<WgDump, GenericError>;
+
+    /// Adds the peer to the interface, or replaces its configuration if it already exists
+    fn set_peer(&self, interface_name: &str, peer: &PeerConfig) -> Result<(), GenericError>;
+
+    /// Replaces the public key of a peer in a single operation, moving its allowed ips to the new key,
+    /// so that the peer is never left without a key that can reach its VPN IP
+    fn replace_peer(
+        &self,
+        interface_name: &str,
+        old_public_key: &str,
+        peer: &PeerConfig,
+    ) -> Result<(), GenericError>;
+
+    /// Removes the peer from the interface
+    fn remove_peer(&self, interface_name: &str, public_key: &str) -> Result<(), GenericError>;
+
+    /// Returns the names of the interfaces that are up, sorted as the backend lists them.
+    /// The first one is the interface managed by default
+    fn interface_names(&self) -> Result<Vec<String>, GenericError>;
+
+    /// Returns the name, the public key and the listen port of the interface
+    fn interface_info(&self, interface_name: &str) -> Result<InterfaceInfo, GenericError>;
+
+    /// Replaces the private key of the interface (and so its public key), e.g. to restore the keys
+    /// of the interface of another proxy, so that its peers can connect without changing their config
+    fn set_private_key(&self, interface_name: &str, private_key: &str) -> Result<(), GenericError>;
+
+    /// Returns the addresses assigned to the interface, with their prefix length.
+    /// An empty list means that the backend can't read them
+    fn interface_addresses(&self, interface_name: &str) -> Result<Vec<IpNet>, GenericError>;
+
+    /// Persists the running configuration of the interface, so that it survives a restart
+    fn save_config(&self, interface_name: &str) -> Result<(), GenericError>;
+}
+
+/// The available [WireguardBackend] implementations, selected with the `WIREGUARD_BACKEND` env variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireguardBackendKind {
+    /// Runs `wg` and `wg-quick` in the `WIREGUARD_CONTAINER_NAME` container, see [docker_exec::DockerExecBackend]
+    DockerExec,
+    /// Runs `wg` and `wg-quick` on the same host of the proxy, see [local::LocalBackend]
+    Local,
+    /// Keeps the peers in memory without touching any interface, see [memory::MemoryBackend]
+    Memory,
+    /// Talks to a userspace WireGuard implementation over its UAPI socket, see [uapi::UapiBackend]
+    Uapi,
+}
+
+impl WireguardBackendKind {
+    /// Reads the backend kind from the `WIREGUARD_BACKEND` env variable, defaulting to `docker`
+    pub fn from_env() -> Result<Self, GenericError> {
+        match get_env_var_or("WIREGUARD_BACKEND", "docker").as_str() {
+            "docker" => Ok(Self::DockerExec),
+            "local" => Ok(Self::Local),
+            "memory" => Ok(Self::Memory),
+            "uapi" => Ok(Self::Uapi),
+            other => Err(format!(
+                "Unknown WireGuard backend: {other}, expected one of docker, local, memory, uapi"
+            )),
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    os::unix::{fs::OpenOptionsExt, net::UnixStream},
+    path::PathBuf,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+
+use crate::{env::get_env_var_or, models::GenericError};
+
+use super::{
+    dump::{WgDump, WgDumpPeer},
+    keys::{base64_to_hex, hex_to_base64, public_key_from_private, KEY_LEN},
+    InterfaceInfo, PeerConfig, WireguardBackend,
+};
+
+const DEFAULT_UAPI_SOCKET_DIR: &str = "/var/run/wireguard";
+const UAPI_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Parses the `key=value` pairs returned by a UAPI `get=1` operation
+fn parse_get_response(pairs: Vec<(String, String)>) -> Result<WgDump, GenericError> {
+    let mut dump = WgDump::default();
+
+    for (key, value) in pairs {
+        let invalid = |e: &dyn std::fmt::Display| format!("Invalid UAPI {key}={value}: {e}");
+
+        // interface keys come first, then every `public_key` starts a new peer
+        if key == "public_key" {
+            dump.peers.push(WgDumpPeer {
+                public_key: hex_to_base64(&value)?,
+                ..Default::default()
+            });
+            continue;
+        }
+
+        match dump.peers.last_mut() {
+            None => match key.as_str() {
+                "private_key" => {
+                    let private_key = hex_to_base64(&value)?;
+                    dump.interface.public_key = Some(public_key_from_private(&private_key)?);
+                    dump.interface.private_key = Some(private_key);
+                }
+                "listen_port" => {
+                    dump.interface.listen_port = value.parse().map_err(|e| invalid(&e))?
+                }
+                "fwmark" => {
+                    dump.interface.fwmark = match value.parse().map_err(|e| invalid(&e))? {
+                        0 => None,
+                        fwmark => Some(fwmark),
+                    }
+                }
+                _ => println!("Ignoring UAPI interface key {key}"),
+            },
+            Some(peer) => match key.as_str() {
+                // an all-zero preshared key means that the peer has no preshared key
+                "preshared_key" if value.chars().any(|c| c != '0') => {
+                    peer.preshared_key = Some(hex_to_base64(&value)?);
+                }
+                "endpoint" => peer.endpoint = Some(value.parse().map_err(|e| invalid(&e))?),
+                "allowed_ip" => peer
+                    .allowed_ips
+                    .push(value.parse().map_err(|e| invalid(&e))?),
+                // we ignore the nanoseconds, like `wg show` does
+                "last_handshake_time_sec" => {
+                    peer.latest_handshake = match value.parse().map_err(|e| invalid(&e))? {
+                        0 => None,
+                        timestamp => Some(timestamp),
+                    }
+                }
+                "rx_bytes" => peer.transfer_rx = value.parse().map_err(|e| invalid(&e))?,
+                "tx_bytes" => peer.transfer_tx = value.parse().map_err(|e| invalid(&e))?,
+                "persistent_keepalive_interval" => {
+                    peer.persistent_keepalive = match value.parse().map_err(|e| invalid(&e))? {
+                        0 => None,
+                        interval => Some(interval),
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+
+    Ok(dump)
+}
+
+/// Renders the interface in the same format of `wg showconf <interface>`
+fn to_config(dump: &WgDump) -> String {
+    let mut config = "[Interface]\n".to_string();
+    config.push_str(&format!("ListenPort = {}\n", dump.interface.listen_port));
+    if let Some(fwmark) = dump.interface.fwmark {
+        config.push_str(&format!("FwMark = 0x{fwmark:x}\n"));
+    }
+    if let Some(private_key) = &dump.interface.private_key {
+        config.push_str(&format!("PrivateKey = {private_key}\n"));
+    }
+
+    for peer in &dump.peers {
+        config.push_str(&format!("\n[Peer]\nPublicKey = {}\n", peer.public_key));
+        if let Some(preshared_key) = &peer.preshared_key {
+            config.push_str(&format!("PresharedKey = {preshared_key}\n"));
+        }
+        if !peer.allowed_ips.is_empty() {
+            let allowed_ips = peer
+                .allowed_ips
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            config.push_str(&format!("AllowedIPs = {allowed_ips}\n"));
+        }
+        if let Some(endpoint) = peer.endpoint {
+            config.push_str(&format!("Endpoint = {endpoint}\n"));
+        }
+        if let Some(interval) = peer.persistent_keepalive {
+            config.push_str(&format!("PersistentKeepalive = {interval}\n"));
+        }
+    }
+
+    config
+}
+
+/// The lines of a `set=1` operation that configure a peer.
+/// An all-zero preshared key removes the preshared key, a zero keepalive interval disables keepalives
+fn peer_lines(peer: &PeerConfig) -> Result<Vec<String>, GenericError> {
+    let preshared_key = match peer.preshared_key.as_deref() {
+        Some(preshared_key) => base64_to_hex(preshared_key)?,
+        None => "0".repeat(KEY_LEN * 2),
+    };
+
+    let mut lines = vec![
+        format!("public_key={}", base64_to_hex(&peer.public_key)?),
+        format!("preshared_key={preshared_key}"),
+        format!(
+            "persistent_keepalive_interval={}",
+            peer.persistent_keepalive.unwrap_or(0)
+        ),
+    ];
+    if let Some(endpoint) = peer.endpoint {
+        lines.push(format!("endpoint={endpoint}"));
+    }
+    lines.push("replace_allowed_ips=true".to_string());
+    lines.extend(
+        peer.allowed_ips
+            .iter()
+            .map(|ip| format!("allowed_ip={}", IpNet::from(*ip))),
+    );
+
+    Ok(lines)
+}
+
+/// Controls WireGuard through the [cross-platform userspace API](https://www.wireguard.com/xplatform/),
+/// exposed on the `<socket_dir>/<interface>.sock` unix socket by userspace implementations
+/// (e.g. `wireguard-go` or `boringtun`). It doesn't need the `wg` binary nor the Docker CLI.
+///
+/// The socket directory is read from the `WIREGUARD_UAPI_SOCKET_DIR` env variable (default: `/var/run/wireguard`).
+/// Since the UAPI has no notion of configuration files, [WireguardBackend::save_config] writes the
+/// running configuration (in the `wg showconf` format) to `WIREGUARD_CONFIG_DIR/<interface>.conf`, if set.
+#[derive(Debug)]
+pub struct UapiBackend {
+    socket_dir: PathBuf,
+    config_dir: Option<PathBuf>,
+}
+
+impl Default for UapiBackend {
+    fn default() -> Self {
+        Self::new(
+            PathBuf::from(get_env_var_or(
+                "WIREGUARD_UAPI_SOCKET_DIR",
+                DEFAULT_UAPI_SOCKET_DIR,
+            )),
+            std::env::var("WIREGUARD_CONFIG_DIR")
+                .ok()
+                .map(PathBuf::from),
+        )
+    }
+}
+
+impl UapiBackend {
+    pub fn new(socket_dir: PathBuf, config_dir: Option<PathBuf>) -> Self {
+        Self {
+            socket_dir,
+            config_dir,
+        }
+    }
+
+    /// Sends an operation to the interface socket and returns the `key=value` pairs of the response,
+    /// failing if the response contains a non-zero `errno`
+    fn request(
+        &self,
+        interface_name: &str,
+        request: &str,
+    ) -> Result<Vec<(String, String)>, GenericError> {
+        let socket_path = self.socket_dir.join(format!("{interface_name}.sock"));
+        let uapi_error =
+            |e: std::io::Error| format!("UAPI error on {}: {e}", socket_path.display());
+
+        let mut stream = UnixStream::connect(&socket_path).map_err(uapi_error)?;
+        stream
+            .set_read_timeout(Some(UAPI_TIMEOUT))
+            .map_err(uapi_error)?;
+        stream.write_all(request.as_bytes()).map_err(uapi_error)?;
+
+        let mut pairs = Vec::new();
+        for line in BufReader::new(stream).lines() {
+            let line = line.map_err(uapi_error)?;
+
+            // the response is terminated by an empty line
+            if line.is_empty() {
+                break;
+            }
+
+            match line.split_once('=') {
+                Some(("errno", "0")) => return Ok(pairs),
+                Some(("errno", errno)) => {
+                    return Err(format!(
+                        "UAPI error on {}: errno={errno}",
+                        socket_path.display()
+                    ))
+                }
+                Some((key, value)) => pairs.push((key.to_string(), value.to_string())),
+                None => {
+                    return Err(format!(
+                        "UAPI error on {}: invalid line {line}",
+                        socket_path.display()
+                    ))
+                }
+            }
+        }
+
+        Err(format!(
+            "UAPI error on {}: response without errno",
+            socket_path.display()
+        ))
+    }
+
+    /// Reads the current state of the interface with a `get=1` operation
+    pub fn get_device(&self, interface_name: &str) -> Result<WgDump, GenericError> {
+        parse_get_response(self.request(interface_name, "get=1\n\n")?)
+    }
+
+    /// Changes the interface with a `set=1` operation made of the given `key=value` lines
+    fn set_device(&self, interface_name: &str, lines: Vec<String>) -> Result<(), GenericError> {
+        let request = format!("set=1\n{}\n\n", lines.join("\n"));
+
+        self.request(interface_name, &request).map(|_| ())
+    }
+}
+
+impl WireguardBackend for UapiBackend {
+    fn show_dump(&self, interface_name: &str) -> Result<WgDump, GenericError> {
+        self.get_device(interface_name)
+    }
+
+    fn set_peer(&self, interface_name: &str, peer: &PeerConfig) -> Result<(), GenericError> {
+        self.set_device(interface_name, peer_lines(peer)?)
+    }
+
+    fn replace_peer(
+        &self,
+        interface_name: &str,
+        old_public_key: &str,
+        peer: &PeerConfig,
+    ) -> Result<(), GenericError> {
+        // a single set operation is applied atomically by the device
+        let mut lines = vec![
+            format!("public_key={}", base64_to_hex(old_public_key)?),
+            "remove=true".to_string(),
+        ];
+        lines.extend(peer_lines(peer)?);
+
+        self.set_device(interface_name, lines)
+    }
+
+    fn remove_peer(&self, interface_name: &str, public_key: &str) -> Result<(), GenericError> {
+        self.set_device(
+            interface_name,
+            vec![
+                format!("public_key={}", base64_to_hex(public_key)?),
+                "remove=true".to_string(),
+            ],
+        )
+    }
+
+    fn interface_names(&self) -> Result<Vec<String>, GenericError> {
+        let mut sockets = fs::read_dir(&self.socket_dir)
+            .map_err(|e| format!("Error reading {}: {e}", self.socket_dir.display()))?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|file_name| file_name.strip_suffix(".sock"))
+                    .map(|name| name.to_string())
+            })
+            .collect::<Vec<String>>();
+        // sorted by name, as `wg show interfaces` does
+        sockets.sort();
+
+        if sockets.is_empty() {
+            return Err(format!(
+                "Error getting interface names: no socket in {}",
+                self.socket_dir.display()
+            ));
+        }
+
+        Ok(sockets)
+    }
+
+    fn interface_info(&self, interface_name: &str) -> Result<InterfaceInfo, GenericError> {
+        let interface = self.get_device(interface_name)?.interface;
+
+        let public_key = interface.public_key.ok_or(format!(
+            "Error getting public key: {interface_name} has no private key"
+        ))?;
+
+        Ok(InterfaceInfo {
+            name: interface_name.to_string(),
+            public_key,
+            listen_port: interface.listen_port,
+        })
+    }
+
+    fn set_private_key(&self, interface_name: &str, private_key: &str) -> Result<(), GenericError> {
+        self.set_device(
+            interface_name,
+            vec![format!("private_key={}", base64_to_hex(private_key)?)],
+        )
+    }
+
+    fn interface_addresses(&self, _interface_name: &str) -> Result<Vec<IpNet>, GenericError> {
+        // the addresses are configured on the TUN device, outside of the UAPI
+        Ok(Vec::new())
+    }
+
+    fn save_config(&self, interface_name: &str) -> Result<(), GenericError> {
+        match &self.config_dir {
+            Some(config_dir) => {
+                let config_path = config_dir.join(format!("{interface_name}.conf"));
+                let config = to_config(&self.get_device(interface_name)?);
+
+                // the config contains the private key (and the preshared keys), so it's written to a
+                // file only the owner can read and then moved into place
+                let tmp_path = config_dir.join(format!("{interface_name}.conf.tmp"));
+
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&tmp_path)
+                    .and_then(|mut file| file.write_all(config.as_bytes()))
+                    .and_then(|_| fs::rename(&tmp_path, &config_path))
+                    .map_err(|e| format!("Error writing {}: {e}", config_path.display()))
+            }
+            None => {
+                println!("WIREGUARD_CONFIG_DIR not set, skipping saving {interface_name} config");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        os::unix::{fs::PermissionsExt, net::UnixListener},
+        thread,
+    };
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const PEER_KEY: &str = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc=";
+    const PEER_KEY_HEX: &str = "0707070707070707070707070707070707070707070707070707070707070707";
+    const OTHER_PEER_KEY: &str = "CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg=";
+    const OTHER_PEER_KEY_HEX: &str =
+        "0808080808080808080808080808080808080808080808080808080808080808";
+    const ZERO_KEY_HEX: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    /// Serves `response` to a single request on the `wg0` socket of a temporary directory,
+    /// returning the backend and the request the server received
+    fn fake_uapi(response: String) -> (TempDir, UapiBackend, thread::JoinHandle<String>) {
+        let socket_dir = TempDir::new().unwrap();
+        let listener = UnixListener::bind(socket_dir.path().join("wg0.sock")).unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+
+            // the request is terminated by an empty line
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            while !request.ends_with(b"\n\n") {
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+
+            String::from_utf8(request).unwrap()
+        });
+
+        let backend = UapiBackend::new(socket_dir.path().to_path_buf(), None);
+
+        (socket_dir, backend, server)
+    }
+
+    fn peer_config() -> PeerConfig {
+        PeerConfig {
+            public_key: PEER_KEY.to_string(),
+            allowed_ips: vec![
+                "10.13.13.2".parse().unwrap(),
+                "fd13:13:13::2".parse().unwrap(),
+            ],
+            preshared_key: None,
+            persistent_keepalive: Some(25),
+            endpoint: None,
+        }
+    }
+
+    #[test]
+    fn parses_the_peers() {
+        let response = format!(
+            "private_key={ZERO_KEY_HEX}\nlisten_port=51820\nfwmark=0\n\
+             public_key={PEER_KEY_HEX}\npreshared_key={ZERO_KEY_HEX}\nendpoint=203.0.113.7:51820\n\
+             last_handshake_time_sec=1700000000\nlast_handshake_time_nsec=5\n\
+             tx_bytes=1024\nrx_bytes=2048\npersistent_keepalive_interval=25\n\
+             allowed_ip=10.13.13.2/32\nallowed_ip=fd13:13:13::2/128\n\
+             public_key={OTHER_PEER_KEY_HEX}\npreshared_key={PEER_KEY_HEX}\n\
+             last_handshake_time_sec=0\npersistent_keepalive_interval=0\n\
+             errno=0\n\n"
+        );
+        let (_socket_dir, backend, server) = fake_uapi(response);
+
+        let dump = backend.show_dump("wg0").unwrap();
+
+        assert_eq!(server.join().unwrap(), "get=1\n\n");
+        assert_eq!(dump.interface.listen_port, 51820);
+        assert_eq!(dump.interface.fwmark, None);
+        assert_eq!(
+            dump.interface.public_key.as_deref(),
+            Some(public_key_from_private(&hex_to_base64(ZERO_KEY_HEX).unwrap()).unwrap())
+                .as_deref()
+        );
+
+        let peer = &dump.peers[0];
+        assert_eq!(peer.public_key, PEER_KEY);
+        assert_eq!(peer.preshared_key, None);
+        assert_eq!(peer.endpoint, Some("203.0.113.7:51820".parse().unwrap()));
+        assert_eq!(peer.latest_handshake, Some(1700000000));
+        assert_eq!(peer.transfer_rx, 2048);
+        assert_eq!(peer.transfer_tx, 1024);
+        assert_eq!(peer.persistent_keepalive, Some(25));
+        assert_eq!(
+            peer.allowed_ips,
+            vec![
+                "10.13.13.2/32".parse::<IpNet>().unwrap(),
+                "fd13:13:13::2/128".parse().unwrap()
+            ]
+        );
+
+        let other_peer = &dump.peers[1];
+        assert_eq!(other_peer.public_key, OTHER_PEER_KEY);
+        assert_eq!(other_peer.preshared_key.as_deref(), Some(PEER_KEY));
+        assert_eq!(other_peer.endpoint, None);
+        assert_eq!(other_peer.latest_handshake, None);
+        assert_eq!(other_peer.persistent_keepalive, None);
+        assert!(other_peer.allowed_ips.is_empty());
+    }
+
+    #[test]
+    fn fails_on_errno() {
+        let (_socket_dir, backend, server) = fake_uapi("errno=19\n\n".to_string());
+
+        let error = backend.show_dump("wg0").unwrap_err();
+
+        server.join().unwrap();
+        assert!(error.contains("errno=19"), "{error}");
+    }
+
+    #[test]
+    fn fails_without_socket() {
+        let socket_dir = TempDir::new().unwrap();
+        let backend = UapiBackend::new(socket_dir.path().to_path_buf(), None);
+
+        assert!(backend.show_dump("wg0").is_err());
+        assert!(backend.interface_names().is_err());
+    }
+
+    #[test]
+    fn saves_the_config_readable_only_by_the_owner() {
+        let response = format!("private_key={ZERO_KEY_HEX}\nlisten_port=51820\nerrno=0\n\n");
+        let (socket_dir, _, server) = fake_uapi(response);
+        let config_dir = TempDir::new().unwrap();
+        let backend = UapiBackend::new(
+            socket_dir.path().to_path_buf(),
+            Some(config_dir.path().to_path_buf()),
+        );
+
+        backend.save_config("wg0").unwrap();
+
+        server.join().unwrap();
+        let config_path = config_dir.path().join("wg0.conf");
+        let mode = fs::metadata(&config_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(fs::read_to_string(&config_path)
+            .unwrap()
+            .contains("PrivateKey"));
+        assert!(!config_dir.path().join("wg0.conf.tmp").exists());
+    }
+
+    #[test]
+    fn sets_a_peer() {
+        let (_socket_dir, backend, server) = fake_uapi("errno=0\n\n".to_string());
+
+        backend.set_peer("wg0", &peer_config()).unwrap();
+
+        assert_eq!(
+            server.join().unwrap(),
+            format!(
+                "set=1\npublic_key={PEER_KEY_HEX}\npreshared_key={ZERO_KEY_HEX}\n\
+                 persistent_keepalive_interval=25\nreplace_allowed_ips=true\n\
+                 allowed_ip=10.13.13.2/32\nallowed_ip=fd13:13:13::2/128\n\n"
+            )
+        );
+    }
+
+    #[test]
+    fn replaces_a_peer_in_a_single_operation() {
+        let (_socket_dir, backend, server) = fake_uapi("errno=0\n\n".to_string());
+        let peer = PeerConfig {
+            public_key: OTHER_PEER_KEY.to_string(),
+            allowed_ips: vec!["10.13.13.2".parse().unwrap()],
+            preshared_key: Some(PEER_KEY.to_string()),
+            persistent_keepalive: None,
+            endpoint: Some("203.0.113.7:51820".parse().unwrap()),
+        };
+
+        backend.replace_peer("wg0", PEER_KEY, &peer).unwrap();
+
+        assert_eq!(
+            server.join().unwrap(),
+            format!(
+                "set=1\npublic_key={PEER_KEY_HEX}\nremove=true\n\
+                 public_key={OTHER_PEER_KEY_HEX}\npreshared_key={PEER_KEY_HEX}\n\
+                 persistent_keepalive_interval=0\nendpoint=203.0.113.7:51820\n\
+                 replace_allowed_ips=true\nallowed_ip=10.13.13.2/32\n\n"
+            )
+        );
+    }
+}